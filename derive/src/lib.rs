@@ -0,0 +1,122 @@
+/*!
+Derive macros for the [pin-weak](https://docs.rs/pin-weak) crate.
+
+Do not use this crate directly, enable the `derive` feature of `pin-weak` instead.
+*/
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+/// See the documentation of `pin_weak::PinnedSelfRef`
+#[proc_macro_derive(PinnedSelfRef, attributes(pin_weak))]
+pub fn pinned_self_ref(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    derive_pinned_self_ref(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+fn derive_pinned_self_ref(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let mut module = quote!(rc);
+    let mut strong = quote!(Rc);
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("pin_weak")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rc") {
+                module = quote!(rc);
+                strong = quote!(Rc);
+                Ok(())
+            } else if meta.path.is_ident("sync") {
+                module = quote!(sync);
+                strong = quote!(Arc);
+                Ok(())
+            } else {
+                Err(meta.error("unknown pin_weak option, expected `rc` or `sync`"))
+            }
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &s.fields,
+                    "PinnedSelfRef can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "PinnedSelfRef can only be derived for structs with named fields",
+            ))
+        }
+    };
+
+    let mut self_ref = None;
+    let mut others = Vec::new();
+    for field in fields {
+        let mut marked = false;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("pin_weak")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("self_ref") {
+                    marked = true;
+                    Ok(())
+                } else {
+                    Err(meta.error("unknown pin_weak option, expected `self_ref`"))
+                }
+            })?;
+        }
+        if !marked {
+            others.push(field);
+        } else if self_ref.is_some() {
+            return Err(Error::new_spanned(
+                field,
+                "only one field can be marked with #[pin_weak(self_ref)]",
+            ));
+        } else {
+            self_ref = Some(field);
+        }
+    }
+    let self_ref = self_ref.and_then(|f| f.ident.as_ref()).ok_or_else(|| {
+        Error::new(input.ident.span(), "missing a field marked with #[pin_weak(self_ref)]")
+    })?;
+
+    let name = &input.ident;
+    let vis = &input.vis;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let names = others.iter().map(|f| &f.ident).collect::<Vec<_>>();
+    let types = others.iter().map(|f| &f.ty);
+    let strong_me_message = format!("{}::strong_me called while the object is being dropped", name);
+
+    Ok(quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            /// Creates a new pinned object, with its self reference pointing to itself
+            #vis fn new_pinned(#(#names: #types),*)
+                -> ::pin_weak::#module::Pin<::pin_weak::#module::#strong<Self>>
+            {
+                ::pin_weak::#module::PinWeak::new_cyclic(|weak| Self {
+                    #self_ref: ::core::clone::Clone::clone(weak),
+                    #(#names),*
+                })
+            }
+
+            /// Returns a weak pointer to this object
+            #vis fn me(&self) -> ::pin_weak::#module::PinWeak<Self> {
+                ::core::clone::Clone::clone(&self.#self_ref)
+            }
+
+            /// Returns a strong pointer to this object
+            ///
+            /// Panics if the object is being dropped.
+            #vis fn strong_me(&self) -> ::pin_weak::#module::Pin<::pin_weak::#module::#strong<Self>> {
+                self.#self_ref.upgrade().expect(#strong_me_message)
+            }
+        }
+
+        impl #impl_generics ::pin_weak::#module::HasPinWeak for #name #ty_generics #where_clause {
+            fn pin_weak(&self) -> ::pin_weak::#module::PinWeak<Self> {
+                ::core::clone::Clone::clone(&self.#self_ref)
+            }
+        }
+    })
+}
@@ -0,0 +1,84 @@
+use pin_weak::rc::{HasPinWeak, Pin, PinWeak, Rc};
+use pin_weak_derive::PinnedSelfRef;
+
+#[derive(PinnedSelfRef)]
+struct Gadget {
+    #[pin_weak(self_ref)]
+    me: PinWeak<Gadget>,
+    name: String,
+    value: u32,
+    _p: core::marker::PhantomPinned,
+}
+
+#[derive(PinnedSelfRef)]
+struct Generic<T: Clone>
+where
+    T: Default,
+{
+    value: T,
+    #[pin_weak(self_ref)]
+    me: PinWeak<Generic<T>>,
+}
+
+#[derive(PinnedSelfRef)]
+#[pin_weak(sync)]
+pub struct Shared {
+    #[pin_weak(self_ref)]
+    me: pin_weak::sync::PinWeak<Shared>,
+    pub value: u32,
+}
+
+#[test]
+fn test_new_pinned() {
+    let gadget = Gadget::new_pinned("hello".into(), 42, core::marker::PhantomPinned);
+    assert_eq!(gadget.name, "hello");
+    assert!(gadget.me() == gadget);
+    assert_eq!(gadget.me().upgrade().unwrap().value, 42);
+    let strong: Pin<Rc<Gadget>> = gadget.strong_me();
+    assert!(gadget.me.ptr_eq_strong(&strong));
+    let weak = gadget.me();
+    drop((gadget, strong));
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_generic() {
+    let generic = Generic::new_pinned(vec![1, 2, 3]);
+    assert_eq!(generic.strong_me().value, [1, 2, 3]);
+}
+
+#[test]
+fn test_has_pin_weak() {
+    fn check<T: HasPinWeak>(object: &T) -> bool {
+        object.pin_weak().ptr_eq(&PinWeak::downgrade(object.strong_self()))
+    }
+    let gadget = Gadget::new_pinned("hello".into(), 42, core::marker::PhantomPinned);
+    assert!(check(&*gadget));
+    assert!(check(&*Generic::new_pinned(42)));
+    let shared = Shared::new_pinned(42);
+    assert!(pin_weak::sync::HasPinWeak::pin_weak(&*shared) == shared);
+}
+
+#[test]
+fn test_sync() {
+    let shared = Shared::new_pinned(42);
+    let weak = shared.me();
+    assert_eq!(std::thread::spawn(move || weak.upgrade().unwrap().value).join().unwrap(), 42);
+    assert_eq!(shared.strong_me().value, 42);
+}
+
+#[test]
+#[should_panic(expected = "Dropped::strong_me called while the object is being dropped")]
+fn test_strong_me_during_drop() {
+    #[derive(PinnedSelfRef)]
+    struct Dropped {
+        #[pin_weak(self_ref)]
+        me: PinWeak<Dropped>,
+    }
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.strong_me();
+        }
+    }
+    drop(Dropped::new_pinned());
+}
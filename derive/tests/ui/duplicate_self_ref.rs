@@ -0,0 +1,12 @@
+use pin_weak::rc::PinWeak;
+use pin_weak_derive::PinnedSelfRef;
+
+#[derive(PinnedSelfRef)]
+struct Foo {
+    #[pin_weak(self_ref)]
+    me: PinWeak<Foo>,
+    #[pin_weak(self_ref)]
+    me2: PinWeak<Foo>,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use pin_weak::rc::PinWeak;
+use pin_weak_derive::PinnedSelfRef;
+
+#[derive(PinnedSelfRef)]
+struct Foo {
+    me: PinWeak<Foo>,
+    value: u32,
+}
+
+fn main() {}
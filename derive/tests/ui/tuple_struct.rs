@@ -0,0 +1,12 @@
+use pin_weak::rc::PinWeak;
+use pin_weak_derive::PinnedSelfRef;
+
+#[derive(PinnedSelfRef)]
+struct Foo(#[pin_weak(self_ref)] PinWeak<Foo>, u32);
+
+#[derive(PinnedSelfRef)]
+enum Bar {
+    A,
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use pin_weak::rc::PinWeak;
+use pin_weak_derive::PinnedSelfRef;
+
+#[derive(PinnedSelfRef)]
+#[pin_weak(arc)]
+struct Foo {
+    #[pin_weak(self_ref)]
+    me: PinWeak<Foo>,
+}
+
+#[derive(PinnedSelfRef)]
+struct Bar {
+    #[pin_weak(me)]
+    me: PinWeak<Bar>,
+}
+
+fn main() {}
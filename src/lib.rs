@@ -56,153 +56,9071 @@ assert!(weak.upgrade().is_none());
 */
 
 #![no_std]
+#![cfg_attr(pin_weak_allocator_api, feature(allocator_api))]
 extern crate alloc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
 
 #[cfg(doc)]
 use alloc::{rc::Rc, sync::Arc};
 #[cfg(doc)]
 use core::pin::Pin;
 
+/// An opaque token identifying the allocation a `PinWeak` points to.
+///
+/// It can be obtained with [`rc::PinWeak::id`] or [`sync::PinWeak::id`] or, from the strong
+/// pointer, with the `From<&Pin<Rc<T>>>` and `From<&Pin<Arc<T>>>` implementations.
+/// Two `PinWeak` pointing to the same allocation have the same id.
+///
+/// Unlike a `PinWeak`, a `WeakId` does not keep the allocation reserved, so the address
+/// (and therefore the id) may be reused by another allocation once all the strong and
+/// weak references are gone.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct WeakId(usize);
+
+/// An identity token combining the address of an allocation with a generation,
+/// obtained with `PinWeak::tagged_id`.
+///
+/// Unlike [`WeakId`], two objects that happen to be allocated at the same address
+/// will have different tagged ids.
+#[cfg(feature = "tracked")]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct TaggedWeakId {
+    id: WeakId,
+    generation: usize,
+}
+
+#[cfg(feature = "tracked")]
+impl TaggedWeakId {
+    /// The [`WeakId`] corresponding to this tagged id, without the generation
+    pub fn id(&self) -> WeakId {
+        self.id
+    }
+}
+
+/// The generation of the allocation a `PinWeak` points to.
+///
+/// The generations are kept in a table indexed by address, with the number of `PinWeak`
+/// (and raw pointers from `PinWeak::into_raw`) pointing to it. While that number is not zero,
+/// the allocation stays reserved and no other allocation can have the same address, so all
+/// the `PinWeak` created during that time get the same generation.
+#[cfg(feature = "tracked")]
+struct Generation {
+    addr: usize,
+    generation: usize,
+}
+
+#[cfg(feature = "tracked")]
+static GENERATIONS: std::sync::Mutex<alloc::collections::BTreeMap<usize, (usize, usize)>> =
+    std::sync::Mutex::new(alloc::collections::BTreeMap::new());
+
+#[cfg(feature = "tracked")]
+impl Generation {
+    /// The generation of `PinWeak::new`
+    const NONE: Self = Self { addr: 0, generation: 0 };
+
+    /// Records one more `PinWeak` to the allocation at `addr`
+    fn register(addr: usize) -> Self {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        // 0 is reserved for PinWeak::new
+        static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+        if addr == 0 {
+            return Self::NONE;
+        }
+        let mut table = GENERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        let (generation, count) = table
+            .entry(addr)
+            .or_insert_with(|| (NEXT_GENERATION.fetch_add(1, Ordering::Relaxed), 0));
+        *count += 1;
+        Self { addr, generation: *generation }
+    }
+
+    /// Releases the `PinWeak` that was turned into a raw pointer with `PinWeak::into_raw`
+    fn release(addr: usize) {
+        if addr == 0 {
+            return;
+        }
+        let mut table = GENERATIONS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, count)) = table.get_mut(&addr) {
+            *count -= 1;
+            if *count == 0 {
+                table.remove(&addr);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tracked")]
+impl Clone for Generation {
+    fn clone(&self) -> Self {
+        let clone = Self::register(self.addr);
+        debug_assert_eq!(clone.generation, self.generation);
+        clone
+    }
+}
+
+#[cfg(feature = "tracked")]
+impl Drop for Generation {
+    fn drop(&mut self) {
+        Self::release(self.addr);
+    }
+}
+
+/// The error returned by `PinWeak::try_upgrade` when the pinned target was dropped
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct UpgradeError;
+
+impl core::fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("cannot upgrade the weak pointer: the pinned target was dropped")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for UpgradeError {}
+
+/// Derive macro for structs containing a `PinWeak` to themselves, requires the `derive` feature.
+///
+/// The field holding the weak pointer must be marked with `#[pin_weak(self_ref)]`.
+/// The derive generates, with the same visibility as the struct:
+///  - `fn new_pinned(...) -> Pin<Rc<Self>>`, taking the other fields as arguments in order,
+///    and constructing the object with `PinWeak::new_cyclic`,
+///  - `fn me(&self) -> PinWeak<Self>` returning a clone of the weak pointer,
+///  - `fn strong_me(&self) -> Pin<Rc<Self>>` upgrading it, which panics if called while the
+///    object is being dropped.
+///
+/// It also implements the `HasPinWeak` trait of the corresponding module.
+///
+/// Use `#[pin_weak(sync)]` on the struct to use [`sync::PinWeak`] and `Arc` instead.
+///
+/// ```
+/// use pin_weak::{rc::PinWeak, PinnedSelfRef};
+///
+/// #[derive(PinnedSelfRef)]
+/// struct Gadget {
+///     #[pin_weak(self_ref)]
+///     me: PinWeak<Gadget>,
+///     value: u32,
+/// }
+///
+/// let gadget = Gadget::new_pinned(42);
+/// assert_eq!(gadget.me().upgrade().unwrap().value, 42);
+/// assert_eq!(gadget.strong_me().value, 42);
+/// ```
+#[cfg(feature = "derive")]
+pub use pin_weak_derive::PinnedSelfRef;
+
+/// A source of time for `ExpiringWeak`
+///
+/// With the `std` feature, [`StdClock`] implements it with [`std::time::Instant`].
+pub trait Clock {
+    /// A point in time
+    type Instant: Copy + Ord + core::ops::Add<core::time::Duration, Output = Self::Instant>;
+    /// Returns the current time
+    fn now(&self) -> Self::Instant;
+}
+
+/// A [`Clock`] using [`std::time::Instant::now`]
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    type Instant = std::time::Instant;
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+}
+
+/// A pinned strong pointer, implemented for `Pin<Rc<T>>` and `Pin<Arc<T>>`, to write code
+/// working with both the [`rc`] and [`sync`] pointers.
+///
+/// See [`PinnedWeak`] for an example.
+pub trait PinnedStrong: Clone + core::ops::Deref {
+    /// The corresponding weak pointer, [`rc::PinWeak`] or [`sync::PinWeak`]
+    type Weak: PinnedWeak<Strong = Self, Target = Self::Target>;
+    /// Creates a weak pointer to the same allocation
+    fn downgrade(&self) -> Self::Weak;
+    /// Returns a pinned reference to the value
+    fn as_pin_ref(&self) -> core::pin::Pin<&Self::Target>;
+}
+
+/// A weak pointer to a pinned value, implemented for [`rc::PinWeak`] and [`sync::PinWeak`], to
+/// write code working with both.
+///
+/// ```
+/// use core::{cell::Cell, pin::Pin};
+/// use pin_weak::{rc, PinnedStrong, PinnedWeak};
+///
+/// struct Observers<W: PinnedWeak> {
+///     list: Vec<W>,
+/// }
+///
+/// impl<W: PinnedWeak> Observers<W> {
+///     fn observe(&mut self, observer: &W::Strong) {
+///         self.list.push(observer.downgrade());
+///     }
+///     fn notify(&self, mut f: impl FnMut(Pin<&W::Target>)) {
+///         for observer in self.list.iter().filter_map(W::upgrade) {
+///             f(observer.as_pin_ref());
+///         }
+///     }
+/// }
+///
+/// let mut local = Observers::<rc::PinWeak<Cell<u32>>> { list: Vec::new() };
+/// let counter = rc::Rc::pin(Cell::new(0));
+/// local.observe(&counter);
+/// local.notify(|c| c.set(c.get() + 1));
+/// assert_eq!(counter.get(), 1);
+///
+/// #[cfg(feature = "sync")]
+/// {
+///     use pin_weak::sync;
+///     let mut shared = Observers::<sync::PinWeak<str>> { list: Vec::new() };
+///     let name: Pin<sync::Arc<str>> = sync::pin_str("name");
+///     shared.observe(&name);
+///     drop(name);
+///     shared.notify(|_| unreachable!());
+/// }
+/// ```
+pub trait PinnedWeak: Clone {
+    /// The type of the value
+    type Target: ?Sized;
+    /// The corresponding pinned strong pointer, `Pin<Rc<T>>` or `Pin<Arc<T>>`
+    type Strong: PinnedStrong<Weak = Self, Target = Self::Target>;
+    /// Returns a strong pointer to the value, or `None` if it was dropped
+    fn upgrade(&self) -> Option<Self::Strong>;
+    /// Returns true if the two pointers point to the same allocation
+    fn ptr_eq(&self, other: &Self) -> bool;
+    /// Returns the number of strong pointers to the value
+    fn strong_count(&self) -> usize;
+}
+
+/// Implements `UpgradeAll` for tuples of references to `PinWeak`, of every length up to
+/// the number of given type parameters
+macro_rules! upgrade_all_tuples {
+    ($Rc:ident;) => {};
+    ($Rc:ident; $T0:ident $w0:ident $(, $T:ident $w:ident)*) => {
+        impl<'a, $T0: ?Sized $(, $T: ?Sized)*> UpgradeAll for (&'a PinWeak<$T0>, $(&'a PinWeak<$T>,)*) {
+            type Output = (Pin<$Rc<$T0>>, $(Pin<$Rc<$T>>,)*);
+            fn upgrade_all(self) -> Option<Self::Output> {
+                let ($w0, $($w,)*) = self;
+                Some(($w0.upgrade()?, $($w.upgrade()?,)*))
+            }
+        }
+        upgrade_all_tuples!($Rc; $($T $w),*);
+    };
+}
+
 /// The implementation is in a macro because it is repeated for Arc and Rc
 macro_rules! implementation {
-    ($Rc:ident, $Weak:ident, $rc_lit:literal) => {
+    ($Rc:ident, $Weak:ident, $rc_lit:literal, $Ext:ident $(, $auto:ident)*) => {
         #[doc(no_inline)]
         /// re-exported for convenience
         pub use core::pin::Pin;
         /// This is a safe wrapper around something that could be compared to [`Pin`]`<`[`Weak<T>`]`>`
         ///
         /// The typical way to obtain a `PinWeak` is to call [`PinWeak::downgrade`]
-        #[derive(Debug)]
-        pub struct PinWeak<T: ?Sized>(Weak<T>);
-        impl<T> Default for PinWeak<T> {
+        ///
+        /// When building with `--cfg pin_weak_allocator_api` on nightly, `PinWeak` also has an
+        /// allocator parameter, like [`Weak`].
+        pub struct PinWeak<
+            T: ?Sized,
+            #[cfg(pin_weak_allocator_api)] A: core::alloc::Allocator = alloc::alloc::Global,
+        > {
+            #[cfg(not(pin_weak_allocator_api))]
+            weak: Option<Weak<T>>,
+            #[cfg(pin_weak_allocator_api)]
+            weak: Option<Weak<T, A>>,
+            #[cfg(feature = "tracked")]
+            generation: crate::Generation,
+        }
+        /// Same as [`PinWeak::new`]
+        impl<T: ?Sized> Default for PinWeak<T> {
             fn default() -> Self {
-                Self(Weak::default())
+                Self::new()
             }
         }
+        #[cfg(not(pin_weak_allocator_api))]
         impl<T: ?Sized> Clone for PinWeak<T> {
             fn clone(&self) -> Self {
-                Self(self.0.clone())
+                Self {
+                    weak: self.weak.clone(),
+                    #[cfg(feature = "tracked")]
+                    generation: self.generation.clone(),
+                }
             }
         }
         impl<T: ?Sized> PinWeak<T> {
+            /// Constructs a new `PinWeak` which does not point to any allocation.
+            /// Calling [`PinWeak::upgrade`] on it always returns `None`.
+            ///
+            /// Unlike [`Weak::new`], this also works for unsized types such as `dyn Trait` or `[T]`,
+            /// and can be used in a const context, for example to initialize a `static`.
+            pub const fn new() -> Self {
+                Self {
+                    weak: None,
+                    #[cfg(feature = "tracked")]
+                    generation: crate::Generation::NONE,
+                }
+            }
+
             #[doc = concat!("Equivalent function to [`", $rc_lit, "::downgrade`], but taking a `Pin<", $rc_lit, "<T>>` instead.")]
+            #[cfg(not(pin_weak_allocator_api))]
             pub fn downgrade(rc: Pin<$Rc<T>>) -> Self {
                 // Safety: we will never return anything else than a Pin<Rc>
-                unsafe { Self($Rc::downgrade(&Pin::into_inner_unchecked(rc))) }
+                unsafe { Self::from_weak($Rc::downgrade(&Pin::into_inner_unchecked(rc))) }
+            }
+
+            #[doc = concat!("Same as [`PinWeak::downgrade`], but taking the `Pin<", $rc_lit, "<T>>` by reference,")]
+            /// so the caller keeps its strong pointer without having to clone it first.
+            #[cfg(not(pin_weak_allocator_api))]
+            pub fn downgrade_ref(rc: &Pin<$Rc<T>>) -> Self {
+                // Safety: we will never return anything else than a Pin<Rc>
+                Self::from_weak($Rc::downgrade(unsafe { pinned_inner(rc) }))
             }
             #[doc = concat!("Equivalent function to [`Weak::upgrade`], but taking a `Pin<", $rc_lit, "<T>>` instead.")]
+            #[cfg(not(pin_weak_allocator_api))]
             pub fn upgrade(&self) -> Option<Pin<$Rc<T>>> {
                 // Safety: the weak was constructed from a Pin<Rc<T>>
-                self.0.upgrade().map(|rc| unsafe { Pin::new_unchecked(rc) })
+                self.weak.as_ref()?.upgrade().map(|rc| unsafe { Pin::new_unchecked(rc) })
+            }
+
+            /// Same as [`PinWeak::upgrade`], but returns an error instead of `None`,
+            /// which is convenient with the `?` operator.
+            pub fn try_upgrade(&self) -> Result<Pin<$Rc<T>>, crate::UpgradeError> {
+                self.upgrade().ok_or(crate::UpgradeError)
+            }
+
+            /// Upgrades the weak pointer, panicking with the given message if the target was dropped.
+            ///
+            /// The panic location is the caller of this function.
+            #[track_caller]
+            pub fn expect_upgrade(&self, msg: &str) -> Pin<$Rc<T>> {
+                self.upgrade().expect(msg)
+            }
+
+            /// Upgrades the weak pointer, panicking if the target was dropped.
+            ///
+            /// The panic location is the caller of this function.
+            #[track_caller]
+            pub fn unwrap_upgrade(&self) -> Pin<$Rc<T>> {
+                match self.upgrade() {
+                    Some(rc) => rc,
+                    None => panic!("called `PinWeak::unwrap_upgrade()` but the pinned target was dropped"),
+                }
+            }
+
+            /// Upgrades the weak pointer without checking that the target is still alive.
+            ///
+            /// This is checked with a `debug_assert!` in debug builds.
+            ///
+            /// # Safety
+            ///
+            /// The strong count must be greater than zero for the whole duration of the call,
+            /// that is, the target must not be dropped, even from another thread.
+            pub unsafe fn upgrade_unchecked(&self) -> Pin<$Rc<T>> {
+                debug_assert!(self.strong_count() > 0, "PinWeak::upgrade_unchecked on a dropped target");
+                // Safety: the caller guarantees that the value is alive, and it was pinned
+                let ptr = Weak::as_ptr(self.weak.as_ref().unwrap_unchecked());
+                $Rc::increment_strong_count(ptr);
+                Pin::new_unchecked($Rc::from_raw(ptr))
+            }
+
+            /// Upgrades the weak pointer and calls `f` with a pinned reference to the value.
+            /// Returns `None`, without calling `f`, if the value was dropped.
+            ///
+            /// This is re-entrancy safe: a strong reference is held for the duration of the call,
+            /// so the value is not dropped even if `f` drops the other strong references to it.
+            pub fn with_upgraded<R>(&self, f: impl FnOnce(Pin<&T>) -> R) -> Option<R> {
+                let rc = self.upgrade()?;
+                Some(f(rc.as_ref()))
+            }
+
+            /// Returns a closure which calls `f` with the upgraded pointer and its argument, or
+            /// returns `None` if the value was dropped.
+            ///
+            /// ```rust
+            #[doc = concat!("use ", module_path!(), "::{PinWeak, ", $rc_lit, "};")]
+            /// use core::pin::Pin;
+            /// struct Button { id: u32 }
+            /// impl Button {
+            ///     fn on_click(self: Pin<&Self>, clicks: u32) -> u32 { self.id * clicks }
+            /// }
+            #[doc = concat!("let button = ", $rc_lit, "::pin(Button { id: 2 });")]
+            /// let on_click = PinWeak::downgrade_ref(&button).bind(Button::on_click);
+            /// assert_eq!(on_click(3), Some(6));
+            /// drop(button);
+            /// assert_eq!(on_click(3), None);
+            /// ```
+            pub fn bind<Args, R>(self, f: impl Fn(Pin<&T>, Args) -> R) -> impl Fn(Args) -> Option<R> {
+                move |args| self.with_upgraded(|target| f(target, args))
+            }
+
+            /// Same as [`PinWeak::bind`], but the closure returns a clone of `default` if the
+            /// value was dropped.
+            pub fn bind_or<Args, R: Clone>(
+                self,
+                f: impl Fn(Pin<&T>, Args) -> R,
+                default: R,
+            ) -> impl Fn(Args) -> R {
+                move |args| self.with_upgraded(|target| f(target, args)).unwrap_or_else(|| default.clone())
+            }
+
+            /// Upgrades the weak pointer into an [`UpgradeGuard`] that keeps the value alive
+            /// for as long as it is in scope and dereferences to it.
+            /// Returns `None` if the value was dropped.
+            ///
+            /// Unlike [`PinWeak::with_upgraded`], this composes with early returns and `?`.
+            pub fn guard(&self) -> Option<UpgradeGuard<'_, T>> {
+                Some(UpgradeGuard { strong: self.upgrade()?, _weak: core::marker::PhantomData })
+            }
+
+            /// Returns true if the target was dropped, or if this `PinWeak` was created with
+            /// [`PinWeak::new`]. In that case, [`PinWeak::upgrade`] would return `None`.
+            ///
+            /// Unlike `upgrade`, this does not create a temporary strong reference.
+            /// If other threads hold strong references, the answer may already be outdated when
+            /// it is used, so it should only be taken as a hint (e.g. to prune a list).
+            pub fn is_dangling(&self) -> bool {
+                self.strong_count() == 0
             }
 
             /// Equivalent to [`Weak::strong_count`]
             pub fn strong_count(&self) -> usize {
-                self.0.strong_count()
+                self.weak.as_ref().map_or(0, Weak::strong_count)
             }
 
             /// Equivalent to [`Weak::weak_count`]
             pub fn weak_count(&self) -> usize {
-                self.0.weak_count()
+                self.weak.as_ref().map_or(0, Weak::weak_count)
             }
 
             /// Equivalent to [`Weak::ptr_eq`]
+            ///
+            /// Two `PinWeak` created with [`PinWeak::new`] are equal to each other.
             pub fn ptr_eq(&self, other: &Self) -> bool {
-                self.0.ptr_eq(&other.0)
+                self.addr() == other.addr()
             }
-        }
 
-        impl<T> PinWeak<T> {
-            #[doc = concat!("Equivalent function to [`", $rc_lit, "::new_cyclic`], but operating on `PinWeak<T>` and `Pin<", $rc_lit, "<T>>` instead.")]
-            pub fn new_cyclic<F>(data_fn: F) -> Pin<$Rc<T>> where F: FnOnce(&Self) -> T {
+            #[doc = concat!("Returns true if this `PinWeak` points to the same allocation as the given `Pin<", $rc_lit, "<T>>`.")]
+            ///
+            /// This does not upgrade the weak pointer. A `PinWeak` whose target was dropped never
+            /// compares equal to a live pointer, since it keeps its own allocation reserved.
+            pub fn ptr_eq_strong(&self, strong: &Pin<$Rc<T>>) -> bool {
+                self.addr() == Pin::get_ref(strong.as_ref()) as *const T as *const ()
+            }
 
-                let rc = $Rc::new_cyclic(|weak| data_fn(&Self(weak.clone())));
-                // Saferty: Nobody else had access to the unpinned Rc before.
-                unsafe { Pin::new_unchecked(rc) }
+            /// Returns true if both pointers point to the same allocation, even if they have
+            /// different types, such as a `PinWeak<T>` and a [`PinWeak`] to a trait object
+            /// implemented by `T`.
+            ///
+            /// Only the addresses are compared and the metadata (such as the vtable) is ignored.
+            /// Like [`PinWeak::ptr_eq`], this does not upgrade the weak pointers, so it can be used
+            /// on weak pointers whose target was dropped, and two `PinWeak` created with
+            /// [`PinWeak::new`] are considered to share the same allocation.
+            pub fn same_allocation<U: ?Sized>(&self, other: &PinWeak<U>) -> bool {
+                self.addr() == other.addr()
+            }
 
+            #[doc = concat!("Same as [`PinWeak::same_allocation`], but compares with a `Pin<", $rc_lit, "<U>>`.")]
+            pub fn same_allocation_strong<U: ?Sized>(&self, strong: &Pin<$Rc<U>>) -> bool {
+                self.addr() == Pin::get_ref(strong.as_ref()) as *const U as *const ()
             }
-        }
 
-        #[test]
-        fn test() {
-            struct Foo {
-                _p: core::marker::PhantomPinned,
-                u: u32,
+            /// Same as [`PinWeak::as_ptr`], but also works for unsized types
+            ///
+            /// Returns `None` if this `PinWeak` was created with [`PinWeak::new`], since there is
+            /// no pointer to return for unsized types.
+            pub fn try_as_ptr(&self) -> Option<*const T> {
+                self.weak.as_ref().map(Weak::as_ptr)
             }
-            impl Foo {
-                fn new(u: u32) -> Self {
-                    Self { _p: core::marker::PhantomPinned, u }
+
+            /// Same as [`PinWeak::into_raw`], but also works for unsized types
+            ///
+            /// Returns `None` if this `PinWeak` was created with [`PinWeak::new`], which does not
+            /// hold any weak reference.
+            pub fn try_into_raw(self) -> Option<*const T> {
+                // The raw pointer keeps the generation of the allocation
+                #[cfg(feature = "tracked")]
+                core::mem::forget(self.generation);
+                self.weak.map(Weak::into_raw)
+            }
+
+            /// Equivalent to [`Weak::from_raw`]
+            ///
+            /// # Safety
+            ///
+            /// The pointer must have been obtained from [`PinWeak::into_raw`] or
+            /// [`PinWeak::try_into_raw`] (and not from [`Weak::into_raw`], which would allow to
+            /// create a `PinWeak` from a value that was never pinned). Each pointer returned by
+            /// `into_raw` owns one weak reference and must be passed to `from_raw` exactly once.
+            pub unsafe fn from_raw(ptr: *const T) -> Self {
+                let this = Self::from_weak(Weak::from_raw(ptr));
+                // Take back the registration that was kept by `into_raw`
+                #[cfg(feature = "tracked")]
+                crate::Generation::release(this.addr() as usize);
+                this
+            }
+
+            /// Returns a [`WeakId`](crate::WeakId) identifying the allocation this `PinWeak` points to
+            pub fn id(&self) -> crate::WeakId {
+                crate::WeakId(self.addr() as usize)
+            }
+
+            /// Returns a [`TaggedWeakId`](crate::TaggedWeakId) combining the address of the
+            /// allocation with a generation of that allocation.
+            ///
+            /// All the `PinWeak` to an allocation have the same tagged id, as long as at least
+            /// one of them (or a pointer from [`PinWeak::into_raw`]) exists, which keeps the
+            /// allocation reserved. So two objects reusing the same address never share a
+            /// tagged id. Once all of them are dropped, a new `PinWeak` to a still alive object
+            /// may get a new generation.
+            /// Use [`PinWeak::ptr_eq`] or [`PinWeak::id`] to compare the allocation only.
+            #[cfg(feature = "tracked")]
+            pub fn tagged_id(&self) -> crate::TaggedWeakId {
+                crate::TaggedWeakId { id: self.id(), generation: self.generation.generation }
+            }
+
+            #[cfg(not(pin_weak_allocator_api))]
+            fn from_weak(weak: Weak<T>) -> Self {
+                // A dangling `Weak`, such as the one from `into_raw` on `PinWeak::new()`, is
+                // stored as `None` so that it compares equal to `PinWeak::new()`
+                let dangling = Weak::<()>::new().as_ptr();
+                let weak = Some(weak).filter(|weak| weak.as_ptr() as *const () != dangling);
+                #[cfg(feature = "tracked")]
+                let generation = crate::Generation::register(
+                    weak.as_ref().map_or(0, |weak| weak.as_ptr() as *const () as usize),
+                );
+                Self {
+                    weak,
+                    #[cfg(feature = "tracked")]
+                    generation,
                 }
             }
-            let c = $Rc::pin(Foo::new(44));
-            let weak1 = PinWeak::downgrade(c.clone());
-            assert_eq!(weak1.upgrade().unwrap().u, 44);
-            assert_eq!(weak1.clone().upgrade().unwrap().u, 44);
-            assert_eq!(weak1.strong_count(), 1);
-            assert_eq!(weak1.weak_count(), 1);
-            let weak2 = PinWeak::downgrade(c.clone());
-            assert_eq!(weak2.upgrade().unwrap().u, 44);
-            assert_eq!(weak1.upgrade().unwrap().u, 44);
-            assert_eq!(weak2.strong_count(), 1);
-            assert_eq!(weak2.weak_count(), 2);
-            assert!(weak1.ptr_eq(&weak2));
-            assert!(!weak1.ptr_eq(&Default::default()));
-            // note that this moves c and therefore it will be dropped
-            let weak3 = PinWeak::downgrade(c);
-            assert!(weak3.upgrade().is_none());
-            assert!(weak2.upgrade().is_none());
-            assert!(weak1.upgrade().is_none());
-            assert!(weak1.clone().upgrade().is_none());
-            assert_eq!(weak2.strong_count(), 0);
-            assert_eq!(weak2.weak_count(), 0);
 
-            let def = PinWeak::<alloc::boxed::Box<&'static mut ()>>::default();
-            assert!(def.upgrade().is_none());
-            assert!(def.clone().upgrade().is_none());
+            /// The address of the allocation, without the metadata of unsized types
+            fn addr(&self) -> *const () {
+                self.weak.as_ref().map_or(core::ptr::null(), |weak| weak.as_ptr() as *const ())
+            }
         }
 
-        #[test]
-        fn test_cyclic() {
-            use alloc::string::String;
-            struct Gadget {
-                me: PinWeak<Gadget>,
-                value: String,
+        /// With the allocator API, the allocator of the strong pointer is kept by the `PinWeak`
+        /// and given back by `upgrade`.
+        #[cfg(pin_weak_allocator_api)]
+        impl<T: ?Sized, A: core::alloc::Allocator + Clone> PinWeak<T, A> {
+            #[doc = concat!("Equivalent function to [`", $rc_lit, "::downgrade`], but taking a `Pin<", $rc_lit, "<T, A>>` instead.")]
+            pub fn downgrade(rc: Pin<$Rc<T, A>>) -> Self {
+                // Safety: we will never return anything else than a Pin<Rc>
+                unsafe { Self::from_weak($Rc::downgrade(&Pin::into_inner_unchecked(rc))) }
             }
 
-            impl Gadget {
-                fn new(value: String) -> Pin<$Rc<Self>> {
-                    PinWeak::new_cyclic(|me| {
-                        Gadget { me: me.clone(), value }
-                    })
-                }
+            #[doc = concat!("Same as [`PinWeak::downgrade`], but taking the `Pin<", $rc_lit, "<T, A>>` by reference,")]
+            /// so the caller keeps its strong pointer without having to clone it first.
+            pub fn downgrade_ref(rc: &Pin<$Rc<T, A>>) -> Self {
+                // Safety: Pin is repr(transparent), and the Rc is only used to create a Weak
+                let rc = unsafe { &*(rc as *const Pin<$Rc<T, A>> as *const $Rc<T, A>) };
+                Self::from_weak($Rc::downgrade(rc))
+            }
 
-                /// Return a reference counted pointer to Self.
-                fn me(&self) -> Pin<$Rc<Self>> {
-                    self.me.upgrade().unwrap()
+            #[doc = concat!("Equivalent function to [`Weak::upgrade`], but taking a `Pin<", $rc_lit, "<T, A>>` instead.")]
+            pub fn upgrade(&self) -> Option<Pin<$Rc<T, A>>> {
+                // Safety: the weak was constructed from a Pin<Rc<T, A>>
+                self.weak.as_ref()?.upgrade().map(|rc| unsafe { Pin::new_unchecked(rc) })
+            }
+
+            fn from_weak(weak: Weak<T, A>) -> Self {
+                // A dangling `Weak`, such as the one from `into_raw` on `PinWeak::new()`, is
+                // stored as `None` so that it compares equal to `PinWeak::new()`
+                let dangling = Weak::<()>::new().as_ptr();
+                let weak = Some(weak).filter(|weak| weak.as_ptr() as *const () != dangling);
+                #[cfg(feature = "tracked")]
+                let generation = crate::Generation::register(
+                    weak.as_ref().map_or(0, |weak| weak.as_ptr() as *const () as usize),
+                );
+                Self {
+                    weak,
+                    #[cfg(feature = "tracked")]
+                    generation,
                 }
+            }
+        }
 
-                fn value(self: Pin<&Self>) -> &str {
-                    &self.get_ref().value
+        #[cfg(pin_weak_allocator_api)]
+        impl<T, A: core::alloc::Allocator + Clone> PinWeak<T, A> {
+            /// Same as [`PinWeak::new_cyclic`], but allocates in the given allocator.
+            ///
+            /// This requires a nightly compiler and `--cfg pin_weak_allocator_api`.
+            pub fn new_cyclic_in<F>(data_fn: F, alloc: A) -> Pin<$Rc<T, A>>
+            where
+                F: FnOnce(&Self) -> T,
+            {
+                let rc = $Rc::new_cyclic_in(|weak| data_fn(&Self::from_weak(weak.clone())), alloc);
+                // Safety: Nobody else had access to the unpinned Rc before.
+                unsafe { Pin::new_unchecked(rc) }
+            }
+        }
+
+        #[cfg(pin_weak_allocator_api)]
+        impl<T: ?Sized, A: core::alloc::Allocator + Clone> Clone for PinWeak<T, A> {
+            fn clone(&self) -> Self {
+                Self {
+                    weak: self.weak.clone(),
+                    #[cfg(feature = "tracked")]
+                    generation: self.generation.clone(),
                 }
             }
+        }
 
-            let g = Gadget::new("hello".into());
-            assert_eq!(g.me().as_ref().value(), "hello");
+        impl<T> PinWeak<T> {
+            /// Equivalent to [`Weak::as_ptr`]
+            ///
+            /// If this `PinWeak` was created with [`PinWeak::new`], this returns the same dangling
+            /// pointer as [`Weak::new`]. Use [`PinWeak::try_as_ptr`] for unsized types.
+            ///
+            /// The pointer is only valid while the strong count is not zero. It must never be
+            /// used to create a mutable reference or to move the value, as that would break the
+            /// pinning guarantees.
+            pub fn as_ptr(&self) -> *const T {
+                self.weak.as_ref().map_or_else(|| Weak::new().as_ptr(), Weak::as_ptr)
+            }
+
+            /// Equivalent to [`Weak::into_raw`]
+            ///
+            /// Consumes the `PinWeak` and turns it into a raw pointer, keeping the weak reference.
+            /// If this `PinWeak` was created with [`PinWeak::new`], this returns the same dangling
+            /// pointer as [`Weak::new`], which [`PinWeak::from_raw`] turns back into a
+            /// `PinWeak::new()`. Use [`PinWeak::try_into_raw`] for unsized types.
+            ///
+            /// The pointer can be turned back into a `PinWeak` with [`PinWeak::from_raw`].
+            /// It must never be used to create a mutable reference or to move the value.
+            pub fn into_raw(self) -> *const T {
+                // The raw pointer keeps the generation of the allocation
+                #[cfg(feature = "tracked")]
+                core::mem::forget(self.generation);
+                self.weak.unwrap_or_default().into_raw()
+            }
         }
-    };
-}
 
-pub mod rc {
-    #[doc(no_inline)]
-    /// re-exported for convenience
-    pub use alloc::rc::{Rc, Weak};
-    implementation! {Rc, Weak, "Rc"}
-}
+        /// Two `PinWeak` are equal if they point to the same allocation, like [`PinWeak::ptr_eq`].
+        /// The value they point to is not compared.
+        impl<T: ?Sized> PartialEq for PinWeak<T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.ptr_eq(other)
+            }
+        }
+        impl<T: ?Sized> Eq for PinWeak<T> {}
 
-#[cfg(feature = "sync")]
-pub mod sync {
-    #[doc(no_inline)]
-    /// re-exported for convenience
-    pub use alloc::sync::{Arc, Weak};
-    implementation! {Arc, Weak, "Arc"}
+        /// Same as [`PinWeak::ptr_eq_strong`]
+        impl<T: ?Sized> PartialEq<Pin<$Rc<T>>> for PinWeak<T> {
+            fn eq(&self, other: &Pin<$Rc<T>>) -> bool {
+                self.ptr_eq_strong(other)
+            }
+        }
+
+        /// Same as [`PinWeak::ptr_eq_strong`]
+        impl<T: ?Sized> PartialEq<PinWeak<T>> for Pin<$Rc<T>> {
+            fn eq(&self, other: &PinWeak<T>) -> bool {
+                other.ptr_eq_strong(self)
+            }
+        }
+
+        /// `PinWeak` are ordered by the address of the allocation (identity), not by value.
+        ///
+        /// The order is consistent with [`PinWeak::ptr_eq`] but is otherwise arbitrary and
+        /// may change from one run of the program to another.
+        impl<T: ?Sized> PartialOrd for PinWeak<T> {
+            fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl<T: ?Sized> Ord for PinWeak<T> {
+            fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+                self.addr().cmp(&other.addr())
+            }
+        }
+
+        /// Same as [`PinWeak::downgrade`]
+        ///
+        /// ```
+        #[doc = concat!("use ", module_path!(), "::*;")]
+        #[doc = concat!("let pinned = ", $rc_lit, "::pin(42);")]
+        /// let w: PinWeak<_> = pinned.clone().into();
+        /// assert_eq!(*w.upgrade().unwrap(), 42);
+        /// ```
+        impl<T: ?Sized> From<Pin<$Rc<T>>> for PinWeak<T> {
+            fn from(rc: Pin<$Rc<T>>) -> Self {
+                Self::downgrade(rc)
+            }
+        }
+
+        /// Same as [`PinWeak::downgrade`], but without taking ownership of the strong pointer
+        ///
+        /// ```
+        #[doc = concat!("use ", module_path!(), "::*;")]
+        #[doc = concat!("let pinned = ", $rc_lit, "::pin(42);")]
+        /// let w = PinWeak::from(&pinned);
+        /// assert!(w == pinned);
+        /// ```
+        impl<T: ?Sized> From<&Pin<$Rc<T>>> for PinWeak<T> {
+            fn from(rc: &Pin<$Rc<T>>) -> Self {
+                Self::downgrade_ref(rc)
+            }
+        }
+
+        /// Same as [`PinWeak::id`] for a `PinWeak` created from this pointer
+        impl<T: ?Sized> From<&Pin<$Rc<T>>> for crate::WeakId {
+            fn from(rc: &Pin<$Rc<T>>) -> Self {
+                Self(Pin::get_ref(rc.as_ref()) as *const T as *const () as usize)
+            }
+        }
+
+        /// Same as [`PinWeak::try_upgrade`]
+        impl<T: ?Sized> TryFrom<&PinWeak<T>> for Pin<$Rc<T>> {
+            type Error = crate::UpgradeError;
+            fn try_from(weak: &PinWeak<T>) -> Result<Self, Self::Error> {
+                weak.try_upgrade()
+            }
+        }
+
+        /// Shows the address of the allocation, whether it is still alive, and the reference counts.
+        /// The value itself is never accessed.
+        impl<T: ?Sized> core::fmt::Debug for PinWeak<T> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct("PinWeak")
+                    .field("ptr", &self.addr())
+                    .field("alive", &(self.strong_count() > 0))
+                    .field("strong", &self.strong_count())
+                    .field("weak", &self.weak_count())
+                    .finish()
+            }
+        }
+
+        /// Shows the address of the allocation and whether it is still alive.
+        /// The value itself is never accessed.
+        #[cfg(feature = "defmt")]
+        impl<T: ?Sized> defmt::Format for PinWeak<T> {
+            fn format(&self, f: defmt::Formatter<'_>) {
+                defmt::write!(
+                    f,
+                    "PinWeak {{ ptr: {=usize:#x}, alive: {=bool} }}",
+                    self.addr() as usize,
+                    self.strong_count() > 0
+                )
+            }
+        }
+
+        /// Formats the address of the allocation, like the [`fmt::Pointer`](core::fmt::Pointer)
+        #[doc = concat!("implementation of `", $rc_lit, "`.")]
+        impl<T: ?Sized> core::fmt::Pointer for PinWeak<T> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                core::fmt::Pointer::fmt(&self.addr(), f)
+            }
+        }
+
+        /// The hash is based on the address of the allocation (identity), not on the value,
+        /// consistent with [`PinWeak::ptr_eq`].
+        impl<T: ?Sized> core::hash::Hash for PinWeak<T> {
+            fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+                self.addr().hash(state)
+            }
+        }
+
+        impl<T: ?Sized + Unpin> PinWeak<T> {
+            /// Returns a reference to the inner [`Weak`], or `None` if this `PinWeak` was
+            /// created with [`PinWeak::new`].
+            ///
+            /// This is only available when `T` is [`Unpin`], because the pinning guarantees are
+            /// meaningless for such types: the value can be moved out of a pin with [`Pin::into_inner`].
+            pub fn as_weak(&self) -> Option<&Weak<T>> {
+                self.weak.as_ref()
+            }
+        }
+
+        /// Conversion from a [`Weak`], which is only safe when `T` is [`Unpin`],
+        /// as the pinning guarantees are meaningless for such types.
+        impl<T: ?Sized + Unpin> From<Weak<T>> for PinWeak<T> {
+            fn from(weak: Weak<T>) -> Self {
+                Self::from_weak(weak)
+            }
+        }
+
+        /// Conversion into a [`Weak`], which is only safe when `T` is [`Unpin`],
+        /// as the pinning guarantees are meaningless for such types.
+        impl<T: Unpin> From<PinWeak<T>> for Weak<T> {
+            fn from(weak: PinWeak<T>) -> Self {
+                weak.weak.unwrap_or_default()
+            }
+        }
+
+        impl<T> PinWeak<[T]> {
+            /// Returns the length of the slice, without upgrading the weak pointer.
+            ///
+            /// The length is still available after the target was dropped, since the weak pointer
+            /// keeps the original allocation reserved. Returns 0 if this `PinWeak` was created
+            /// with [`PinWeak::new`].
+            pub fn len(&self) -> usize {
+                // Safety: `[()]` has no bytes to read, so the reference is valid as long as
+                // the pointer is not null, and the cast keeps the length
+                self.try_as_ptr().map_or(0, |ptr| unsafe { &*(ptr as *const [()]) }.len())
+            }
+
+            /// Returns true if the slice has a length of 0, see [`PinWeak::len`]
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            /// Returns a weak handle to the element at `index` of the slice, or `None` if the
+            /// index is out of bounds.
+            pub fn element(&self, index: usize) -> Option<PinWeakElement<T>> {
+                (index < self.len()).then(|| PinWeakElement { slice: self.clone(), index })
+            }
+        }
+
+        /// A weak handle to an element of a pinned slice, created with [`PinWeak::element`]
+        pub struct PinWeakElement<T> {
+            slice: PinWeak<[T]>,
+            index: usize,
+        }
+
+        impl<T> PinWeakElement<T> {
+            /// Upgrades the weak pointer to the slice. The returned guard keeps the whole slice
+            /// alive and dereferences to the element.
+            pub fn upgrade(&self) -> Option<ElementGuard<T>> {
+                Some(ElementGuard { slice: self.slice.upgrade()?, index: self.index })
+            }
+
+            /// The index of the element in the slice
+            pub fn index(&self) -> usize {
+                self.index
+            }
+
+            /// The weak pointer to the whole slice
+            pub fn slice(&self) -> &PinWeak<[T]> {
+                &self.slice
+            }
+        }
+
+        impl<T> Clone for PinWeakElement<T> {
+            fn clone(&self) -> Self {
+                Self { slice: self.slice.clone(), index: self.index }
+            }
+        }
+
+        /// Two handles are equal if they refer to the same element of the same allocation
+        impl<T> PartialEq for PinWeakElement<T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.index == other.index && self.slice.ptr_eq(&other.slice)
+            }
+        }
+
+        impl<T> Eq for PinWeakElement<T> {}
+
+        /// The result of [`PinWeakElement::upgrade`]
+        pub struct ElementGuard<T> {
+            slice: Pin<$Rc<[T]>>,
+            index: usize,
+        }
+
+        impl<T> ElementGuard<T> {
+            /// Returns a pinned reference to the element
+            pub fn as_pin_ref(&self) -> Pin<&T> {
+                // Safety: the elements of a pinned slice are pinned
+                unsafe { self.slice.as_ref().map_unchecked(|slice| &slice[self.index]) }
+            }
+
+            /// The strong pointer to the whole slice
+            pub fn slice(&self) -> &Pin<$Rc<[T]>> {
+                &self.slice
+            }
+        }
+
+        impl<T> core::ops::Deref for ElementGuard<T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.slice[self.index]
+            }
+        }
+
+        impl PinWeak<str> {
+            /// Returns the length of the string in bytes, without upgrading the weak pointer.
+            ///
+            /// The length is still available after the target was dropped, since the weak pointer
+            /// keeps the original allocation reserved. Returns 0 if this `PinWeak` was created
+            /// with [`PinWeak::new`].
+            pub fn len(&self) -> usize {
+                // Safety: same as for slices
+                self.try_as_ptr().map_or(0, |ptr| unsafe { &*(ptr as *const [()]) }.len())
+            }
+
+            /// Returns true if the string has a length of 0
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+        }
+
+        #[cfg(feature = "unsize")]
+        impl<T> PinWeak<T> {
+            /// Convert this `PinWeak` into a `PinWeak` to an unsized type, such as a trait object,
+            /// without upgrading it. This also works if the target was already dropped.
+            ///
+            /// This is a shortcut for [`unsize::CoerceUnsize::unsize`].
+            ///
+            /// ```rust
+            #[doc = concat!("use ", module_path!(), "::{PinWeak, ", $rc_lit, "};")]
+            /// use core::fmt::Debug;
+            /// use unsize::Coercion;
+            #[doc = concat!("let rc = ", $rc_lit, "::pin(42u32);")]
+            /// let weak = PinWeak::downgrade(rc.clone()).coerce::<dyn Debug, _>(Coercion!(to dyn Debug));
+            /// assert_eq!(format!("{:?}", weak.upgrade().unwrap()), "42");
+            /// ```
+            pub fn coerce<U: ?Sized, F>(self, with: unsize::Coercion<T, U, F>) -> PinWeak<U>
+            where
+                F: FnOnce(*const T) -> *const U,
+            {
+                unsize::CoerceUnsize::unsize(self, with)
+            }
+        }
+
+        // Safety: the coercion does not move the target, so it stays pinned, and replace_ptr
+        // keeps the same allocation.
+        #[cfg(feature = "unsize")]
+        unsafe impl<T, U: ?Sized> unsize::CoerciblePtr<U> for PinWeak<T> {
+            type Pointee = T;
+            type Output = PinWeak<U>;
+            fn as_sized_ptr(&mut self) -> *mut T {
+                match &self.weak {
+                    Some(weak) => weak.as_ptr() as *mut T,
+                    None => core::ptr::NonNull::dangling().as_ptr(),
+                }
+            }
+            unsafe fn replace_ptr(self, new: *mut U) -> PinWeak<U> {
+                let weak = self.weak.map(|weak| {
+                    let raw = Weak::into_raw(weak) as *mut T;
+                    Weak::from_raw(raw.replace_ptr(new) as *const U)
+                });
+                PinWeak {
+                    weak,
+                    #[cfg(feature = "tracked")]
+                    generation: self.generation,
+                }
+            }
+        }
+
+        impl<T: ?Sized + PartialEq> PinWeak<T> {
+            /// Compares the values the two weak pointers point to, if they are both alive.
+            ///
+            /// Returns `None` if either target was dropped. Unlike [`PinWeak::ptr_eq`] and the
+            /// `PartialEq` implementation of `PinWeak`, which compare the allocations, this
+            /// compares the values through shared references.
+            pub fn upgraded_eq(&self, other: &PinWeak<T>) -> Option<bool> {
+                let this = self.upgrade()?;
+                let other = other.upgrade()?;
+                Some(*this == *other)
+            }
+
+            /// Compares the value this weak pointer points to with `value`, if it is alive.
+            ///
+            /// Returns `None` if the target was dropped.
+            pub fn upgraded_eq_value(&self, value: &T) -> Option<bool> {
+                self.with_upgraded(|this| *this == *value)
+            }
+        }
+
+        /// A [`PinWeak`] that is considered dead after a deadline, even if its target is
+        /// still alive
+        ///
+        /// The time is given by a [`Clock`](crate::Clock), such as `StdClock` with the `std` feature.
+        pub struct ExpiringWeak<T: ?Sized, C: crate::Clock> {
+            weak: PinWeak<T>,
+            deadline: C::Instant,
+            clock: C,
+        }
+
+        impl<T: ?Sized, C: crate::Clock + Clone> Clone for ExpiringWeak<T, C> {
+            fn clone(&self) -> Self {
+                Self { weak: self.weak.clone(), deadline: self.deadline, clock: self.clock.clone() }
+            }
+        }
+
+        impl<T: ?Sized, C: crate::Clock> ExpiringWeak<T, C> {
+            /// Creates an `ExpiringWeak` that expires after `ttl` from now
+            pub fn new(weak: PinWeak<T>, ttl: core::time::Duration, clock: C) -> Self {
+                let deadline = clock.now() + ttl;
+                Self { weak, deadline, clock }
+            }
+
+            /// Upgrades the weak pointer, or returns `None` if the deadline has passed or
+            /// if the target was dropped
+            pub fn upgrade(&self) -> Option<Pin<$Rc<T>>> {
+                if self.is_expired() {
+                    None
+                } else {
+                    self.weak.upgrade()
+                }
+            }
+
+            /// Returns true if the deadline has passed. This does not check whether the
+            /// target is alive.
+            pub fn is_expired(&self) -> bool {
+                self.clock.now() >= self.deadline
+            }
+
+            /// Pushes the deadline back by `duration`
+            pub fn extend(&mut self, duration: core::time::Duration) {
+                self.deadline = self.deadline + duration;
+            }
+
+            /// Returns the deadline after which [`ExpiringWeak::upgrade`] returns `None`
+            pub fn expires_at(&self) -> C::Instant {
+                self.deadline
+            }
+
+            /// The underlying `PinWeak`, which does not expire
+            pub fn weak(&self) -> &PinWeak<T> {
+                &self.weak
+            }
+        }
+
+        /// A [`PinWeak`] that keeps the result of the last successful upgrade, so that it can
+        /// be accessed repeatedly without touching the reference counts.
+        ///
+        /// **While a strong reference is cached, it keeps the target alive**, like any other
+        #[doc = concat!("`Pin<", $rc_lit, "<T>>`. Call [`CachedPinWeak::release`] or [`CachedPinWeak::refresh`]")]
+        /// regularly (e.g. at the end of each frame) to let the target be dropped.
+        pub struct CachedPinWeak<T: ?Sized> {
+            weak: PinWeak<T>,
+            cached: Option<Pin<$Rc<T>>>,
+        }
+
+        impl<T: ?Sized> CachedPinWeak<T> {
+            /// Creates a `CachedPinWeak` with nothing cached yet
+            pub fn new(weak: PinWeak<T>) -> Self {
+                Self { weak, cached: None }
+            }
+
+            /// Returns the cached strong reference, upgrading the weak pointer first if nothing
+            /// is cached. Returns `None` if the target was dropped.
+            pub fn get(&mut self) -> Option<Pin<&T>> {
+                if self.cached.is_none() {
+                    self.cached = self.weak.upgrade();
+                }
+                self.cached.as_ref().map(Pin::as_ref)
+            }
+
+            /// Drops the cached strong reference, if any
+            pub fn release(&mut self) {
+                self.cached = None;
+            }
+
+            /// Drops the cached strong reference and upgrades again, returning true if the
+            /// target is still alive
+            pub fn refresh(&mut self) -> bool {
+                self.release();
+                self.get().is_some()
+            }
+
+            /// Returns true if a strong reference is currently cached
+            pub fn is_cached(&self) -> bool {
+                self.cached.is_some()
+            }
+
+            /// The underlying `PinWeak`
+            pub fn weak(&self) -> &PinWeak<T> {
+                &self.weak
+            }
+        }
+
+        impl<T: ?Sized> From<PinWeak<T>> for CachedPinWeak<T> {
+            fn from(weak: PinWeak<T>) -> Self {
+                Self::new(weak)
+            }
+        }
+
+        /// A type that can produce a [`PinWeak`] to itself
+        ///
+        /// The intended pattern is to store the weak pointer in a field of the type, initialized
+        /// with [`PinWeak::new_cyclic`], as done by [`WithWeak`] and by the `PinnedSelfRef`
+        /// derive macro, which both implement this trait.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{HasPinWeak, PinWeak, Pin, ", $rc_lit, "};")]
+        /// struct Node { me: PinWeak<Node> }
+        /// impl HasPinWeak for Node {
+        ///     fn pin_weak(&self) -> PinWeak<Self> {
+        ///         self.me.clone()
+        ///     }
+        /// }
+        #[doc = concat!("fn register<T: HasPinWeak>(object: &T) -> Pin<", $rc_lit, "<T>> {")]
+        ///     object.strong_self()
+        /// }
+        /// let node = PinWeak::new_cyclic(|me| Node { me: me.clone() });
+        /// assert!(register(&*node).pin_weak() == node);
+        /// ```
+        pub trait HasPinWeak {
+            /// Returns a weak pointer to this object
+            fn pin_weak(&self) -> PinWeak<Self>;
+
+            /// Returns a strong pointer to this object, or `None` if it is being dropped
+            fn try_strong_self(&self) -> Option<Pin<$Rc<Self>>> {
+                self.pin_weak().upgrade()
+            }
+
+            /// Returns a strong pointer to this object
+            ///
+            /// # Panics
+            ///
+            /// Panics if the object is being dropped, or if [`HasPinWeak::pin_weak`] does not
+            /// point to a live object.
+            #[track_caller]
+            fn strong_self(&self) -> Pin<$Rc<Self>> {
+                self.pin_weak().expect_upgrade("HasPinWeak::strong_self called on an object being dropped")
+            }
+        }
+
+        /// A value paired with a [`PinWeak`] to itself
+        ///
+        /// This gives a weak pointer to self to types which can't be modified to hold one.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::WithWeak;")]
+        /// let rc = WithWeak::new_pinned(42);
+        /// assert_eq!(*rc.weak().upgrade().unwrap().as_ref().get(), 42);
+        /// ```
+        pub struct WithWeak<T> {
+            value: T,
+            weak: PinWeak<WithWeak<T>>,
+        }
+
+        impl<T> WithWeak<T> {
+            /// Creates a new pinned `WithWeak` holding the value
+            pub fn new_pinned(value: T) -> Pin<$Rc<Self>> {
+                PinWeak::new_cyclic(|weak| WithWeak { value, weak: weak.clone() })
+            }
+
+            /// Returns a pinned reference to the value
+            pub fn get(self: Pin<&Self>) -> Pin<&T> {
+                // Safety: the value is structurally pinned, WithWeak never moves it or gives
+                // mutable access to it.
+                unsafe { self.map_unchecked(|this| &this.value) }
+            }
+
+            /// Returns a weak pointer to this `WithWeak`
+            pub fn weak(&self) -> PinWeak<Self> {
+                self.weak.clone()
+            }
+        }
+
+        impl<T> HasPinWeak for WithWeak<T> {
+            fn pin_weak(&self) -> PinWeak<Self> {
+                self.weak.clone()
+            }
+        }
+
+        impl<T> core::ops::Deref for WithWeak<T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.value
+            }
+        }
+
+        /// Returns a reference to the `Rc` inside the `Pin`.
+        ///
+        /// Safety: the result must not be used to move the value or to get a mutable reference.
+        unsafe fn pinned_inner<T: ?Sized>(rc: &Pin<$Rc<T>>) -> &$Rc<T> {
+            // Pin is repr(transparent)
+            &*(rc as *const Pin<$Rc<T>> as *const $Rc<T>)
+        }
+
+        #[doc = concat!("Extension methods for `Pin<", $rc_lit, "<T>>`, equivalent to the ones of [`", $rc_lit, "`].")]
+        ///
+        /// They are safe because they never expose the unpinned pointer.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{", stringify!($Ext), ", PinWeak, ", $rc_lit, "};")]
+        #[doc = concat!("let rc = ", $rc_lit, "::pin(42);")]
+        /// let weak = PinWeak::downgrade_ref(&rc);
+        /// assert_eq!((rc.strong_count(), rc.weak_count()), (1, 1));
+        /// assert!(rc.ptr_eq(&rc.clone()));
+        /// ```
+        pub trait $Ext {
+            /// The type of the target
+            type Target: ?Sized;
+            #[doc = concat!("Equivalent to [`", $rc_lit, "::strong_count`]")]
+            fn strong_count(&self) -> usize;
+            #[doc = concat!("Equivalent to [`", $rc_lit, "::weak_count`]")]
+            fn weak_count(&self) -> usize;
+            #[doc = concat!("Equivalent to [`", $rc_lit, "::ptr_eq`]")]
+            fn ptr_eq(&self, other: &Self) -> bool;
+            #[doc = concat!("Equivalent to [`", $rc_lit, "::as_ptr`]. The pointer must not be used to move the value or to mutate it.")]
+            fn as_ptr(&self) -> *const Self::Target;
+        }
+
+        impl<T: ?Sized> $Ext for Pin<$Rc<T>> {
+            type Target = T;
+            fn strong_count(&self) -> usize {
+                // Safety: only used to read the count
+                $Rc::strong_count(unsafe { pinned_inner(self) })
+            }
+            fn weak_count(&self) -> usize {
+                // Safety: only used to read the count
+                $Rc::weak_count(unsafe { pinned_inner(self) })
+            }
+            fn ptr_eq(&self, other: &Self) -> bool {
+                // Safety: only used to compare the pointers
+                $Rc::ptr_eq(unsafe { pinned_inner(self) }, unsafe { pinned_inner(other) })
+            }
+            fn as_ptr(&self) -> *const T {
+                Pin::get_ref(self.as_ref()) as *const T
+            }
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::get_mut`] for a pinned pointer, giving a `Pin<&mut T>`.")]
+        ///
+        /// Returns `None` if there are other strong or weak pointers to the same allocation,
+        /// including `PinWeak`.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{pin_get_mut, PinWeak, ", $rc_lit, "};")]
+        #[doc = concat!("let mut rc = ", $rc_lit, "::pin(41);")]
+        /// *pin_get_mut(&mut rc).unwrap() += 1;
+        /// assert_eq!(*rc, 42);
+        /// let weak = PinWeak::downgrade_ref(&rc);
+        /// assert!(pin_get_mut(&mut rc).is_none());
+        /// ```
+        pub fn pin_get_mut<T: ?Sized>(this: &mut Pin<$Rc<T>>) -> Option<Pin<&mut T>> {
+            // Safety: Pin is repr(transparent), and the mutable reference is only given out pinned
+            let rc = unsafe { &mut *(this as *mut Pin<$Rc<T>> as *mut $Rc<T>) };
+            $Rc::get_mut(rc).map(|value| unsafe { Pin::new_unchecked(value) })
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::make_mut`] for a pinned pointer, giving a `Pin<&mut T>`.")]
+        ///
+        /// If there are no other strong or weak pointers to the same allocation, the value is
+        /// mutated in place. Otherwise it is cloned into a new pinned allocation, which replaces
+        /// `this`. The original value is never moved: if `this` was its only strong pointer, it is
+        /// dropped in place and the weak pointers to it (including `PinWeak`) no longer upgrade.
+        ///
+        #[doc = concat!("Unlike [`", $rc_lit, "::make_mut`], the value is also cloned when there are only weak")]
+        /// pointers left, since moving it to a new allocation would break the pinning guarantee.
+        pub fn pin_make_mut<T: Clone>(this: &mut Pin<$Rc<T>>) -> Pin<&mut T> {
+            if pin_get_mut(this).is_none() {
+                *this = $Rc::pin(T::clone(this));
+            }
+            // we just made sure the value is not shared
+            pin_get_mut(this).unwrap()
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::try_unwrap`] for a pinned pointer to an `Unpin` type.")]
+        ///
+        /// Returns the value if `this` is its only strong pointer, otherwise returns `this`.
+        /// Like `try_unwrap`, this succeeds even if there are weak pointers, which no longer upgrade.
+        pub fn pin_try_unwrap<T: Unpin>(this: Pin<$Rc<T>>) -> Result<T, Pin<$Rc<T>>> {
+            $Rc::try_unwrap(Pin::into_inner(this)).map_err(Pin::new)
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::into_inner`] for a pinned pointer to an `Unpin` type.")]
+        ///
+        /// Returns the value if `this` is its only strong pointer, otherwise drops `this`
+        /// and returns `None`.
+        pub fn pin_into_inner<T: Unpin>(this: Pin<$Rc<T>>) -> Option<T> {
+            $Rc::into_inner(Pin::into_inner(this))
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::unwrap_or_clone`] for a pinned pointer to an `Unpin` type.")]
+        ///
+        /// Moves the value out if `this` is its only strong pointer, otherwise returns a clone.
+        ///
+        /// A value that is not `Unpin` must never be moved out of its pinned allocation, so this
+        /// function requires `T: Unpin`. For other types, use [`pin_clone_inner`], which always
+        /// clones:
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{pin_clone_inner, ", $rc_lit, "};")]
+        /// #[derive(Clone)]
+        /// struct Node(core::marker::PhantomPinned);
+        #[doc = concat!("let node: Node = pin_clone_inner(", $rc_lit, "::pin(Node(core::marker::PhantomPinned)));")]
+        /// ```
+        ///
+        /// ```compile_fail
+        #[doc = concat!("use ", module_path!(), "::{pin_unwrap_or_clone, ", $rc_lit, "};")]
+        /// #[derive(Clone)]
+        /// struct Node(core::marker::PhantomPinned);
+        #[doc = concat!("let node = pin_unwrap_or_clone(", $rc_lit, "::pin(Node(core::marker::PhantomPinned)));")]
+        /// ```
+        pub fn pin_unwrap_or_clone<T: Clone + Unpin>(this: Pin<$Rc<T>>) -> T {
+            pin_try_unwrap(this).unwrap_or_else(|rc| T::clone(&rc))
+        }
+
+        /// Returns a clone of the value and drops the pinned pointer.
+        ///
+        /// Unlike [`pin_unwrap_or_clone`], this also works for types that are not `Unpin`,
+        /// since the value is cloned even if `this` is its only strong pointer: it is never
+        /// moved out of its pinned allocation.
+        pub fn pin_clone_inner<T: Clone>(this: Pin<$Rc<T>>) -> T {
+            T::clone(&this)
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::into_raw`] for a pinned pointer.")]
+        ///
+        /// The strong reference is kept, and the pointer can be turned back into a pinned
+        /// pointer with [`pin_from_raw`]. It must never be used to move the value or to mutate it.
+        pub fn pin_into_raw<T: ?Sized>(this: Pin<$Rc<T>>) -> *const T {
+            // Safety: the pointer can only be turned back into a Pin with pin_from_raw
+            $Rc::into_raw(unsafe { Pin::into_inner_unchecked(this) })
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::from_raw`] for a pinned pointer.")]
+        ///
+        /// # Safety
+        ///
+        /// The pointer must have been obtained from [`pin_into_raw`] (and not from
+        #[doc = concat!("[`", $rc_lit, "::into_raw`], which would allow to pin a value that was moved before).")]
+        /// Each pointer returned by `pin_into_raw` owns one strong reference and must be passed
+        /// to `pin_from_raw` exactly once.
+        pub unsafe fn pin_from_raw<T: ?Sized>(ptr: *const T) -> Pin<$Rc<T>> {
+            Pin::new_unchecked($Rc::from_raw(ptr))
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::increment_strong_count`] for a pinned pointer.")]
+        ///
+        /// # Safety
+        ///
+        /// The pointer must have been obtained from [`pin_into_raw`] or [`pin_as_ptr`], and the
+        /// allocation must still be alive. The extra strong reference must be released with
+        /// [`pin_decrement_strong_count`] or by passing the pointer to [`pin_from_raw`].
+        pub unsafe fn pin_increment_strong_count<T: ?Sized>(ptr: *const T) {
+            $Rc::increment_strong_count(ptr)
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::decrement_strong_count`] for a pinned pointer.")]
+        ///
+        /// # Safety
+        ///
+        /// The pointer must have been obtained from [`pin_into_raw`] or [`pin_as_ptr`], and it must
+        /// own a strong reference, from `pin_into_raw` or [`pin_increment_strong_count`].
+        pub unsafe fn pin_decrement_strong_count<T: ?Sized>(ptr: *const T) {
+            $Rc::decrement_strong_count(ptr)
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::as_ptr`] for a pinned pointer.")]
+        ///
+        /// The pointer must never be used to move the value or to mutate it.
+        pub fn pin_as_ptr<T: ?Sized>(this: &Pin<$Rc<T>>) -> *const T {
+            Pin::get_ref(this.as_ref()) as *const T
+        }
+
+        #[doc = concat!("Equivalent to [`", $rc_lit, "::downcast`] for a pinned pointer.")]
+        ///
+        /// Returns the original pointer as an error if the value is not of type `T`.
+        pub fn pin_downcast<T: core::any::Any $(+ $auto)*>(
+            this: Pin<$Rc<dyn core::any::Any $(+ $auto)*>>,
+        ) -> Result<Pin<$Rc<T>>, Pin<$Rc<dyn core::any::Any $(+ $auto)*>>> {
+            // Safety: the value is not moved, and both results are pinned again
+            let rc = unsafe { Pin::into_inner_unchecked(this) };
+            match rc.downcast::<T>() {
+                Ok(rc) => Ok(unsafe { Pin::new_unchecked(rc) }),
+                Err(rc) => Err(unsafe { Pin::new_unchecked(rc) }),
+            }
+        }
+
+        #[doc = concat!("Same as [`", $rc_lit, "::pin`], but returns an error if the allocation fails.")]
+        ///
+        /// This requires a nightly compiler, and building with `--cfg pin_weak_allocator_api`
+        /// (for example with `RUSTFLAGS`), since it uses the unstable `allocator_api` feature.
+        #[cfg(pin_weak_allocator_api)]
+        pub fn try_pin<T>(value: T) -> Result<Pin<$Rc<T>>, core::alloc::AllocError> {
+            // Safety: nobody else had access to the unpinned Rc
+            $Rc::try_new(value).map(|rc| unsafe { Pin::new_unchecked(rc) })
+        }
+
+        #[doc = concat!("Same as [`", $rc_lit, "::pin_in`]: pins the value in a new `", $rc_lit, "` using the given allocator.")]
+        ///
+        /// [`PinWeak::downgrade`] and [`PinWeak::upgrade`] keep the allocator.
+        /// This requires a nightly compiler and `--cfg pin_weak_allocator_api`, like [`try_pin`].
+        #[cfg(pin_weak_allocator_api)]
+        pub fn pin_in<T, A: core::alloc::Allocator + 'static>(value: T, alloc: A) -> Pin<$Rc<T, A>> {
+            $Rc::pin_in(value, alloc)
+        }
+
+        #[doc = concat!("Pins the value in a new `", $rc_lit, "` and returns it along with a `PinWeak` to it.")]
+        ///
+        /// For an existing pointer, use [`PinWeak::downgrade_ref`].
+        pub fn pin_and_downgrade<T>(value: T) -> (Pin<$Rc<T>>, PinWeak<T>) {
+            let rc = $Rc::pin(value);
+            let weak = PinWeak::downgrade_ref(&rc);
+            (rc, weak)
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<[T]>>` by cloning the elements of a slice into a new allocation.")]
+        ///
+        /// Pinning a freshly created pointer is sound because nothing else has access to the
+        /// values yet, so they can't have been moved once pinned.
+        pub fn pin_from_slice<T: Clone>(slice: &[T]) -> Pin<$Rc<[T]>> {
+            // Safety: nobody else had access to the unpinned Rc
+            unsafe { Pin::new_unchecked($Rc::from(slice)) }
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<str>>` by copying a string into a new allocation.")]
+        pub fn pin_str(s: &str) -> Pin<$Rc<str>> {
+            // str is Unpin
+            Pin::new($Rc::from(s))
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<[T]>>` from the elements of an iterator, see [`pin_from_slice`].")]
+        pub fn pin_from_iter<T, I: IntoIterator<Item = T>>(iter: I) -> Pin<$Rc<[T]>> {
+            // Safety: the values were never pinned before, and nobody else had access to the unpinned Rc
+            unsafe { Pin::new_unchecked(iter.into_iter().collect()) }
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<[T]>>` by moving the elements of a `Vec`, see [`pin_from_slice`].")]
+        pub fn pin_from_vec<T>(vec: alloc::vec::Vec<T>) -> Pin<$Rc<[T]>> {
+            // Safety: the values were never pinned before, and nobody else had access to the unpinned Rc
+            unsafe { Pin::new_unchecked($Rc::from(vec)) }
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<T>>` from a `Box<T>`, which can hold an unsized value such as a trait object.")]
+        ///
+        #[doc = concat!("The value is moved into a new allocation, like with `", $rc_lit, "::from`. This is sound because")]
+        /// the `Box` was not pinned. There is intentionally no equivalent taking a `Pin<Box<T>>`,
+        /// since it would move a pinned value.
+        pub fn pin_from_box<T: ?Sized>(b: alloc::boxed::Box<T>) -> Pin<$Rc<T>> {
+            // Safety: the value was not pinned before, and nobody else had access to the unpinned Rc
+            unsafe { Pin::new_unchecked($Rc::from(b)) }
+        }
+
+        #[doc = concat!("Creates a `Pin<", $rc_lit, "<str>>` from a `String`.")]
+        pub fn pin_from_string(s: alloc::string::String) -> Pin<$Rc<str>> {
+            Pin::new($Rc::from(s))
+        }
+
+        #[doc = concat!("A wrapper around a `Pin<", $rc_lit, "<T>>` that is compared, ordered, and hashed by the")]
+        /// address of the allocation, so it can be used as the key of a map by identity.
+        ///
+        /// It implements `Borrow<WeakId>`, so a map can also be queried with [`PinWeak::id`].
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{ByAddress, PinWeak, ", $rc_lit, "};")]
+        /// use std::collections::HashMap;
+        /// struct Node;
+        #[doc = concat!("let node = ", $rc_lit, "::pin(Node);")]
+        /// let weak = PinWeak::downgrade_ref(&node);
+        /// let mut map = HashMap::new();
+        /// map.insert(ByAddress::new(node.clone()), "metadata");
+        /// assert_eq!(map[&ByAddress::new(node)], "metadata");
+        /// assert_eq!(map[&weak.id()], "metadata");
+        /// ```
+        pub struct ByAddress<T: ?Sized> {
+            rc: Pin<$Rc<T>>,
+            id: crate::WeakId,
+        }
+
+        impl<T: ?Sized> ByAddress<T> {
+            /// Wraps the pointer
+            pub fn new(rc: Pin<$Rc<T>>) -> Self {
+                let id = crate::WeakId::from(&rc);
+                Self { rc, id }
+            }
+
+            /// Returns a pinned reference to the value
+            pub fn as_pin_ref(&self) -> Pin<&T> {
+                self.rc.as_ref()
+            }
+
+            /// Returns the wrapped pointer
+            pub fn get(&self) -> &Pin<$Rc<T>> {
+                &self.rc
+            }
+
+            /// Returns the [`WeakId`](crate::WeakId) of the allocation
+            pub fn id(&self) -> crate::WeakId {
+                self.id
+            }
+
+            /// Returns the wrapped pointer
+            pub fn into_inner(self) -> Pin<$Rc<T>> {
+                self.rc
+            }
+        }
+
+        impl<T: ?Sized> Clone for ByAddress<T> {
+            fn clone(&self) -> Self {
+                Self { rc: self.rc.clone(), id: self.id }
+            }
+        }
+
+        impl<T: ?Sized> core::ops::Deref for ByAddress<T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.rc
+            }
+        }
+
+        impl<T: ?Sized> From<Pin<$Rc<T>>> for ByAddress<T> {
+            fn from(rc: Pin<$Rc<T>>) -> Self {
+                Self::new(rc)
+            }
+        }
+
+        impl<T: ?Sized> PartialEq for ByAddress<T> {
+            fn eq(&self, other: &Self) -> bool {
+                self.id == other.id
+            }
+        }
+
+        impl<T: ?Sized> Eq for ByAddress<T> {}
+
+        impl<T: ?Sized> PartialOrd for ByAddress<T> {
+            fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl<T: ?Sized> Ord for ByAddress<T> {
+            fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+                self.id.cmp(&other.id)
+            }
+        }
+
+        /// Hashes the same way as the [`WeakId`](crate::WeakId), which is required for `Borrow`
+        impl<T: ?Sized> core::hash::Hash for ByAddress<T> {
+            fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+                self.id.hash(state)
+            }
+        }
+
+        impl<T: ?Sized> core::borrow::Borrow<crate::WeakId> for ByAddress<T> {
+            fn borrow(&self) -> &crate::WeakId {
+                &self.id
+            }
+        }
+
+        /// A hash map whose values are [`PinWeak`], so the map does not keep them alive.
+        /// Requires the `std` feature.
+        ///
+        /// The entries whose value was dropped are removed when they are looked up, or with
+        /// [`WeakValueHashMap::prune`].
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{", $rc_lit, ", WeakValueHashMap};")]
+        /// struct Image;
+        /// let mut cache = WeakValueHashMap::new();
+        #[doc = concat!("let image = ", $rc_lit, "::pin(Image);")]
+        /// cache.insert(String::from("logo.png"), &image);
+        /// assert!(cache.get("logo.png").is_some());
+        /// drop(image);
+        /// assert!(cache.get("logo.png").is_none());
+        /// assert!(cache.is_empty());
+        /// ```
+        #[cfg(feature = "std")]
+        pub struct WeakValueHashMap<K, T: ?Sized> {
+            map: std::collections::HashMap<K, PinWeak<T>>,
+        }
+
+        #[cfg(feature = "std")]
+        impl<K, T: ?Sized> WeakValueHashMap<K, T> {
+            /// Creates an empty map
+            pub fn new() -> Self {
+                Self { map: std::collections::HashMap::new() }
+            }
+
+            /// Returns the number of entries, including those whose value was dropped
+            pub fn len(&self) -> usize {
+                self.map.len()
+            }
+
+            /// Returns true if the map has no entries, including those whose value was dropped
+            pub fn is_empty(&self) -> bool {
+                self.map.is_empty()
+            }
+
+            /// Returns the number of entries whose value is still alive
+            pub fn alive_len(&self) -> usize {
+                self.map.values().filter(|w| w.strong_count() > 0).count()
+            }
+
+            /// Removes the entries whose value was dropped, and returns how many were removed
+            pub fn prune(&mut self) -> usize {
+                let len = self.map.len();
+                self.map.retain(|_, w| w.strong_count() > 0);
+                len - self.map.len()
+            }
+
+            /// Iterates over the entries whose value is still alive, upgrading them
+            pub fn iter(&self) -> impl Iterator<Item = (&K, Pin<$Rc<T>>)> {
+                self.map.iter().filter_map(|(k, w)| Some((k, w.upgrade()?)))
+            }
+        }
+
+        #[cfg(feature = "std")]
+        impl<K: Eq + core::hash::Hash, T: ?Sized> WeakValueHashMap<K, T> {
+            /// Inserts a weak pointer to `value`, and returns the previous value if it was
+            /// still alive
+            pub fn insert(&mut self, key: K, value: &Pin<$Rc<T>>) -> Option<Pin<$Rc<T>>> {
+                self.map.insert(key, PinWeak::downgrade_ref(value))?.upgrade()
+            }
+
+            /// Returns the value of `key` if it is still alive, otherwise removes the entry
+            pub fn get<Q>(&mut self, key: &Q) -> Option<Pin<$Rc<T>>>
+            where
+                K: core::borrow::Borrow<Q>,
+                Q: ?Sized + Eq + core::hash::Hash,
+            {
+                let value = self.map.get(key)?.upgrade();
+                if value.is_none() {
+                    self.map.remove(key);
+                }
+                value
+            }
+
+            /// Removes the entry of `key`, and returns its value if it was still alive
+            pub fn remove<Q>(&mut self, key: &Q) -> Option<Pin<$Rc<T>>>
+            where
+                K: core::borrow::Borrow<Q>,
+                Q: ?Sized + Eq + core::hash::Hash,
+            {
+                self.map.remove(key)?.upgrade()
+            }
+        }
+
+        #[cfg(feature = "std")]
+        impl<K, T: ?Sized> Default for WeakValueHashMap<K, T> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        /// A hash map whose keys are pinned objects held with [`PinWeak`], to associate data
+        /// with objects without keeping them alive. Requires the `std` feature.
+        ///
+        /// The keys are compared by the identity of the allocation. The value of an object
+        /// that was dropped is dropped when the entry is removed, by [`WeakKeyHashMap::prune`]
+        /// or [`WeakKeyHashMap::insert`].
+        ///
+        /// Because each entry holds a weak pointer to its key, the memory of the key is not
+        /// released while it is in the map, so a new object cannot reuse its address and
+        /// match a stale entry.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{", $rc_lit, ", WeakKeyHashMap};")]
+        /// struct Widget;
+        /// let mut tooltips = WeakKeyHashMap::new();
+        #[doc = concat!("let button = ", $rc_lit, "::pin(Widget);")]
+        /// tooltips.insert(&button, "Click me");
+        /// assert_eq!(tooltips.get(&button), Some(&"Click me"));
+        /// drop(button);
+        /// assert_eq!(tooltips.prune(), 1);
+        /// ```
+        #[cfg(feature = "std")]
+        pub struct WeakKeyHashMap<K: ?Sized, V> {
+            map: std::collections::HashMap<crate::WeakId, (PinWeak<K>, V)>,
+        }
+
+        #[cfg(feature = "std")]
+        impl<K: ?Sized, V> WeakKeyHashMap<K, V> {
+            /// Creates an empty map
+            pub fn new() -> Self {
+                Self { map: std::collections::HashMap::new() }
+            }
+
+            /// Inserts a value for `key`, and returns the previous one.
+            ///
+            /// This also removes the entries whose key was dropped, when the map needs to grow.
+            pub fn insert(&mut self, key: &Pin<$Rc<K>>, value: V) -> Option<V> {
+                if self.map.len() == self.map.capacity() {
+                    self.prune();
+                }
+                self.map.insert(key.into(), (PinWeak::downgrade_ref(key), value)).map(|(_, v)| v)
+            }
+
+            /// Returns the value associated with `key`
+            pub fn get(&self, key: &Pin<$Rc<K>>) -> Option<&V> {
+                self.map.get(&key.into()).map(|(_, v)| v)
+            }
+
+            /// Returns the value associated with `key`
+            pub fn get_mut(&mut self, key: &Pin<$Rc<K>>) -> Option<&mut V> {
+                self.map.get_mut(&key.into()).map(|(_, v)| v)
+            }
+
+            /// Removes the entry of `key`, and returns its value
+            pub fn remove(&mut self, key: &Pin<$Rc<K>>) -> Option<V> {
+                self.map.remove(&key.into()).map(|(_, v)| v)
+            }
+
+            /// Removes the entries whose key was dropped, and returns how many were removed
+            pub fn prune(&mut self) -> usize {
+                let len = self.map.len();
+                self.map.retain(|_, (k, _)| k.strong_count() > 0);
+                len - self.map.len()
+            }
+
+            /// Returns the number of entries, including those whose key was dropped
+            pub fn len(&self) -> usize {
+                self.map.len()
+            }
+
+            /// Returns true if the map has no entries, including those whose key was dropped
+            pub fn is_empty(&self) -> bool {
+                self.map.is_empty()
+            }
+        }
+
+        #[cfg(feature = "std")]
+        impl<K: ?Sized, V> Default for WeakKeyHashMap<K, V> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        /// An integer identifying an object in a [`HandleRegistry`]
+        ///
+        /// It can be converted to and from a `u64` to be given to code which can only hold
+        /// integers.
+        #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+        pub struct Handle(u64);
+
+        impl From<Handle> for u64 {
+            fn from(handle: Handle) -> u64 {
+                handle.0
+            }
+        }
+
+        impl From<u64> for Handle {
+            fn from(value: u64) -> Handle {
+                Handle(value)
+            }
+        }
+
+        /// Associates integer [`Handle`]s to pinned objects, held with [`PinWeak`]
+        ///
+        /// A registry never gives the same handle to two different objects, even after an
+        /// object was removed.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{HandleRegistry, ", $rc_lit, "};")]
+        /// let mut registry = HandleRegistry::new();
+        #[doc = concat!("let object = ", $rc_lit, "::pin(42);")]
+        /// let handle = registry.insert(&object);
+        /// assert_eq!(registry.resolve(handle).map(|o| *o), Some(42));
+        /// drop(object);
+        /// assert!(registry.resolve(handle).is_none());
+        /// ```
+        pub struct HandleRegistry<T: ?Sized> {
+            entries: alloc::collections::BTreeMap<Handle, PinWeak<T>>,
+            handles: alloc::collections::BTreeMap<crate::WeakId, Handle>,
+            next_handle: u64,
+        }
+
+        impl<T: ?Sized> HandleRegistry<T> {
+            /// Creates an empty registry
+            pub const fn new() -> Self {
+                Self {
+                    entries: alloc::collections::BTreeMap::new(),
+                    handles: alloc::collections::BTreeMap::new(),
+                    next_handle: 1,
+                }
+            }
+
+            /// Returns the handle of `object`, adding it to the registry if it is not there
+            /// yet.
+            pub fn insert(&mut self, object: &Pin<$Rc<T>>) -> Handle {
+                let next_handle = &mut self.next_handle;
+                let entries = &mut self.entries;
+                *self.handles.entry(object.into()).or_insert_with(|| {
+                    let handle = Handle(*next_handle);
+                    *next_handle += 1;
+                    entries.insert(handle, PinWeak::downgrade_ref(object));
+                    handle
+                })
+            }
+
+            /// Returns the object of `handle`, if it is in the registry and alive
+            pub fn resolve(&self, handle: Handle) -> Option<Pin<$Rc<T>>> {
+                self.entries.get(&handle)?.upgrade()
+            }
+
+            /// Removes `handle` from the registry. Returns false if it was not there.
+            pub fn remove(&mut self, handle: Handle) -> bool {
+                match self.entries.remove(&handle) {
+                    Some(weak) => {
+                        self.handles.remove(&weak.id());
+                        true
+                    }
+                    None => false,
+                }
+            }
+
+            /// Removes the handles whose object was dropped, and returns how many were removed.
+            /// The other handles are unchanged.
+            pub fn compact(&mut self) -> usize {
+                let len = self.entries.len();
+                let handles = &mut self.handles;
+                self.entries.retain(|_, weak| {
+                    let alive = weak.strong_count() > 0;
+                    if !alive {
+                        handles.remove(&weak.id());
+                    }
+                    alive
+                });
+                len - self.entries.len()
+            }
+
+            /// Returns the number of handles, including those whose object was dropped
+            pub fn len(&self) -> usize {
+                self.entries.len()
+            }
+
+            /// Returns true if the registry has no handles
+            pub fn is_empty(&self) -> bool {
+                self.entries.is_empty()
+            }
+        }
+
+        impl<T: ?Sized> Default for HandleRegistry<T> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        #[doc = concat!("Either a `Pin<", $rc_lit, "<T>>` or a [`PinWeak<T>`], which can be switched between the two")]
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{MaybeStrong, PinWeak, ", $rc_lit, "};")]
+        #[doc = concat!("let child = MaybeStrong::Strong(", $rc_lit, "::pin(42));")]
+        /// let mut child2 = child.clone();
+        /// child2.make_weak();
+        /// assert!(child.ptr_eq(&child2));
+        /// drop(child);
+        /// assert!(!child2.make_strong());
+        /// ```
+        pub enum MaybeStrong<T: ?Sized> {
+            /// Keeps the target alive
+            Strong(Pin<$Rc<T>>),
+            /// Does not keep the target alive
+            Weak(PinWeak<T>),
+        }
+
+        impl<T: ?Sized> MaybeStrong<T> {
+            /// Returns a strong pointer to the target, if it is alive
+            pub fn upgrade(&self) -> Option<Pin<$Rc<T>>> {
+                match self {
+                    Self::Strong(rc) => Some(rc.clone()),
+                    Self::Weak(weak) => weak.upgrade(),
+                }
+            }
+
+            /// Switches to a weak pointer
+            pub fn make_weak(&mut self) {
+                if let Self::Strong(rc) = self {
+                    *self = Self::Weak(PinWeak::downgrade_ref(rc));
+                }
+            }
+
+            /// Switches to a strong pointer. Returns false, and stays weak, if the target was
+            /// dropped.
+            pub fn make_strong(&mut self) -> bool {
+                match self {
+                    Self::Strong(_) => true,
+                    Self::Weak(weak) => match weak.upgrade() {
+                        Some(rc) => {
+                            *self = Self::Strong(rc);
+                            true
+                        }
+                        None => false,
+                    },
+                }
+            }
+
+            /// Returns true if this is a strong pointer
+            pub fn is_strong(&self) -> bool {
+                matches!(self, Self::Strong(_))
+            }
+
+            /// Returns a weak pointer to the target
+            pub fn downgrade(&self) -> PinWeak<T> {
+                match self {
+                    Self::Strong(rc) => PinWeak::downgrade_ref(rc),
+                    Self::Weak(weak) => weak.clone(),
+                }
+            }
+
+            fn id(&self) -> crate::WeakId {
+                match self {
+                    Self::Strong(rc) => rc.into(),
+                    Self::Weak(weak) => weak.id(),
+                }
+            }
+
+            /// Returns true if both point to the same allocation, whether they are strong or weak
+            pub fn ptr_eq(&self, other: &Self) -> bool {
+                self.id() == other.id()
+            }
+
+            /// Returns true if `weak` points to the same allocation
+            pub fn ptr_eq_weak(&self, weak: &PinWeak<T>) -> bool {
+                self.id() == weak.id()
+            }
+
+            /// Returns true if `rc` points to the same allocation
+            pub fn ptr_eq_strong(&self, rc: &Pin<$Rc<T>>) -> bool {
+                self.id() == crate::WeakId::from(rc)
+            }
+        }
+
+        impl<T: ?Sized> Clone for MaybeStrong<T> {
+            fn clone(&self) -> Self {
+                match self {
+                    Self::Strong(rc) => Self::Strong(rc.clone()),
+                    Self::Weak(weak) => Self::Weak(weak.clone()),
+                }
+            }
+        }
+
+        impl<T: ?Sized> From<Pin<$Rc<T>>> for MaybeStrong<T> {
+            fn from(rc: Pin<$Rc<T>>) -> Self {
+                Self::Strong(rc)
+            }
+        }
+
+        impl<T: ?Sized> From<PinWeak<T>> for MaybeStrong<T> {
+            fn from(weak: PinWeak<T>) -> Self {
+                Self::Weak(weak)
+            }
+        }
+
+        /// Strong pointers obtained by upgrading several [`PinWeak`] at once, which are released
+        /// when it is dropped
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{PinWeak, Strongify, ", $rc_lit, "};")]
+        #[doc = concat!("let children = [", $rc_lit, "::pin(1), ", $rc_lit, "::pin(2)];")]
+        /// let weaks: Vec<_> = children.iter().map(PinWeak::downgrade_ref).collect();
+        /// let strong = Strongify::new(&weaks);
+        /// assert_eq!(strong.iter().map(|c| *c).sum::<i32>(), 3);
+        /// ```
+        pub struct Strongify<T: ?Sized> {
+            strong: alloc::vec::Vec<Pin<$Rc<T>>>,
+            dead_count: usize,
+        }
+
+        impl<T: ?Sized> Strongify<T> {
+            /// Upgrades all the weak pointers, skipping those whose target was dropped
+            pub fn new<'a>(weaks: impl IntoIterator<Item = &'a PinWeak<T>>) -> Self
+            where
+                T: 'a,
+            {
+                let mut dead_count = 0;
+                let strong = weaks
+                    .into_iter()
+                    .filter_map(|w| {
+                        let rc = w.upgrade();
+                        dead_count += rc.is_none() as usize;
+                        rc
+                    })
+                    .collect();
+                Self { strong, dead_count }
+            }
+
+            /// Upgrades all the weak pointers, or returns `None` if any of their target was
+            /// dropped
+            pub fn all_or_nothing<'a>(weaks: impl IntoIterator<Item = &'a PinWeak<T>>) -> Option<Self>
+            where
+                T: 'a,
+            {
+                let strong = weaks.into_iter().map(PinWeak::upgrade).collect::<Option<_>>()?;
+                Some(Self { strong, dead_count: 0 })
+            }
+
+            /// Iterates over the targets which were alive, in order
+            pub fn iter(&self) -> impl Iterator<Item = Pin<&T>> {
+                self.strong.iter().map(|rc| rc.as_ref())
+            }
+
+            /// Returns the number of weak pointers whose target was dropped
+            pub fn dead_count(&self) -> usize {
+                self.dead_count
+            }
+        }
+
+        /// A weak pointer which can be upgraded by [`PinWeakIterExt::upgraded`], implemented for
+        /// `PinWeak<T>` and `&PinWeak<T>`
+        pub trait UpgradeItem {
+            /// The type of the target
+            type Target: ?Sized;
+            /// Upgrades the weak pointer
+            fn upgrade_item(self) -> Option<Pin<$Rc<Self::Target>>>;
+        }
+
+        impl<T: ?Sized> UpgradeItem for PinWeak<T> {
+            type Target = T;
+            fn upgrade_item(self) -> Option<Pin<$Rc<T>>> {
+                self.upgrade()
+            }
+        }
+
+        impl<T: ?Sized> UpgradeItem for &PinWeak<T> {
+            type Target = T;
+            fn upgrade_item(self) -> Option<Pin<$Rc<T>>> {
+                self.upgrade()
+            }
+        }
+
+        #[doc = concat!("A strong pointer which can be downgraded by [`PinWeakIterExt::downgraded`], implemented for `Pin<", $rc_lit, "<T>>` and `&Pin<", $rc_lit, "<T>>`")]
+        pub trait DowngradeItem {
+            /// The type of the target
+            type Target: ?Sized;
+            /// Creates a weak pointer
+            fn downgrade_item(self) -> PinWeak<Self::Target>;
+        }
+
+        impl<T: ?Sized> DowngradeItem for Pin<$Rc<T>> {
+            type Target = T;
+            fn downgrade_item(self) -> PinWeak<T> {
+                PinWeak::downgrade(self)
+            }
+        }
+
+        impl<T: ?Sized> DowngradeItem for &Pin<$Rc<T>> {
+            type Target = T;
+            fn downgrade_item(self) -> PinWeak<T> {
+                PinWeak::downgrade_ref(self)
+            }
+        }
+
+        impl<T: ?Sized> crate::PinnedStrong for Pin<$Rc<T>> {
+            type Weak = PinWeak<T>;
+            fn downgrade(&self) -> PinWeak<T> {
+                PinWeak::downgrade_ref(self)
+            }
+            fn as_pin_ref(&self) -> Pin<&T> {
+                self.as_ref()
+            }
+        }
+
+        impl<T: ?Sized> crate::PinnedWeak for PinWeak<T> {
+            type Target = T;
+            type Strong = Pin<$Rc<T>>;
+            fn upgrade(&self) -> Option<Pin<$Rc<T>>> {
+                PinWeak::upgrade(self)
+            }
+            fn ptr_eq(&self, other: &Self) -> bool {
+                PinWeak::ptr_eq(self, other)
+            }
+            fn strong_count(&self) -> usize {
+                PinWeak::strong_count(self)
+            }
+        }
+
+        /// Extension methods for iterators of weak and strong pointers
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{PinWeakIterExt, ", $rc_lit, "};")]
+        #[doc = concat!("let values = vec![", $rc_lit, "::pin(1), ", $rc_lit, "::pin(2)];")]
+        /// let weaks: Vec<_> = values.iter().downgraded().collect();
+        /// assert_eq!(weaks.iter().upgraded().map(|v| *v).sum::<i32>(), 3);
+        /// ```
+        pub trait PinWeakIterExt: Iterator + Sized {
+            /// Upgrades the weak pointers, skipping those whose target was dropped
+            fn upgraded(self) -> Upgraded<Self>
+            where
+                Self::Item: UpgradeItem,
+            {
+                Upgraded { iter: self }
+            }
+
+            /// Creates weak pointers from the strong pointers
+            fn downgraded(self) -> Downgraded<Self>
+            where
+                Self::Item: DowngradeItem,
+            {
+                Downgraded { iter: self }
+            }
+        }
+
+        impl<I: Iterator> PinWeakIterExt for I {}
+
+        /// The iterator returned by [`PinWeakIterExt::upgraded`]
+        #[derive(Clone, Debug)]
+        pub struct Upgraded<I> {
+            iter: I,
+        }
+
+        impl<I: Iterator> Iterator for Upgraded<I>
+        where
+            I::Item: UpgradeItem,
+        {
+            type Item = Pin<$Rc<<I::Item as UpgradeItem>::Target>>;
+            fn next(&mut self) -> Option<Self::Item> {
+                self.iter.by_ref().find_map(UpgradeItem::upgrade_item)
+            }
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (0, self.iter.size_hint().1)
+            }
+        }
+
+        /// The iterator returned by [`PinWeakIterExt::downgraded`]
+        #[derive(Clone, Debug)]
+        pub struct Downgraded<I> {
+            iter: I,
+        }
+
+        impl<I: Iterator> Iterator for Downgraded<I>
+        where
+            I::Item: DowngradeItem,
+        {
+            type Item = PinWeak<<I::Item as DowngradeItem>::Target>;
+            fn next(&mut self) -> Option<Self::Item> {
+                self.iter.next().map(DowngradeItem::downgrade_item)
+            }
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.iter.size_hint()
+            }
+        }
+
+        /// A list of up to `N` [`PinWeak`] stored inline, which never allocates. Requires the
+        /// `heapless` feature.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{PinWeak, PinWeakList, ", $rc_lit, "};")]
+        /// let mut observers = PinWeakList::<u32, 2>::new();
+        #[doc = concat!("let (a, b, c) = (", $rc_lit, "::pin(1), ", $rc_lit, "::pin(2), ", $rc_lit, "::pin(3));")]
+        /// observers.push(PinWeak::downgrade_ref(&a)).unwrap();
+        /// observers.push(PinWeak::downgrade_ref(&b)).unwrap();
+        /// assert!(observers.push(PinWeak::downgrade_ref(&c)).is_err());
+        /// drop(a);
+        /// observers.push(PinWeak::downgrade_ref(&c)).unwrap();
+        /// ```
+        #[cfg(feature = "heapless")]
+        pub struct PinWeakList<T: ?Sized, const N: usize> {
+            entries: heapless::Vec<PinWeak<T>, N>,
+        }
+
+        #[cfg(feature = "heapless")]
+        impl<T: ?Sized, const N: usize> PinWeakList<T, N> {
+            /// Creates an empty list
+            pub const fn new() -> Self {
+                Self { entries: heapless::Vec::new() }
+            }
+
+            /// Adds a weak pointer at the end of the list. If the list is full, the entries
+            /// whose target was dropped are removed first, and the pointer is given back if
+            /// there is still no room.
+            pub fn push(&mut self, weak: PinWeak<T>) -> Result<(), PinWeak<T>> {
+                if self.entries.is_full() {
+                    self.entries.retain(|w| w.strong_count() > 0);
+                }
+                self.entries.push(weak)
+            }
+
+            /// Calls `f` with each target that is still alive, in insertion order, and removes
+            /// the dead entries from the list.
+            pub fn for_each_alive(&mut self, mut f: impl FnMut(Pin<&T>)) {
+                self.entries.retain(|w| w.with_upgraded(&mut f).is_some());
+            }
+
+            /// Removes all the entries
+            pub fn clear(&mut self) {
+                self.entries.clear();
+            }
+
+            /// Returns the number of entries, including those whose target was dropped
+            pub fn len(&self) -> usize {
+                self.entries.len()
+            }
+
+            /// Returns true if the list has no entries, including dead ones
+            pub fn is_empty(&self) -> bool {
+                self.entries.is_empty()
+            }
+
+            /// Returns the number of entries whose target is still alive
+            pub fn alive_len(&self) -> usize {
+                self.entries.iter().filter(|w| w.strong_count() > 0).count()
+            }
+        }
+
+        #[cfg(feature = "heapless")]
+        impl<T: ?Sized, const N: usize> Default for PinWeakList<T, N> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        /// A collection of weak pointers that can be upgraded all at once with [`upgrade_all`]
+        ///
+        /// This is implemented for tuples of up to 12 `&PinWeak`, and for slices of `PinWeak`.
+        pub trait UpgradeAll {
+            /// The strong pointers
+            type Output;
+            /// Upgrades all the weak pointers, or returns `None` if any of their target was dropped
+            fn upgrade_all(self) -> Option<Self::Output>;
+        }
+
+        upgrade_all_tuples!($Rc; A a, B b, C c, D d, E e, F f, G g, H h, I i, J j, K k, L l);
+
+        impl<'a, T: ?Sized> UpgradeAll for &'a [PinWeak<T>] {
+            type Output = alloc::vec::Vec<Pin<$Rc<T>>>;
+            fn upgrade_all(self) -> Option<Self::Output> {
+                self.iter().map(PinWeak::upgrade).collect()
+            }
+        }
+
+        /// Removes the weak pointers whose target was dropped, keeping the order of the others.
+        /// Returns the number of removed pointers.
+        pub fn prune<T: ?Sized>(weaks: &mut alloc::vec::Vec<PinWeak<T>>) -> usize {
+            let len = weaks.len();
+            weaks.retain(|w| w.strong_count() > 0);
+            len - weaks.len()
+        }
+
+        /// Returns true if one of the weak pointers points to the same allocation as `rc`
+        pub fn contains_ptr<T: ?Sized>(weaks: &[PinWeak<T>], rc: &Pin<$Rc<T>>) -> bool {
+            weaks.iter().any(|w| w.ptr_eq_strong(rc))
+        }
+
+        /// Upgrades the weak pointers whose target is alive, in order, and skips the others.
+        ///
+        /// Unlike [`upgrade_all`], this does not fail if some of the targets were dropped.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{upgrade_live, PinWeak, ", $rc_lit, "};")]
+        #[doc = concat!("let (a, b) = (", $rc_lit, "::pin(1), ", $rc_lit, "::pin(2));")]
+        /// let weaks = [PinWeak::downgrade_ref(&a), PinWeak::downgrade(b)];
+        /// assert_eq!(upgrade_live(&weaks).iter().map(|v| **v).collect::<Vec<_>>(), [1]);
+        /// ```
+        pub fn upgrade_live<T: ?Sized>(weaks: &[PinWeak<T>]) -> alloc::vec::Vec<Pin<$Rc<T>>> {
+            weaks.iter().filter_map(PinWeak::upgrade).collect()
+        }
+
+        /// Removes the weak pointers pointing to the same allocation as a previous one, keeping
+        /// the order of the others.
+        pub fn dedup_by_ptr<T: ?Sized>(weaks: &mut alloc::vec::Vec<PinWeak<T>>) {
+            let mut seen = alloc::collections::BTreeSet::new();
+            weaks.retain(|w| seen.insert(w.id()));
+        }
+
+        /// Upgrades all the weak pointers of a tuple or a slice, or none of them.
+        ///
+        /// To only upgrade the pointers whose target is alive, use [`upgrade_live`] or
+        /// [`PinWeakIterExt::upgraded`].
+        ///
+        /// Returns `None` if any of the targets was dropped, in which case the strong
+        /// pointers that were already obtained are released.
+        ///
+        /// ```rust
+        #[doc = concat!("use ", module_path!(), "::{upgrade_all, PinWeak, ", $rc_lit, "};")]
+        #[doc = concat!("let (a, b) = (", $rc_lit, "::pin(1), ", $rc_lit, "::pin(\"b\"));")]
+        /// let (wa, wb) = (PinWeak::downgrade(a.clone()), PinWeak::downgrade(b.clone()));
+        /// let (a2, b2) = upgrade_all((&wa, &wb)).unwrap();
+        /// assert_eq!((*a2, *b2), (1, "b"));
+        /// drop((a, a2));
+        /// assert!(upgrade_all((&wa, &wb)).is_none());
+        /// ```
+        pub fn upgrade_all<W: UpgradeAll>(weaks: W) -> Option<W::Output> {
+            weaks.upgrade_all()
+        }
+
+        /// A temporary strong reference obtained with [`PinWeak::guard`]
+        ///
+        /// It only gives shared access to the value, through [`Deref`](core::ops::Deref) or
+        /// [`UpgradeGuard::as_pin_ref`]. The strong reference is released when the guard is dropped.
+        pub struct UpgradeGuard<'a, T: ?Sized> {
+            strong: Pin<$Rc<T>>,
+            _weak: core::marker::PhantomData<&'a PinWeak<T>>,
+        }
+
+        impl<T: ?Sized> UpgradeGuard<'_, T> {
+            /// Returns a pinned reference to the value
+            pub fn as_pin_ref(&self) -> Pin<&T> {
+                self.strong.as_ref()
+            }
+        }
+
+        impl<T: ?Sized> core::ops::Deref for UpgradeGuard<'_, T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.strong
+            }
+        }
+
+        impl<T: ?Sized + 'static> PinWeak<T> {
+            /// Creates a [`MappedPinWeak`] that gives access to a part of the target (such as a
+            /// field) through the projection `f`, without exposing the rest of it.
+            ///
+            /// ```rust
+            #[doc = concat!("use ", module_path!(), "::{PinWeak, ", $rc_lit, ", Pin};")]
+            /// struct State { name: String, count: u32 }
+            #[doc = concat!("let rc = ", $rc_lit, "::pin(State { name: \"x\".into(), count: 42 });")]
+            /// let count = PinWeak::downgrade(rc.clone()).map(|s| Pin::new(&s.get_ref().count));
+            /// assert_eq!(count.with_upgraded(|c| *c), Some(42));
+            /// ```
+            pub fn map<U: ?Sized + 'static>(self, f: fn(Pin<&T>) -> Pin<&U>) -> MappedPinWeak<T, U> {
+                MappedPinWeak { weak: self, projection: $Rc::new(f) }
+            }
+        }
+
+        /// A [`PinWeak`] with a projection to a part of its target, created with [`PinWeak::map`]
+        pub struct MappedPinWeak<T: ?Sized, U: ?Sized> {
+            weak: PinWeak<T>,
+            projection: $Rc<dyn Fn(Pin<&T>) -> Pin<&U> $(+ $auto)*>,
+        }
+
+        impl<T: ?Sized, U: ?Sized> Clone for MappedPinWeak<T, U> {
+            fn clone(&self) -> Self {
+                Self { weak: self.weak.clone(), projection: self.projection.clone() }
+            }
+        }
+
+        impl<T: ?Sized, U: ?Sized> MappedPinWeak<T, U> {
+            /// Upgrades the underlying [`PinWeak`]. The returned [`MappedPin`] keeps the whole
+            /// target alive and dereferences to the projected part.
+            pub fn upgrade(&self) -> Option<MappedPin<T, U>> {
+                Some(MappedPin { owner: self.weak.upgrade()?, projection: self.projection.clone() })
+            }
+
+            /// Calls `f` with the projected part if the target is still alive, keeping the target
+            /// alive for the duration of the call.
+            pub fn with_upgraded<R>(&self, f: impl FnOnce(Pin<&U>) -> R) -> Option<R> {
+                self.weak.with_upgraded(|target| f((self.projection)(target)))
+            }
+
+            /// The underlying `PinWeak` to the whole target
+            pub fn base(&self) -> &PinWeak<T> {
+                &self.weak
+            }
+        }
+
+        impl<T: ?Sized + 'static, U: ?Sized + 'static> MappedPinWeak<T, U> {
+            /// Projects further to a part of `U`, like [`PinWeak::map`]
+            pub fn map<V: ?Sized + 'static>(self, f: fn(Pin<&U>) -> Pin<&V>) -> MappedPinWeak<T, V> {
+                fn chain<T: ?Sized + 'static, U: ?Sized + 'static, V: ?Sized + 'static>(
+                    first: impl Fn(Pin<&T>) -> Pin<&U>,
+                    second: fn(Pin<&U>) -> Pin<&V>,
+                ) -> impl Fn(Pin<&T>) -> Pin<&V> {
+                    move |target| second(first(target))
+                }
+                let first = self.projection;
+                MappedPinWeak {
+                    weak: self.weak,
+                    projection: $Rc::new(chain(move |target| first(target), f)),
+                }
+            }
+        }
+
+        impl<T: ?Sized, U: ?Sized> From<MappedPinWeak<T, U>> for PinWeak<T> {
+            fn from(mapped: MappedPinWeak<T, U>) -> Self {
+                mapped.weak
+            }
+        }
+
+        /// The result of [`MappedPinWeak::upgrade`]: a strong pointer to the whole target that
+        /// dereferences to the projected part
+        pub struct MappedPin<T: ?Sized, U: ?Sized> {
+            owner: Pin<$Rc<T>>,
+            projection: $Rc<dyn Fn(Pin<&T>) -> Pin<&U> $(+ $auto)*>,
+        }
+
+        impl<T: ?Sized, U: ?Sized> MappedPin<T, U> {
+            /// The projected part, as a pinned reference
+            pub fn as_pin_ref(&self) -> Pin<&U> {
+                (self.projection)(self.owner.as_ref())
+            }
+
+            /// The strong pointer to the whole target
+            pub fn owner(&self) -> &Pin<$Rc<T>> {
+                &self.owner
+            }
+        }
+
+        impl<T: ?Sized, U: ?Sized> core::ops::Deref for MappedPin<T, U> {
+            type Target = U;
+            fn deref(&self) -> &U {
+                self.as_pin_ref().get_ref()
+            }
+        }
+
+        impl PinWeak<dyn core::any::Any $(+ $auto)*> {
+            #[doc = concat!("Attempts to downcast the `PinWeak<", stringify!(dyn Any $(+ $auto)*), ">` to a concrete type, like [`", $rc_lit, "::downcast`].")]
+            ///
+            /// The type can only be checked while the target is alive, so this returns the
+            /// original `PinWeak` as an error if the type does not match, if the target was
+            /// dropped (or is being dropped), or if it was created with [`PinWeak::new`].
+            /// Code that needs the concrete type after the target is gone should keep a
+            /// `PinWeak<T>` from before the coercion to `dyn Any`.
+            pub fn downcast<T: core::any::Any>(self) -> Result<PinWeak<T>, Self> {
+                if !self.with_upgraded(|value| value.get_ref().is::<T>()).unwrap_or(false) {
+                    return Err(self);
+                }
+                // Safety: we just checked that the value is of type T, and it stays pinned
+                let weak = self.weak.map(|weak| unsafe { Weak::from_raw(Weak::into_raw(weak) as *const T) });
+                Ok(PinWeak {
+                    weak,
+                    #[cfg(feature = "tracked")]
+                    generation: self.generation,
+                })
+            }
+        }
+
+        impl<T> PinWeak<T> {
+            /// Upgrades the weak pointer and returns a clone of the value, or `None` if it was dropped.
+            ///
+            /// The original value stays pinned in place, only the clone is returned.
+            pub fn upgrade_cloned(&self) -> Option<T>
+            where
+                T: Clone,
+            {
+                self.with_upgraded(|value| T::clone(&value))
+            }
+
+            #[doc = concat!("Equivalent function to [`", $rc_lit, "::new_cyclic`], but operating on `PinWeak<T>` and `Pin<", $rc_lit, "<T>>` instead.")]
+            ///
+            #[doc = concat!("Like [`", $rc_lit, "::new_cyclic`], this aborts if the allocation fails. There is no")]
+            /// fallible variant, because the standard library does not provide one, and it cannot
+            /// be built on top of the fallible constructors without letting `data_fn` upgrade the
+            /// weak pointer to uninitialized data.
+            pub fn new_cyclic<F>(data_fn: F) -> Pin<$Rc<T>> where F: FnOnce(&Self) -> T {
+
+                let rc = $Rc::new_cyclic(|weak| data_fn(&Self::from_weak(weak.clone())));
+                // Saferty: Nobody else had access to the unpinned Rc before.
+                unsafe { Pin::new_unchecked(rc) }
+
+            }
+
+            /// Same as [`PinWeak::new_cyclic`], but `data_fn` also returns an auxiliary value,
+            /// which is returned alongside the pinned pointer once it is constructed.
+            ///
+            /// ```rust
+            #[doc = concat!("use ", module_path!(), "::PinWeak;")]
+            /// struct Node { me: PinWeak<Node> }
+            /// let (node, me) = PinWeak::new_cyclic_with(|me| (Node { me: me.clone() }, me.clone()));
+            /// assert!(me.upgrade().is_some());
+            /// assert!(node.me.ptr_eq(&me));
+            /// ```
+            pub fn new_cyclic_with<F, R>(data_fn: F) -> (Pin<$Rc<T>>, R)
+            where
+                F: FnOnce(&Self) -> (T, R),
+            {
+                let mut aux = None;
+                let rc = Self::new_cyclic(|weak| {
+                    let (value, r) = data_fn(weak);
+                    aux = Some(r);
+                    value
+                });
+                // new_cyclic always calls data_fn
+                (rc, aux.unwrap())
+            }
+
+            /// Constructs two pinned objects that can refer to each other.
+            ///
+            /// Like [`PinWeak::new_cyclic`], `data_fn` receives the weak pointers to both objects
+            /// before they are constructed, and they can't be upgraded until this function returns.
+            ///
+            /// ```rust
+            #[doc = concat!("use ", module_path!(), "::PinWeak;")]
+            /// struct Model { view: PinWeak<View> }
+            /// struct View { model: PinWeak<Model> }
+            /// let (model, view) = PinWeak::new_cyclic_pair(|model, view| {
+            ///     (Model { view: view.clone() }, View { model: model.clone() })
+            /// });
+            /// assert!(model.view.upgrade().unwrap().model == model);
+            /// ```
+            pub fn new_cyclic_pair<U, F>(data_fn: F) -> (Pin<$Rc<T>>, Pin<$Rc<U>>)
+            where
+                F: FnOnce(&Self, &PinWeak<U>) -> (T, U),
+            {
+                let mut second = None;
+                let first = Self::new_cyclic(|first_weak| {
+                    let mut first = None;
+                    second = Some(PinWeak::new_cyclic(|second_weak| {
+                        let (a, b) = data_fn(first_weak, second_weak);
+                        first = Some(a);
+                        b
+                    }));
+                    // new_cyclic always calls data_fn
+                    first.unwrap()
+                });
+                (first, second.unwrap())
+            }
+        }
+
+        /// Helpers to (de)serialize a `PinWeak` with `#[serde(with = "...")]`
+        ///
+        /// A `PinWeak` is serialized as an `Option<T>`: the value if it can be upgraded, or `None`
+        /// otherwise. Deserializing always produces a dangling `PinWeak`, as the data is not
+        /// owned by anything and would be dropped immediately.
+        ///
+        /// See the example in the documentation of the `Deserialize` implementation of [`PinWeak`].
+        #[cfg(feature = "serde")]
+        pub mod serde {
+            use super::*;
+
+            /// Serializes the upgraded value as `Some`, or `None` if the target was dropped.
+            pub fn serialize<T, S>(weak: &PinWeak<T>, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                T: ?Sized + ::serde::Serialize,
+                S: ::serde::Serializer,
+            {
+                match weak.upgrade() {
+                    Some(rc) => serializer.serialize_some(&*rc),
+                    None => serializer.serialize_none(),
+                }
+            }
+
+            /// Deserializes an `Option<T>` and discards it, returning a dangling `PinWeak`.
+            pub fn deserialize<'de, T, D>(deserializer: D) -> Result<PinWeak<T>, D::Error>
+            where
+                T: ::serde::Deserialize<'de>,
+                D: ::serde::Deserializer<'de>,
+            {
+                <PinWeak<T> as ::serde::Deserialize>::deserialize(deserializer)
+            }
+        }
+
+        /// Deserializes an `Option<T>` and discards it, returning a dangling `PinWeak`.
+        ///
+        /// See the [`serde`](self::serde) module. For a field that is not serialized at all,
+        /// `#[serde(skip)]` can be used since `PinWeak` implements [`Default`].
+        ///
+        /// ```
+        #[doc = concat!("use ", module_path!(), "::{PinWeak, ", $rc_lit, "};")]
+        /// #[derive(serde::Serialize, serde::Deserialize)]
+        /// struct Child {
+        #[doc = concat!("    #[serde(with = \"", module_path!(), "::serde\")]")]
+        ///     parent: PinWeak<String>,
+        /// }
+        #[doc = concat!("let parent = ", $rc_lit, "::pin(String::from(\"parent\"));")]
+        /// let child = Child { parent: PinWeak::downgrade(parent.clone()) };
+        /// assert_eq!(serde_json::to_string(&child).unwrap(), r#"{"parent":"parent"}"#);
+        /// let child: Child = serde_json::from_str(r#"{"parent":"parent"}"#).unwrap();
+        /// assert!(child.parent.upgrade().is_none());
+        /// ```
+        #[cfg(feature = "serde")]
+        impl<'de, T: ::serde::Deserialize<'de>> ::serde::Deserialize<'de> for PinWeak<T> {
+            fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                <Option<T> as ::serde::Deserialize>::deserialize(deserializer)?;
+                Ok(Self::default())
+            }
+        }
+
+        #[test]
+        fn test() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            impl Foo {
+                fn new(u: u32) -> Self {
+                    Self { _p: core::marker::PhantomPinned, u }
+                }
+            }
+            let c = $Rc::pin(Foo::new(44));
+            let weak1 = PinWeak::downgrade(c.clone());
+            assert_eq!(weak1.upgrade().unwrap().u, 44);
+            assert_eq!(weak1.clone().upgrade().unwrap().u, 44);
+            assert_eq!(weak1.strong_count(), 1);
+            assert_eq!(weak1.weak_count(), 1);
+            let weak2 = PinWeak::downgrade(c.clone());
+            assert_eq!(weak2.upgrade().unwrap().u, 44);
+            assert_eq!(weak1.upgrade().unwrap().u, 44);
+            assert_eq!(weak2.strong_count(), 1);
+            assert_eq!(weak2.weak_count(), 2);
+            assert!(weak1.ptr_eq(&weak2));
+            assert!(!weak1.ptr_eq(&Default::default()));
+            // note that this moves c and therefore it will be dropped
+            let weak3 = PinWeak::downgrade(c);
+            assert!(weak3.upgrade().is_none());
+            assert!(weak2.upgrade().is_none());
+            assert!(weak1.upgrade().is_none());
+            assert!(weak1.clone().upgrade().is_none());
+            assert_eq!(weak2.strong_count(), 0);
+            assert_eq!(weak2.weak_count(), 0);
+
+            let def = PinWeak::<alloc::boxed::Box<&'static mut ()>>::default();
+            assert!(def.upgrade().is_none());
+            assert!(def.clone().upgrade().is_none());
+        }
+
+        #[test]
+        fn test_hash() {
+            use std::collections::HashSet;
+            let a = $Rc::pin(core::marker::PhantomPinned);
+            let b = $Rc::pin(core::marker::PhantomPinned);
+            let weak_a = PinWeak::downgrade(a.clone());
+            let mut set = HashSet::new();
+            assert!(set.insert(weak_a.clone()));
+            assert!(!set.insert(weak_a.clone()));
+            assert!(!set.insert(PinWeak::downgrade(a.clone())));
+            assert!(set.insert(PinWeak::downgrade(b.clone())));
+            assert!(set.insert(PinWeak::default()));
+            assert!(!set.insert(PinWeak::default()));
+            assert_eq!(set.len(), 3);
+            // dropping the target does not change the identity
+            std::mem::drop(a);
+            assert!(set.contains(&weak_a));
+            assert!(!set.insert(weak_a));
+
+            let dyn_weak: PinWeak<dyn core::any::Any> = PinWeak::downgrade(b.clone());
+            let dyn_set: HashSet<_> = [dyn_weak.clone(), dyn_weak].into_iter().collect();
+            assert_eq!(dyn_set.len(), 1);
+        }
+
+        #[test]
+        fn test_ord() {
+            use alloc::collections::BTreeSet;
+            let a = $Rc::pin(core::marker::PhantomPinned);
+            let b = $Rc::pin(core::marker::PhantomPinned);
+            let weak_a = PinWeak::downgrade(a.clone());
+            let weak_b = PinWeak::downgrade(b.clone());
+            assert_ne!(weak_a.cmp(&weak_b), core::cmp::Ordering::Equal);
+            assert_eq!(weak_a.cmp(&weak_a.clone()), core::cmp::Ordering::Equal);
+
+            let mut set = BTreeSet::new();
+            assert!(set.insert(weak_a.clone()));
+            assert!(set.insert(PinWeak::default()));
+            assert!(set.insert(weak_b.clone()));
+            assert!(!set.insert(PinWeak::downgrade(a.clone())));
+            assert!(!set.insert(PinWeak::default()));
+            assert_eq!(set.len(), 3);
+            assert!(set.contains(&weak_b));
+            assert!(set.contains(&PinWeak::default()));
+            std::mem::drop(a);
+            assert!(set.remove(&weak_a));
+            assert!(!set.contains(&weak_a));
+            assert!(set.remove(&PinWeak::default()));
+            assert_eq!(set.into_iter().collect::<alloc::vec::Vec<_>>(), [weak_b]);
+        }
+
+        #[test]
+        fn test_fmt_pointer() {
+            use alloc::format;
+            let rc = $Rc::pin(42u32);
+            let weak = PinWeak::downgrade(rc.clone());
+            let expected = format!("{:p}", $Rc::as_ptr(&Pin::into_inner(rc.clone())));
+            assert_eq!(format!("{:p}", weak), expected);
+            let dyn_weak: PinWeak<dyn core::any::Any> = PinWeak::downgrade(rc);
+            assert_eq!(format!("{:p}", dyn_weak), expected);
+            assert_eq!(format!("{:p}", weak), expected);
+            // dangling
+            assert!(format!("{:p}", PinWeak::<u32>::default()).starts_with("0x"));
+        }
+
+        #[test]
+        fn test_debug() {
+            use alloc::format;
+            let rc = $Rc::pin(42u32);
+            let weak = PinWeak::downgrade(rc.clone());
+            let _weak2 = weak.clone();
+            let _rc2 = rc.clone();
+            assert_eq!(
+                format!("{:?}", weak),
+                format!("PinWeak {{ ptr: {:p}, alive: true, strong: 2, weak: 2 }}", weak)
+            );
+            std::mem::drop((rc, _rc2));
+            assert_eq!(
+                format!("{:?}", weak),
+                format!("PinWeak {{ ptr: {:p}, alive: false, strong: 0, weak: 0 }}", weak)
+            );
+            let def = PinWeak::<u32>::default();
+            assert_eq!(
+                format!("{:?}", def),
+                format!("PinWeak {{ ptr: {:p}, alive: false, strong: 0, weak: 0 }}", def)
+            );
+        }
+
+        #[test]
+        fn test_debug_without_debug_target() {
+            trait Handler {}
+            struct NotDebug;
+            impl Handler for NotDebug {}
+            #[derive(Debug)]
+            struct Holder {
+                handler: PinWeak<dyn Handler>,
+            }
+            let rc = $Rc::pin(NotDebug);
+            let holder = Holder { handler: PinWeak::downgrade(rc.clone()) };
+            assert!(alloc::format!("{:?}", holder).starts_with("Holder { handler: PinWeak { "));
+            assert!(holder.handler.upgrade().is_some());
+        }
+
+        #[test]
+        fn test_eq_strong() {
+            let a = $Rc::pin(42u32);
+            let b = $Rc::pin(42u32);
+            let weak_a = PinWeak::downgrade(a.clone());
+            assert!(weak_a.ptr_eq_strong(&a));
+            assert!(weak_a == a);
+            assert!(a == weak_a);
+            assert!(!weak_a.ptr_eq_strong(&b));
+            assert!(weak_a != b);
+            assert!(b != weak_a);
+            assert!(PinWeak::default() != a);
+            assert_eq!(weak_a.strong_count(), 1);
+
+            let dyn_a: Pin<$Rc<dyn core::any::Any>> = a.clone();
+            let dyn_weak: PinWeak<dyn core::any::Any> = PinWeak::downgrade(dyn_a.clone());
+            assert!(dyn_weak == dyn_a);
+
+            std::mem::drop((a, dyn_a));
+            assert!(weak_a != b);
+            assert!(dyn_weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_from() {
+            fn make_weak<T: ?Sized>(w: impl Into<PinWeak<T>>) -> PinWeak<T> {
+                w.into()
+            }
+            let rc: Pin<$Rc<dyn core::any::Any>> = $Rc::pin(42u32);
+            let w1 = make_weak(&rc);
+            let w2: PinWeak<_> = rc.clone().into();
+            assert!(w1.ptr_eq(&w2));
+            assert_eq!(w1.strong_count(), 1);
+            assert_eq!(w1.weak_count(), 2);
+            assert_eq!(w2.upgrade().unwrap().downcast_ref::<u32>(), Some(&42));
+            std::mem::drop(rc);
+            assert!(w1.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_unpin_weak_conversion() {
+            let rc = $Rc::new(42u32);
+            let pin_weak = PinWeak::from($Rc::downgrade(&rc));
+            assert!(pin_weak.as_weak().unwrap().ptr_eq(&$Rc::downgrade(&rc)));
+            assert_eq!(*pin_weak.upgrade().unwrap(), 42);
+            let weak: Weak<u32> = pin_weak.clone().into();
+            assert!(weak.ptr_eq(pin_weak.as_weak().unwrap()));
+            assert!(PinWeak::from(weak.clone()).ptr_eq(&pin_weak));
+            assert!(pin_weak == Pin::new(rc.clone()));
+            std::mem::drop(rc);
+            assert!(weak.upgrade().is_none());
+            assert!(pin_weak.upgrade().is_none());
+            assert!(PinWeak::from(weak).ptr_eq(&pin_weak));
+            assert!(PinWeak::<u32>::new().as_weak().is_none());
+            assert!(Weak::from(PinWeak::<u32>::new()).upgrade().is_none());
+        }
+
+        #[cfg(feature = "serde")]
+        #[test]
+        fn test_serde() {
+            use alloc::string::String;
+            #[derive(::serde::Serialize, ::serde::Deserialize)]
+            struct Document {
+                title: String,
+                #[serde(with = "serde")]
+                parent: PinWeak<Document>,
+                #[serde(skip)]
+                cache: PinWeak<u32>,
+            }
+            let root = $Rc::pin(Document {
+                title: "root".into(),
+                parent: PinWeak::default(),
+                cache: PinWeak::default(),
+            });
+            let cache = $Rc::pin(42);
+            let child = Document {
+                title: "child".into(),
+                parent: PinWeak::downgrade(root.clone()),
+                cache: PinWeak::downgrade(cache.clone()),
+            };
+            let json = serde_json::to_string(&child).unwrap();
+            assert_eq!(
+                json,
+                r#"{"title":"child","parent":{"title":"root","parent":null}}"#
+            );
+            let loaded: Document = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.title, "child");
+            assert!(loaded.parent.upgrade().is_none());
+            assert!(loaded.cache.upgrade().is_none());
+
+            std::mem::drop(root);
+            let json = serde_json::to_string(&child).unwrap();
+            assert_eq!(json, r#"{"title":"child","parent":null}"#);
+            let loaded: Document = serde_json::from_str(&json).unwrap();
+            assert!(loaded.parent.upgrade().is_none());
+
+            let weak: PinWeak<u32> = serde_json::from_str("12").unwrap();
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_unsized_default() {
+            use core::any::Any;
+            let def_any = PinWeak::<dyn Any>::default();
+            assert!(def_any.upgrade().is_none());
+            assert_eq!(def_any.strong_count(), 0);
+            assert_eq!(def_any.weak_count(), 0);
+            assert!(def_any.ptr_eq(&PinWeak::new()));
+            assert!(def_any.clone().upgrade().is_none());
+            let rc: Pin<$Rc<dyn Any>> = $Rc::pin(42u32);
+            assert!(!def_any.ptr_eq(&PinWeak::downgrade(rc.clone())));
+            assert!(def_any != rc);
+
+            let def_slice = PinWeak::<[u8]>::new();
+            assert!(def_slice.upgrade().is_none());
+            assert_eq!(def_slice.strong_count(), 0);
+            assert_eq!(def_slice.weak_count(), 0);
+            let slice: Pin<$Rc<[u8]>> = $Rc::pin([1, 2, 3]);
+            assert!(!def_slice.ptr_eq(&PinWeak::downgrade(slice)));
+            assert!(def_slice.ptr_eq(&Default::default()));
+
+            #[cfg(not(feature = "tracked"))]
+            assert_eq!(core::mem::size_of::<PinWeak<u32>>(), core::mem::size_of::<Weak<u32>>());
+            // the null niche is used by `PinWeak::new()`, so an `Option` needs an extra word
+            #[cfg(not(feature = "tracked"))]
+            assert_eq!(
+                core::mem::size_of::<Option<PinWeak<u32>>>(),
+                2 * core::mem::size_of::<Option<Weak<u32>>>()
+            );
+        }
+
+        #[cfg(feature = "defmt")]
+        #[test]
+        fn test_defmt() {
+            fn assert_format<T: defmt::Format + ?Sized>() {}
+            assert_format::<PinWeak<u32>>();
+            assert_format::<PinWeak<dyn core::any::Any>>();
+            assert_format::<PinWeak<[u8]>>();
+        }
+
+        #[test]
+        fn test_const_new() {
+            use core::cell::RefCell;
+            struct Widget(u32);
+            const EMPTY: PinWeak<dyn core::any::Any> = PinWeak::new();
+            assert!(EMPTY.upgrade().is_none());
+            std::thread_local! {
+                static LAST_FOCUSED: RefCell<PinWeak<Widget>> = const { RefCell::new(PinWeak::new()) };
+            }
+            assert!(LAST_FOCUSED.with(|w| w.borrow().upgrade().is_none()));
+            let widget = $Rc::pin(Widget(42));
+            LAST_FOCUSED.with(|w| *w.borrow_mut() = PinWeak::downgrade(widget.clone()));
+            assert_eq!(LAST_FOCUSED.with(|w| w.borrow().upgrade().unwrap().0), 42);
+            std::mem::drop(widget);
+            assert!(LAST_FOCUSED.with(|w| w.borrow().upgrade().is_none()));
+        }
+
+        #[test]
+        fn test_as_ptr() {
+            trait Named {
+                fn name(&self) -> &str;
+            }
+            struct Foo(core::marker::PhantomPinned);
+            impl Named for Foo {
+                fn name(&self) -> &str {
+                    "foo"
+                }
+            }
+            let rc = $Rc::pin(Foo(core::marker::PhantomPinned));
+            let weak = PinWeak::downgrade(rc.clone());
+            let expected = Pin::get_ref(rc.as_ref()) as *const Foo;
+            assert_eq!(weak.as_ptr(), expected);
+            assert_eq!(weak.try_as_ptr(), Some(expected));
+
+            let dyn_rc: Pin<$Rc<dyn Named>> = rc;
+            let dyn_weak = PinWeak::downgrade(dyn_rc.clone());
+            let ptr = dyn_weak.try_as_ptr().unwrap();
+            assert_eq!(ptr as *const Foo, expected);
+            assert_eq!(unsafe { &*ptr }.name(), "foo");
+
+            assert_eq!(PinWeak::<Foo>::new().as_ptr(), Weak::<Foo>::new().as_ptr());
+            assert_eq!(PinWeak::<Foo>::new().try_as_ptr(), None);
+            assert_eq!(PinWeak::<dyn Named>::new().try_as_ptr(), None);
+        }
+
+        #[test]
+        fn test_raw() {
+            use core::ffi::c_void;
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            extern "C" fn callback(userdata: *mut c_void) -> u32 {
+                let weak = unsafe { PinWeak::from_raw(userdata as *const Foo) };
+                let result = weak.upgrade().map_or(0, |foo| foo.u);
+                // give back the ownership of the weak reference to the caller
+                core::mem::forget(weak);
+                result
+            }
+            fn register(weak: PinWeak<Foo>) -> (extern "C" fn(*mut c_void) -> u32, *mut c_void) {
+                (callback, weak.into_raw() as *mut c_void)
+            }
+
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let (f, userdata) = register(PinWeak::downgrade(rc.clone()));
+            assert_eq!(f(userdata), 42);
+            assert_eq!(f(userdata), 42);
+            let weak = PinWeak::downgrade(rc.clone());
+            assert_eq!(weak.weak_count(), 2);
+            unsafe { std::mem::drop(PinWeak::from_raw(userdata as *const Foo)) };
+            assert_eq!(weak.weak_count(), 1);
+
+            let (f, userdata) = register(weak.clone());
+            std::mem::drop(rc);
+            assert_eq!(f(userdata), 0);
+            let weak2 = unsafe { PinWeak::from_raw(userdata as *const Foo) };
+            assert!(weak2.ptr_eq(&weak));
+            assert!(weak2.upgrade().is_none());
+
+            let slice: Pin<$Rc<[u32]>> = $Rc::pin([1, 2, 3]);
+            let raw = PinWeak::downgrade(slice.clone()).try_into_raw().unwrap();
+            let weak = unsafe { PinWeak::from_raw(raw) };
+            assert_eq!(&*weak.upgrade().unwrap(), &[1, 2, 3]);
+            assert_eq!(weak.weak_count(), 1);
+
+            assert!(PinWeak::<dyn core::any::Any>::new().try_into_raw().is_none());
+
+            // a dangling pointer round-trips too
+            let (f, userdata) = register(PinWeak::new());
+            assert_eq!(f(userdata), 0);
+            let dangling = unsafe { PinWeak::from_raw(userdata as *const Foo) };
+            assert!(dangling.ptr_eq(&PinWeak::new()));
+            assert_eq!((dangling.strong_count(), dangling.weak_count()), (0, 0));
+        }
+
+        #[test]
+        fn test_id() {
+            use crate::WeakId;
+            use std::collections::HashMap;
+            let a = $Rc::pin(1u32);
+            let b = $Rc::pin(1u32);
+            let weak_a = PinWeak::downgrade(a.clone());
+            assert_eq!(weak_a.id(), weak_a.clone().id());
+            assert_eq!(weak_a.id(), PinWeak::downgrade(a.clone()).id());
+            assert_eq!(weak_a.id(), WeakId::from(&a));
+            assert_ne!(weak_a.id(), WeakId::from(&b));
+            assert_eq!(PinWeak::<u32>::new().id(), PinWeak::<u32>::new().id());
+
+            let mut map = HashMap::<WeakId, &str>::new();
+            map.insert(WeakId::from(&a), "a");
+            map.insert(PinWeak::downgrade(b.clone()).id(), "b");
+            let dyn_b: Pin<$Rc<dyn core::any::Any>> = b.clone();
+            assert_eq!(map.get(&WeakId::from(&dyn_b)), Some(&"b"));
+            std::mem::drop(a);
+            assert_eq!(map.get(&weak_a.id()), Some(&"a"));
+        }
+
+        #[cfg(feature = "tracked")]
+        #[test]
+        fn test_tagged_id() {
+            use std::collections::HashSet;
+            let rc = $Rc::pin(42u64);
+            let weak = PinWeak::downgrade(rc.clone());
+            let tagged = weak.tagged_id();
+            assert_eq!(tagged, weak.clone().tagged_id());
+            assert_eq!(tagged.id(), weak.id());
+            assert_eq!(PinWeak::<u64>::new().tagged_id(), PinWeak::<u64>::new().tagged_id());
+            // independent downgrades of a live object, and raw round trips, keep the tagged id
+            assert_eq!(tagged, PinWeak::downgrade_ref(&rc).tagged_id());
+            let raw = PinWeak::downgrade_ref(&rc).into_raw();
+            assert_eq!(tagged, unsafe { PinWeak::from_raw(raw) }.tagged_id());
+            let raw = weak.clone().into_raw();
+            std::mem::drop(weak);
+            let weak = unsafe { PinWeak::from_raw(raw) };
+            assert_eq!(tagged, weak.tagged_id());
+            assert_eq!(weak.weak_count(), 1);
+            let mut seen = HashSet::new();
+            seen.insert(tagged);
+            std::mem::drop((rc, weak));
+
+            // Allocate objects until one gets the same address (the allocator usually reuses
+            // it right away). All of them must have a different tagged id.
+            for _ in 0..100 {
+                let rc = $Rc::pin(43u64);
+                let new = PinWeak::downgrade(rc.clone()).tagged_id();
+                assert!(seen.insert(new));
+                if new.id() == tagged.id() {
+                    assert_ne!(new, tagged);
+                    break;
+                }
+            }
+        }
+
+        #[test]
+        fn test_with_upgraded() {
+            use core::cell::Cell;
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: Cell<u32>,
+            }
+            impl Foo {
+                fn bump(self: Pin<&Self>) -> u32 {
+                    self.u.set(self.u.get() + 1);
+                    self.u.get()
+                }
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: Cell::new(0) });
+            let weak = PinWeak::downgrade(rc.clone());
+            assert_eq!(weak.with_upgraded(Foo::bump), Some(1));
+            assert_eq!(weak.with_upgraded(|_| assert_eq!(weak.strong_count(), 2)), Some(()));
+            assert_eq!(weak.strong_count(), 1);
+            let mut holder = Some(rc);
+            // dropping the last external strong reference within the closure is fine
+            assert_eq!(weak.with_upgraded(|foo| { holder = None; foo.bump() }), Some(2));
+            assert!(holder.is_none());
+            assert_eq!(weak.with_upgraded(Foo::bump), None);
+            let mut called = false;
+            assert_eq!(weak.with_upgraded(|_| called = true), None);
+            assert!(!called);
+        }
+
+        #[test]
+        fn test_upgrade_cloned() {
+            #[derive(Clone)]
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let weak = PinWeak::downgrade(rc.clone());
+            let mut clone = weak.upgrade_cloned().unwrap();
+            clone.u = 43;
+            assert_eq!(rc.u, 42);
+            assert!(weak == rc);
+            assert_eq!(weak.strong_count(), 1);
+            std::mem::drop(rc);
+            assert!(weak.upgrade_cloned().is_none());
+            assert_eq!(clone.u, 43);
+        }
+
+        #[test]
+        fn test_try_upgrade() {
+            use crate::UpgradeError;
+            fn get(weak: &PinWeak<u32>) -> Result<u32, UpgradeError> {
+                Ok(*weak.try_upgrade()?)
+            }
+            fn get_generic<W, P>(weak: W) -> Result<P, W::Error>
+            where
+                W: TryInto<P>,
+            {
+                weak.try_into()
+            }
+            let rc = $Rc::pin(42);
+            let weak = PinWeak::downgrade(rc.clone());
+            assert_eq!(get(&weak), Ok(42));
+            let strong: Pin<$Rc<u32>> = get_generic(&weak).unwrap();
+            assert!(weak == strong);
+            assert!(Pin::<$Rc<u32>>::try_from(&weak).is_ok());
+            std::mem::drop((rc, strong));
+            assert_eq!(get(&weak), Err(UpgradeError));
+            assert_eq!(get_generic::<_, Pin<$Rc<u32>>>(&weak), Err(UpgradeError));
+            assert_eq!(
+                alloc::string::ToString::to_string(&UpgradeError),
+                "cannot upgrade the weak pointer: the pinned target was dropped"
+            );
+            #[cfg(feature = "std")]
+            {
+                fn boxed(weak: &PinWeak<u32>) -> Result<u32, std::boxed::Box<dyn std::error::Error>> {
+                    Ok(*weak.try_upgrade()?)
+                }
+                assert!(boxed(&weak).is_err());
+            }
+        }
+
+        #[test]
+        fn test_expect_upgrade() {
+            use std::string::String;
+            fn panic_message(f: impl FnOnce()) -> String {
+                let payload = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_err();
+                match payload.downcast::<String>() {
+                    Ok(s) => *s,
+                    Err(payload) => String::from(*payload.downcast::<&str>().unwrap()),
+                }
+            }
+            let rc = $Rc::pin(42);
+            let weak = PinWeak::downgrade(rc.clone());
+            assert_eq!(*weak.expect_upgrade("the answer is gone"), 42);
+            assert_eq!(*weak.unwrap_upgrade(), 42);
+            std::mem::drop(rc);
+            assert_eq!(
+                panic_message(|| {
+                    weak.expect_upgrade("the answer is gone");
+                }),
+                "the answer is gone"
+            );
+            assert!(panic_message(|| {
+                weak.unwrap_upgrade();
+            })
+            .contains("pinned target was dropped"));
+        }
+
+        #[test]
+        fn test_upgrade_unchecked() {
+            let rc = $Rc::pin(42);
+            let weak = PinWeak::downgrade(rc.clone());
+            let upgraded = unsafe { weak.upgrade_unchecked() };
+            assert_eq!(*upgraded, 42);
+            assert!(weak == upgraded);
+            assert_eq!(weak.strong_count(), 2);
+            std::mem::drop(upgraded);
+            assert_eq!(weak.strong_count(), 1);
+            let dyn_rc: Pin<$Rc<dyn core::any::Any>> = rc;
+            let dyn_weak = PinWeak::downgrade(dyn_rc.clone());
+            let upgraded = unsafe { dyn_weak.upgrade_unchecked() };
+            assert_eq!(upgraded.downcast_ref::<i32>(), Some(&42));
+            std::mem::drop((dyn_rc, upgraded));
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_is_dangling() {
+            let rc = $Rc::pin(42);
+            let weak = PinWeak::downgrade(rc.clone());
+            assert!(!weak.is_dangling());
+            assert_eq!(weak.strong_count(), 1);
+            std::mem::drop(rc);
+            assert!(weak.is_dangling());
+            assert!(PinWeak::<u32>::default().is_dangling());
+            assert!(PinWeak::<dyn core::any::Any>::new().is_dangling());
+        }
+
+        #[test]
+        fn test_downcast() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let any: Pin<$Rc<dyn core::any::Any $(+ $auto)*>> = rc.clone();
+            let weak_any = PinWeak::downgrade(any.clone());
+            let weak_any = weak_any.downcast::<u32>().unwrap_err();
+            assert!(weak_any == any);
+            let weak_foo = weak_any.clone().downcast::<Foo>().ok().unwrap();
+            assert!(weak_foo == rc);
+            assert_eq!(weak_foo.upgrade().unwrap().u, 42);
+            assert_eq!(weak_foo.weak_count(), 2);
+            std::mem::drop((rc, any));
+            assert!(weak_foo.upgrade().is_none());
+            let weak_any = weak_any.downcast::<Foo>().unwrap_err();
+            assert!(weak_any.is_dangling());
+            assert!(PinWeak::<dyn core::any::Any $(+ $auto)*>::new().downcast::<Foo>().is_err());
+        }
+
+        #[cfg(feature = "unsize")]
+        #[test]
+        fn test_unsize() {
+            use unsize::Coercion;
+            trait Widget {
+                fn name(&self) -> &str;
+            }
+            struct Button {
+                _p: core::marker::PhantomPinned,
+                label: &'static str,
+            }
+            impl Widget for Button {
+                fn name(&self) -> &str {
+                    self.label
+                }
+            }
+            let rc = $Rc::pin(Button { _p: core::marker::PhantomPinned, label: "ok" });
+            let weak = PinWeak::downgrade(rc.clone());
+            let widget = weak.clone().coerce::<dyn Widget, _>(Coercion!(to dyn Widget));
+            assert_eq!(widget.upgrade().unwrap().name(), "ok");
+            assert!(widget.id() == weak.id());
+            let any: PinWeak<dyn core::any::Any> = weak.clone().coerce(Coercion::to_any());
+            assert!(any.upgrade().unwrap().is::<Button>());
+            assert_eq!(weak.weak_count(), 3);
+            drop(rc);
+            let widget = weak.coerce::<dyn Widget, _>(Coercion!(to dyn Widget));
+            assert!(widget.upgrade().is_none());
+            let widget = PinWeak::<Button>::new().coerce::<dyn Widget, _>(Coercion!(to dyn Widget));
+            assert!(widget.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_map() {
+            use core::marker::PhantomPinned;
+            struct Inner {
+                _p: PhantomPinned,
+                value: u32,
+            }
+            struct Outer {
+                _p: PhantomPinned,
+                inner: Inner,
+                name: &'static str,
+            }
+            let rc = $Rc::pin(Outer {
+                _p: PhantomPinned,
+                inner: Inner { _p: PhantomPinned, value: 42 },
+                name: "outer",
+            });
+            let name = PinWeak::downgrade(rc.clone()).map(|o| Pin::new(&o.get_ref().name));
+            assert_eq!(name.with_upgraded(|n| *n), Some("outer"));
+            // Safety: the fields are structurally pinned
+            let inner = PinWeak::downgrade(rc.clone())
+                .map(|o| unsafe { o.map_unchecked(|o| &o.inner) });
+            let value = inner.clone().map(|i| Pin::new(&i.get_ref().value));
+            assert_eq!(inner.with_upgraded(|i| i.value), Some(42));
+            let strong = value.upgrade().unwrap();
+            assert_eq!(*strong, 42);
+            assert_eq!(*strong.as_pin_ref(), 42);
+            assert!(PinWeak::from(value.clone()) == rc);
+            assert_eq!(name.base().strong_count(), 2);
+            drop(rc);
+            // the MappedPin keeps the target alive
+            assert_eq!(*strong, 42);
+            assert!(value.base().upgrade().is_some());
+            drop(strong);
+            assert!(value.upgrade().is_none());
+            assert!(inner.with_upgraded(|i| i.value).is_none());
+            assert!(name.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_same_allocation() {
+            use core::fmt::Debug;
+            let rc = $Rc::pin(42u32);
+            let dyn_rc: Pin<$Rc<dyn Debug>> = rc.clone();
+            let weak = PinWeak::downgrade(rc.clone());
+            let dyn_weak = PinWeak::downgrade(dyn_rc.clone());
+            assert!(weak.same_allocation(&dyn_weak));
+            assert!(dyn_weak.same_allocation(&weak));
+            assert!(weak.same_allocation_strong(&dyn_rc));
+            assert!(dyn_weak.same_allocation_strong(&rc));
+            let other = $Rc::pin(42u32);
+            assert!(!dyn_weak.same_allocation(&PinWeak::downgrade(other.clone())));
+            assert!(!dyn_weak.same_allocation_strong(&other));
+            assert!(!weak.same_allocation(&PinWeak::<dyn Debug>::new()));
+            drop((rc, dyn_rc));
+            assert!(weak.same_allocation(&dyn_weak));
+            assert!(!weak.same_allocation_strong(&other));
+        }
+
+        #[test]
+        fn test_len() {
+            let slice: Pin<$Rc<[u8]>> = Pin::new($Rc::from(&[1u8, 2, 3][..]));
+            let weak = PinWeak::downgrade(slice.clone());
+            assert_eq!(weak.len(), 3);
+            assert!(!weak.is_empty());
+            drop(slice);
+            assert_eq!(weak.len(), 3);
+            assert!(!weak.is_empty());
+            let empty: Pin<$Rc<[u32]>> = Pin::new($Rc::from(&[][..]));
+            assert!(PinWeak::<[u32]>::downgrade(empty).is_empty());
+            assert_eq!(PinWeak::<[u8]>::new().len(), 0);
+            assert!(PinWeak::<[u8]>::new().is_empty());
+
+            let string: Pin<$Rc<str>> = Pin::new($Rc::from("hello"));
+            let weak = PinWeak::downgrade(string.clone());
+            assert_eq!(weak.len(), 5);
+            drop(string);
+            assert_eq!(weak.len(), 5);
+            assert!(!weak.is_empty());
+            assert!(PinWeak::<str>::new().is_empty());
+        }
+
+        #[test]
+        fn test_guard() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            impl Foo {
+                fn double(self: Pin<&Self>) -> u32 {
+                    self.u * 2
+                }
+            }
+            fn doubled(weak: &PinWeak<Foo>) -> Option<u32> {
+                let foo = weak.guard()?;
+                assert_eq!(foo.u, 21);
+                Some(foo.as_pin_ref().double())
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 21 });
+            let weak = PinWeak::downgrade(rc.clone());
+            assert_eq!(doubled(&weak), Some(42));
+            assert_eq!(weak.strong_count(), 1);
+            let guard = weak.guard().unwrap();
+            assert_eq!(weak.strong_count(), 2);
+            drop(rc);
+            assert_eq!(guard.u, 21);
+            drop(guard);
+            assert_eq!(weak.strong_count(), 0);
+            assert!(weak.guard().is_none());
+            assert_eq!(doubled(&weak), None);
+        }
+
+        #[test]
+        fn test_upgrade_all() {
+            let (a, b, c) = ($Rc::pin(1u8), $Rc::pin(2u16), $Rc::pin(3u32));
+            let (d, e, f) = ($Rc::pin(4u64), $Rc::pin("e"), $Rc::pin(6i8));
+            let (wa, wb, wc) = (PinWeak::downgrade(a.clone()), PinWeak::downgrade(b.clone()), PinWeak::downgrade(c.clone()));
+            let (wd, we, wf) = (PinWeak::downgrade(d.clone()), PinWeak::downgrade(e.clone()), PinWeak::downgrade(f.clone()));
+            assert_eq!(*upgrade_all((&wa,)).unwrap().0, 1);
+            let (a2, b2) = upgrade_all((&wa, &wb)).unwrap();
+            assert_eq!((*a2, *b2), (1, 2));
+            drop((a2, b2));
+            assert!(upgrade_all((&wa, &wb, &wc)).is_some());
+            assert!(upgrade_all((&wa, &wb, &wc, &wd)).is_some());
+            assert!(upgrade_all((&wa, &wb, &wc, &wd, &we)).is_some());
+            let (.., e2, f2) = upgrade_all((&wa, &wb, &wc, &wd, &we, &wf)).unwrap();
+            assert_eq!((*e2, *f2), ("e", 6));
+            drop((e2, f2));
+            drop(c);
+            assert!(upgrade_all((&wa, &wb)).is_some());
+            assert!(upgrade_all((&wa, &wb, &wc)).is_none());
+            assert!(upgrade_all((&wc, &wd, &we, &wf)).is_none());
+            assert!(upgrade_all((&wa, &wb, &wc, &wd, &we, &wf)).is_none());
+            // the strong pointers acquired before the failure are released
+            assert_eq!(wa.strong_count(), 1);
+            assert_eq!(wb.strong_count(), 1);
+            assert_eq!(wf.strong_count(), 1);
+            drop((a, b, d, e, f));
+            assert!(upgrade_all((&wa,)).is_none());
+        }
+
+        #[test]
+        fn test_upgraded_eq() {
+            let a = $Rc::pin(std::string::String::from("hello"));
+            let b = $Rc::pin(std::string::String::from("hello"));
+            let c = $Rc::pin(std::string::String::from("world"));
+            let (wa, wb, wc) = (PinWeak::downgrade(a.clone()), PinWeak::downgrade(b.clone()), PinWeak::downgrade(c.clone()));
+            assert!(wa != wb);
+            assert_eq!(wa.upgraded_eq(&wb), Some(true));
+            assert_eq!(wa.upgraded_eq(&wa), Some(true));
+            assert_eq!(wa.upgraded_eq(&wc), Some(false));
+            assert_eq!(wc.upgraded_eq_value(&"world".into()), Some(true));
+            assert_eq!(wc.upgraded_eq_value(&"hello".into()), Some(false));
+            drop(b);
+            assert_eq!(wa.upgraded_eq(&wb), None);
+            assert_eq!(wb.upgraded_eq(&wa), None);
+            assert_eq!(wb.upgraded_eq_value(&"hello".into()), None);
+            assert_eq!(PinWeak::new().upgraded_eq_value(&1), None);
+            drop((a, c));
+        }
+
+        #[test]
+        fn test_expiring_weak() {
+            use core::time::Duration;
+            use std::{cell::Cell, rc::Rc};
+            #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+            struct MockInstant(Duration);
+            impl core::ops::Add<Duration> for MockInstant {
+                type Output = Self;
+                fn add(self, d: Duration) -> Self {
+                    MockInstant(self.0 + d)
+                }
+            }
+            #[derive(Clone, Default)]
+            struct MockClock(Rc<Cell<Duration>>);
+            impl crate::Clock for MockClock {
+                type Instant = MockInstant;
+                fn now(&self) -> MockInstant {
+                    MockInstant(self.0.get())
+                }
+            }
+            let clock = MockClock::default();
+            let secs = Duration::from_secs;
+
+            let rc = $Rc::pin(42);
+            let mut weak = ExpiringWeak::new(PinWeak::downgrade(rc.clone()), secs(10), clock.clone());
+            assert_eq!(weak.expires_at(), MockInstant(secs(10)));
+            clock.0.set(secs(9));
+            assert_eq!(weak.upgrade().map(|x| *x), Some(42));
+            weak.extend(secs(5));
+            assert_eq!(weak.expires_at(), MockInstant(secs(15)));
+            clock.0.set(secs(14));
+            assert!(!weak.is_expired());
+            assert!(weak.upgrade().is_some());
+            clock.0.set(secs(15));
+            assert!(weak.is_expired());
+            assert!(weak.upgrade().is_none());
+            // the target is still alive
+            assert!(weak.weak().upgrade().is_some());
+            weak.extend(secs(1));
+            assert!(weak.upgrade().is_some());
+
+            let weak = ExpiringWeak::new(PinWeak::downgrade(rc.clone()), secs(10), clock.clone());
+            let weak2 = weak.clone();
+            drop(rc);
+            assert!(weak2.upgrade().is_none());
+            assert!(!weak.is_expired());
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[cfg(feature = "std")]
+        #[test]
+        fn test_expiring_weak_std_clock() {
+            let rc = $Rc::pin(42);
+            let weak = ExpiringWeak::new(PinWeak::downgrade(rc.clone()), core::time::Duration::from_secs(3600), crate::StdClock);
+            assert_eq!(weak.upgrade().map(|x| *x), Some(42));
+            let weak = ExpiringWeak::new(PinWeak::downgrade(rc.clone()), core::time::Duration::ZERO, crate::StdClock);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_cached_pin_weak() {
+            let rc = $Rc::pin(3u64);
+            let mut cached = CachedPinWeak::from(PinWeak::downgrade(rc.clone()));
+            assert!(!cached.is_cached());
+            assert_eq!(cached.weak().strong_count(), 1);
+            let mut sum = 0;
+            for _ in 0..10_000 {
+                sum += *cached.get().unwrap();
+                // only the first call upgraded
+                assert_eq!(cached.weak().strong_count(), 2);
+            }
+            assert_eq!(sum, 30_000);
+            assert!(cached.is_cached());
+            drop(rc);
+            // the cache keeps the target alive
+            assert_eq!(cached.get().map(|x| *x), Some(3));
+            cached.release();
+            assert!(!cached.is_cached());
+            assert!(cached.get().is_none());
+            assert!(!cached.refresh());
+
+            let rc = $Rc::pin(4u64);
+            let mut cached = CachedPinWeak::new(PinWeak::downgrade(rc.clone()));
+            assert!(cached.refresh());
+            assert!(cached.is_cached());
+            drop(rc);
+            assert!(!cached.refresh());
+            assert!(cached.weak().upgrade().is_none());
+        }
+
+        #[test]
+        fn test_new_cyclic_with() {
+            struct Node {
+                me: PinWeak<Node>,
+                _p: core::marker::PhantomPinned,
+            }
+            let (node, (me, id)) = PinWeak::new_cyclic_with(|me| {
+                assert!(me.upgrade().is_none());
+                (Node { me: me.clone(), _p: core::marker::PhantomPinned }, (me.clone(), me.id()))
+            });
+            assert!(me.upgrade().is_some());
+            assert!(me == node);
+            assert!(node.me.ptr_eq(&me));
+            assert!(id == crate::WeakId::from(&node));
+            assert_eq!(me.weak_count(), 2);
+            drop(node);
+            assert!(me.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_new_cyclic_pair() {
+            use core::marker::PhantomPinned;
+            struct Model {
+                view: PinWeak<View>,
+                _p: PhantomPinned,
+            }
+            struct View {
+                model: PinWeak<Model>,
+                _p: PhantomPinned,
+            }
+            let (model, view) = PinWeak::new_cyclic_pair(|model, view| {
+                assert!(model.upgrade().is_none());
+                assert!(view.upgrade().is_none());
+                (Model { view: view.clone(), _p: PhantomPinned }, View { model: model.clone(), _p: PhantomPinned })
+            });
+            assert!(model.view == view);
+            assert!(view.model == model);
+            assert!(model.view.upgrade().unwrap().model == model);
+            assert_eq!(model.view.strong_count(), 1);
+            assert_eq!(view.model.strong_count(), 1);
+            let (weak_model, weak_view) = (view.model.clone(), model.view.clone());
+            drop((model, view));
+            assert!(weak_model.upgrade().is_none());
+            assert!(weak_view.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_with_weak() {
+            struct Foreign {
+                _p: core::marker::PhantomPinned,
+                count: u32,
+            }
+            let rc = WithWeak::new_pinned(Foreign { _p: core::marker::PhantomPinned, count: 42 });
+            let weak = rc.weak();
+            assert!(weak == rc);
+            assert_eq!(rc.count, 42);
+            assert_eq!(rc.as_ref().get().count, 42);
+            let upgraded = weak.upgrade().unwrap();
+            assert!(PinWeak::downgrade(upgraded.clone()).ptr_eq(&weak));
+            assert!(upgraded.weak().ptr_eq(&weak));
+            assert_eq!(weak.strong_count(), 2);
+            drop((rc, upgraded));
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_has_pin_weak() {
+            struct Node {
+                me: PinWeak<Node>,
+                name: &'static str,
+            }
+            impl HasPinWeak for Node {
+                fn pin_weak(&self) -> PinWeak<Self> {
+                    self.me.clone()
+                }
+            }
+            fn registered<T: HasPinWeak>(object: &T) -> Option<PinWeak<T>> {
+                let strong = object.try_strong_self()?;
+                assert!(object.pin_weak() == object.strong_self());
+                Some(PinWeak::downgrade(strong))
+            }
+            let node = PinWeak::new_cyclic(|me| Node { me: me.clone(), name: "node" });
+            assert!(registered(&*node).unwrap() == node);
+            assert_eq!(node.strong_self().name, "node");
+            let with_weak = WithWeak::new_pinned(42);
+            assert!(registered(&*with_weak).unwrap() == with_weak);
+
+            let detached = Node { me: PinWeak::new(), name: "detached" };
+            assert!(registered(&detached).is_none());
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| detached.strong_self().name));
+            assert!(result.is_err());
+        }
+
+        #[test]
+        fn test_downgrade_ref() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(weak.strong_count(), 1);
+            assert_eq!(weak.weak_count(), 1);
+            assert_eq!(weak.upgrade().unwrap().u, 42);
+            // the consuming equivalent
+            let weak2 = PinWeak::downgrade(rc.clone());
+            assert!(weak2.ptr_eq(&weak));
+            assert_eq!(weak.strong_count(), 1);
+            assert_eq!(weak.weak_count(), 2);
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_pinned_ext() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            assert_eq!(rc.strong_count(), 1);
+            assert_eq!(rc.weak_count(), 0);
+            let weak = PinWeak::downgrade_ref(&rc);
+            let rc2 = rc.clone();
+            assert_eq!(rc.strong_count(), 2);
+            assert_eq!(rc.weak_count(), 1);
+            assert!(rc.ptr_eq(&rc2));
+            assert!(!rc.ptr_eq(&$Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 })));
+            assert_eq!(rc.as_ptr(), weak.as_ptr());
+            assert_eq!(unsafe { &*rc2.as_ptr() }.u, 42);
+            drop(rc2);
+            assert_eq!(rc.strong_count(), 1);
+            drop(weak);
+            assert_eq!(rc.weak_count(), 0);
+        }
+
+        #[test]
+        fn test_pin_get_mut() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            impl Foo {
+                fn incr(self: Pin<&mut Self>) {
+                    // Safety: u is not structurally pinned
+                    unsafe { self.get_unchecked_mut().u += 1 }
+                }
+            }
+            let mut rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 41 });
+            pin_get_mut(&mut rc).unwrap().incr();
+            assert_eq!(rc.u, 42);
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert!(pin_get_mut(&mut rc).is_none());
+            drop(weak);
+            let rc2 = rc.clone();
+            assert!(pin_get_mut(&mut rc).is_none());
+            drop(rc2);
+            pin_get_mut(&mut rc).unwrap().incr();
+            assert_eq!(rc.u, 43);
+        }
+
+        #[test]
+        fn test_pin_make_mut() {
+            #[derive(Clone)]
+            struct Style {
+                _p: core::marker::PhantomPinned,
+                size: u32,
+            }
+            fn set_size(style: Pin<&mut Style>, size: u32) {
+                // Safety: size is not structurally pinned
+                unsafe { style.get_unchecked_mut().size = size }
+            }
+            let mut rc = $Rc::pin(Style { _p: core::marker::PhantomPinned, size: 1 });
+            let addr = PinWeak::downgrade_ref(&rc).id();
+            // unique: in place
+            set_size(pin_make_mut(&mut rc), 2);
+            assert_eq!(rc.size, 2);
+            assert!(PinWeak::downgrade_ref(&rc).id() == addr);
+
+            // shared: clone, the original is untouched
+            let shared = rc.clone();
+            let weak = PinWeak::downgrade_ref(&shared);
+            set_size(pin_make_mut(&mut rc), 3);
+            assert_eq!((rc.size, shared.size), (3, 2));
+            assert!(weak == shared);
+            assert!(weak != rc);
+            assert_eq!(weak.upgrade().unwrap().size, 2);
+
+            // only a weak left: clone too, and the weak dangles
+            let mut rc = shared;
+            set_size(pin_make_mut(&mut rc), 4);
+            assert_eq!(rc.size, 4);
+            assert!(weak.upgrade().is_none());
+            assert!(weak != rc);
+        }
+
+        #[test]
+        fn test_pin_try_unwrap() {
+            let rc = $Rc::pin(std::string::String::from("hello"));
+            let weak = PinWeak::downgrade_ref(&rc);
+            let other = rc.clone();
+            let rc = pin_try_unwrap(rc).unwrap_err();
+            assert!(weak == rc);
+            assert!(pin_into_inner(other).is_none());
+            assert_eq!(pin_try_unwrap(rc).unwrap(), "hello");
+            assert!(weak.upgrade().is_none());
+
+            let rc = $Rc::pin(42);
+            let other = rc.clone();
+            assert_eq!(pin_into_inner(rc), None);
+            assert_eq!(pin_into_inner(other), Some(42));
+        }
+
+        #[test]
+        fn test_pin_unwrap_or_clone() {
+            #[derive(Clone, PartialEq, Debug)]
+            struct Node(std::vec::Vec<u32>);
+            let rc = $Rc::pin(Node(std::vec![1, 2, 3]));
+            let data = rc.0.as_ptr();
+            let shared = rc.clone();
+            let weak = PinWeak::downgrade_ref(&rc);
+            let cloned = pin_unwrap_or_clone(rc);
+            assert_eq!(cloned, Node(std::vec![1, 2, 3]));
+            assert_ne!(cloned.0.as_ptr(), data);
+            assert!(weak.upgrade().is_some());
+            // unique: moved out
+            let moved = pin_unwrap_or_clone(shared);
+            assert_eq!(moved.0.as_ptr(), data);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_pin_clone_inner() {
+            #[derive(Clone)]
+            struct Node(std::vec::Vec<u32>, core::marker::PhantomPinned);
+            let rc = $Rc::pin(Node(std::vec![1, 2, 3], core::marker::PhantomPinned));
+            let data = rc.0.as_ptr();
+            let weak = PinWeak::downgrade_ref(&rc);
+            // unique, but still cloned
+            let cloned = pin_clone_inner(rc);
+            assert_eq!(cloned.0, [1, 2, 3]);
+            assert_ne!(cloned.0.as_ptr(), data);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_pin_raw() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let weak = PinWeak::downgrade_ref(&rc);
+            let handle = pin_into_raw(rc.clone()) as usize;
+            assert_eq!(handle, pin_as_ptr(&rc) as usize);
+            assert_eq!(weak.strong_count(), 2);
+            let back = unsafe { pin_from_raw(handle as *const Foo) };
+            assert!(weak == back);
+            assert_eq!(back.u, 42);
+            assert_eq!(weak.strong_count(), 2);
+            drop((rc, back));
+            assert!(weak.upgrade().is_none());
+
+            let slice: Pin<$Rc<[u8]>> = Pin::new($Rc::from(&b"abc"[..]));
+            let back = unsafe { pin_from_raw(pin_into_raw(slice)) };
+            assert_eq!(&*back, b"abc");
+        }
+
+        #[test]
+        fn test_pin_strong_count() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            extern "C" fn retain(handle: *const core::ffi::c_void) {
+                unsafe { pin_increment_strong_count(handle as *const Foo) }
+            }
+            extern "C" fn release(handle: *const core::ffi::c_void) {
+                unsafe { pin_decrement_strong_count(handle as *const Foo) }
+            }
+            let rc = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let weak = PinWeak::downgrade_ref(&rc);
+            let handle = pin_into_raw(rc) as *const core::ffi::c_void;
+            retain(handle);
+            assert_eq!(weak.strong_count(), 2);
+            release(handle);
+            assert_eq!(weak.upgrade().unwrap().u, 42);
+            retain(handle);
+            // give back the original reference
+            drop(unsafe { pin_from_raw(handle as *const Foo) });
+            assert_eq!(weak.upgrade().unwrap().u, 42);
+            release(handle);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_pin_downcast() {
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let any: Pin<$Rc<dyn core::any::Any $(+ $auto)*>> = $Rc::pin(Foo { _p: core::marker::PhantomPinned, u: 42 });
+            let weak = PinWeak::downgrade_ref(&any);
+            let any = pin_downcast::<u32>(any).err().unwrap();
+            assert!(weak == any);
+            assert_eq!(weak.strong_count(), 1);
+            let foo = pin_downcast::<Foo>(any).ok().unwrap();
+            assert_eq!(foo.u, 42);
+            assert!(weak.same_allocation_strong(&foo));
+            assert!(weak.clone().downcast::<Foo>().ok().unwrap() == foo);
+            drop(foo);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_pin_from_slice() {
+            #[derive(Clone)]
+            struct Foo {
+                _p: core::marker::PhantomPinned,
+                u: u32,
+            }
+            let foos = [Foo { _p: core::marker::PhantomPinned, u: 1 }, Foo { _p: core::marker::PhantomPinned, u: 2 }];
+            let rc = pin_from_slice(&foos);
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(weak.len(), 2);
+            assert_eq!(weak.upgrade().unwrap()[1].u, 2);
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+
+            let empty = pin_from_slice::<u32>(&[]);
+            let weak = PinWeak::downgrade_ref(&empty);
+            assert!(weak.is_empty());
+            assert!(weak.upgrade().is_some());
+            drop(empty);
+            assert!(weak.upgrade().is_none());
+
+            let rc = pin_str("hello");
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(&*weak.upgrade().unwrap(), "hello");
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+            assert!(PinWeak::downgrade(pin_str("")).is_empty());
+        }
+
+        #[test]
+        fn test_pin_from_vec() {
+            let rc = pin_from_iter((1..=3).map(|x| x * 10));
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(&*weak.upgrade().unwrap(), &[10, 20, 30]);
+            assert!(weak == rc);
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+            assert!(PinWeak::downgrade(pin_from_iter(core::iter::empty::<u8>())).is_empty());
+
+            let rc = pin_from_vec(std::vec![std::string::String::from("a"), "b".into()]);
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(weak.len(), 2);
+            assert_eq!(weak.upgrade().unwrap()[1], "b");
+            assert!(PinWeak::downgrade(pin_from_vec::<u8>(std::vec::Vec::new())).is_empty());
+
+            let rc = pin_from_string("hello".into());
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(&*weak.upgrade().unwrap(), "hello");
+            assert!(pin_from_string(std::string::String::new()).is_empty());
+        }
+
+        #[test]
+        fn test_pin_from_box() {
+            trait Handler {
+                fn handle(&self) -> u32;
+            }
+            struct Plugin(u32, core::marker::PhantomPinned);
+            impl Handler for Plugin {
+                fn handle(&self) -> u32 {
+                    self.0
+                }
+            }
+            let rc = pin_from_box(std::boxed::Box::new(Plugin(1, core::marker::PhantomPinned)));
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(weak.upgrade().unwrap().0, 1);
+
+            let boxed: std::boxed::Box<dyn Handler> = std::boxed::Box::new(Plugin(2, core::marker::PhantomPinned));
+            let rc = pin_from_box(boxed);
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(weak.upgrade().unwrap().handle(), 2);
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+
+            let rc = pin_from_box(std::vec![1, 2, 3].into_boxed_slice());
+            let weak = PinWeak::downgrade_ref(&rc);
+            assert_eq!(&*weak.upgrade().unwrap(), &[1, 2, 3]);
+        }
+
+        #[test]
+        fn test_pin_and_downgrade() {
+            let (rc, weak) = pin_and_downgrade(std::string::String::from("hello"));
+            assert!(weak == rc);
+            assert_eq!(weak.strong_count(), 1);
+            assert_eq!(weak.weak_count(), 1);
+            assert_eq!(*weak.upgrade().unwrap(), "hello");
+            drop(rc);
+            assert!(weak.upgrade().is_none());
+        }
+
+        #[test]
+        fn test_by_address() {
+            use std::collections::{BTreeSet, HashMap};
+            #[derive(PartialEq)]
+            struct Node(u32);
+            let nodes = [$Rc::pin(Node(1)), $Rc::pin(Node(1)), $Rc::pin(Node(2))];
+            let mut map = HashMap::new();
+            for (i, node) in nodes.iter().enumerate() {
+                map.insert(ByAddress::new(node.clone()), i);
+            }
+            // value-equal nodes are different keys
+            assert_eq!(map.len(), 3);
+            assert_eq!(map[&ByAddress::new(nodes[1].clone())], 1);
+            assert_eq!(map[&ByAddress::from(nodes[2].clone())], 2);
+            let weak = PinWeak::downgrade_ref(&nodes[0]);
+            assert_eq!(map[&weak.id()], 0);
+            let key = map.keys().find(|k| weak.ptr_eq_strong(k.get())).unwrap();
+            assert_eq!(key.0, 1);
+            assert_eq!(key.as_pin_ref().0, 1);
+            assert!(key.clone() == ByAddress::new(nodes[0].clone()));
+            let set: BTreeSet<_> = nodes.iter().cloned().map(ByAddress::new).collect();
+            assert_eq!(set.len(), 3);
+            drop(map);
+            assert_eq!(weak.strong_count(), 2);
+            drop(set);
+            assert!(weak == ByAddress::new(nodes[0].clone()).into_inner());
+        }
+
+        #[test]
+        fn test_element() {
+            struct Particle {
+                _p: core::marker::PhantomPinned,
+                x: u32,
+            }
+            let particles = pin_from_iter((0..3).map(|x| Particle { _p: core::marker::PhantomPinned, x }));
+            let weak = PinWeak::downgrade_ref(&particles);
+            assert!(weak.element(3).is_none());
+            assert!(PinWeak::<[Particle]>::new().element(0).is_none());
+            let second = weak.element(1).unwrap();
+            assert_eq!(second.index(), 1);
+            assert!(second == weak.element(1).unwrap());
+            assert!(second == second.clone());
+            assert!(second != weak.element(2).unwrap());
+            let other = pin_from_iter((0..3).map(|x| Particle { _p: core::marker::PhantomPinned, x }));
+            assert!(second != PinWeak::downgrade_ref(&other).element(1).unwrap());
+            let guard = second.upgrade().unwrap();
+            assert_eq!(guard.x, 1);
+            assert_eq!(guard.as_pin_ref().x, 1);
+            assert!(second.slice().ptr_eq_strong(guard.slice()));
+            drop(particles);
+            assert_eq!(guard.x, 1);
+            drop(guard);
+            assert!(second.upgrade().is_none());
+            assert!(weak.element(1).is_some());
+        }
+
+        #[cfg(feature = "std")]
+        #[test]
+        fn test_weak_value_hash_map() {
+            use std::string::String;
+            struct Image(u32, core::marker::PhantomPinned);
+            let new_image = |i| $Rc::pin(Image(i, core::marker::PhantomPinned));
+            let mut map = WeakValueHashMap::new();
+            let a = new_image(1);
+            let b = new_image(2);
+            assert!(map.insert(String::from("a"), &a).is_none());
+            map.insert(String::from("b"), &b);
+            assert_eq!(map.get("a").unwrap().0, 1);
+            assert_eq!((map.len(), map.alive_len()), (2, 2));
+
+            drop(b);
+            assert_eq!((map.len(), map.alive_len()), (2, 1));
+            let alive: std::vec::Vec<_> = map.iter().map(|(k, v)| (k.clone(), v.0)).collect();
+            assert_eq!(alive, [(String::from("a"), 1)]);
+            assert!(map.get("b").is_none());
+            assert_eq!(map.len(), 1);
+
+            // Re-insertion over a dead entry
+            let a2 = new_image(3);
+            let a_weak = PinWeak::downgrade_ref(&a);
+            drop(a);
+            assert!(map.insert(String::from("a"), &a2).is_none());
+            assert!(a_weak.upgrade().is_none());
+            assert_eq!(map.get("a").unwrap().0, 3);
+            assert_eq!(map.insert(String::from("a"), &a2).unwrap().0, 3);
+
+            let c = new_image(4);
+            map.insert(String::from("c"), &c);
+            map.insert(String::from("d"), &new_image(5));
+            assert_eq!(map.prune(), 1);
+            assert_eq!(map.remove("c").unwrap().0, 4);
+            assert!(map.remove("c").is_none());
+            drop(a2);
+            assert_eq!(map.prune(), 1);
+            assert!(map.is_empty());
+        }
+
+        #[cfg(feature = "std")]
+        #[test]
+        fn test_weak_key_hash_map() {
+            use core::cell::Cell;
+            struct DropCounter<'a>(&'a Cell<u32>);
+            impl Drop for DropCounter<'_> {
+                fn drop(&mut self) {
+                    self.0.set(self.0.get() + 1);
+                }
+            }
+            struct Object(core::marker::PhantomPinned);
+            let new_object = || $Rc::pin(Object(core::marker::PhantomPinned));
+            let dropped = Cell::new(0);
+            let mut map = WeakKeyHashMap::new();
+            let a = new_object();
+            let b = new_object();
+            assert!(map.insert(&a, DropCounter(&dropped)).is_none());
+            map.insert(&b, DropCounter(&dropped));
+            assert!(map.get(&new_object()).is_none());
+            assert!(map.get(&a.clone()).is_some());
+            assert!(map.insert(&a.clone(), DropCounter(&dropped)).is_some());
+            assert_eq!(dropped.get(), 1);
+            assert_eq!(map.len(), 2);
+
+            drop(b);
+            assert_eq!(dropped.get(), 1);
+            assert_eq!(map.prune(), 1);
+            assert_eq!(dropped.get(), 2);
+            assert_eq!(map.prune(), 0);
+
+            drop(map.remove(&a));
+            assert_eq!(dropped.get(), 3);
+            assert!(map.is_empty());
+            assert!(map.get_mut(&a).is_none());
+
+            let mut map = WeakKeyHashMap::new();
+            map.insert(&a, 1);
+            *map.get_mut(&a).unwrap() += 1;
+            assert_eq!(map.get(&a), Some(&2));
+        }
+
+        #[test]
+        fn test_bind() {
+            struct Doubler(u32, core::marker::PhantomPinned);
+            impl Doubler {
+                fn apply(self: Pin<&Self>, x: u32) -> u32 {
+                    x * 2 + self.0
+                }
+            }
+            fn run(f: impl Fn(u32) -> Option<u32>) -> Option<u32> {
+                f(20)
+            }
+            fn run_or(f: impl Fn(u32) -> u32) -> u32 {
+                f(20)
+            }
+            let target = $Rc::pin(Doubler(1, core::marker::PhantomPinned));
+            let weak = PinWeak::downgrade_ref(&target);
+            let bound = weak.clone().bind(Doubler::apply);
+            let bound_or = weak.clone().bind_or(|t, x| t.0 + x, 0);
+            assert_eq!(run(&bound), Some(41));
+            assert_eq!(run_or(&bound_or), 21);
+            assert_eq!(weak.strong_count(), 1);
+            drop(target);
+            assert_eq!(run(bound), None);
+            assert_eq!(run_or(bound_or), 0);
+        }
+
+        #[test]
+        fn test_maybe_strong() {
+            let rc = $Rc::pin(42);
+            let weak = PinWeak::downgrade_ref(&rc);
+            let mut a = MaybeStrong::from(rc.clone());
+            let mut b = MaybeStrong::from(weak.clone());
+            assert!(a.is_strong() && !b.is_strong());
+            assert!(a.ptr_eq(&b) && a.ptr_eq_weak(&weak) && b.ptr_eq_strong(&rc));
+            assert!(!a.ptr_eq_strong(&$Rc::pin(42)));
+            assert!(b.make_strong());
+            assert!(b.is_strong());
+            drop(rc);
+            assert_eq!(weak.strong_count(), 2);
+
+            a.make_weak();
+            assert!(!a.is_strong());
+            assert_eq!(*a.upgrade().unwrap(), 42);
+            b.make_weak();
+            assert!(weak.upgrade().is_none());
+            assert!(a.upgrade().is_none());
+            assert!(!a.make_strong());
+            assert!(!a.is_strong());
+            assert!(a.ptr_eq(&b) && a.downgrade().ptr_eq(&weak));
+        }
+
+        #[test]
+        fn test_strongify() {
+            let a = $Rc::pin(1);
+            let b = $Rc::pin(2);
+            let c = $Rc::pin(3);
+            let weaks = [PinWeak::downgrade_ref(&a), PinWeak::downgrade_ref(&b), PinWeak::downgrade_ref(&c)];
+            {
+                let strong = Strongify::new(&weaks);
+                assert_eq!(a.strong_count(), 2);
+                assert_eq!(strong.dead_count(), 0);
+                assert_eq!(strong.iter().map(|x| *x).collect::<std::vec::Vec<_>>(), [1, 2, 3]);
+            }
+            assert_eq!(a.strong_count(), 1);
+            drop(b);
+            {
+                let strong = Strongify::new(weaks.iter());
+                assert_eq!(strong.dead_count(), 1);
+                assert_eq!(strong.iter().map(|x| *x).collect::<std::vec::Vec<_>>(), [1, 3]);
+                assert!(Strongify::all_or_nothing(&weaks).is_none());
+                assert_eq!(c.strong_count(), 2);
+            }
+            assert_eq!(c.strong_count(), 1);
+            let strong = Strongify::all_or_nothing([&weaks[0], &weaks[2]]).unwrap();
+            assert_eq!(strong.iter().count(), 2);
+            assert_eq!(a.strong_count(), 2);
+            drop(strong);
+            assert_eq!((a.strong_count(), c.strong_count()), (1, 1));
+        }
+
+        #[test]
+        fn test_iter_ext() {
+            use std::vec::Vec;
+            let values: Vec<_> = (0..4).map($Rc::pin).collect();
+            let weaks: Vec<PinWeak<i32>> = values.iter().downgraded().collect();
+            assert_eq!(values.iter().downgraded().size_hint(), (4, Some(4)));
+            assert_eq!(weaks.iter().upgraded().size_hint(), (0, Some(4)));
+            assert_eq!(weaks.iter().upgraded().count(), 4);
+            let mut values = values.into_iter();
+            let kept: Vec<_> = values.by_ref().step_by(2).collect();
+            drop(values);
+            let alive: Vec<i32> = weaks.iter().upgraded().map(|v| *v).collect();
+            assert_eq!(alive, [0, 2]);
+            assert_eq!(weaks.clone().into_iter().upgraded().count(), 2);
+            let dead: Vec<_> = kept.into_iter().downgraded().collect();
+            assert!(dead.iter().upgraded().next().is_none());
+            assert_eq!(weaks.iter().upgraded().count(), 0);
+        }
+
+        #[test]
+        fn test_vec_helpers() {
+            use std::vec::Vec;
+            let values: Vec<_> = (0..4).map($Rc::pin).collect();
+            let w = |i: usize| PinWeak::downgrade_ref(&values[i]);
+            let dead = PinWeak::downgrade($Rc::pin(42));
+            let mut weaks = std::vec![w(0), w(1), dead.clone(), w(0), w(2), dead.clone(), w(3), w(2)];
+            assert!(upgrade_all(&weaks[..]).is_none());
+            let live: Vec<_> = upgrade_live(&weaks).iter().map(|v| **v).collect();
+            assert_eq!(live, [0, 1, 0, 2, 3, 2]);
+            assert!(contains_ptr(&weaks, &values[3]));
+            assert!(!contains_ptr(&weaks, &$Rc::pin(3)));
+            dedup_by_ptr(&mut weaks);
+            let ids: Vec<_> = weaks.iter().map(|w| w.id()).collect();
+            assert_eq!(ids, [w(0).id(), w(1).id(), dead.id(), w(2).id(), w(3).id()]);
+            assert_eq!(prune(&mut weaks), 1);
+            assert_eq!(prune(&mut weaks), 0);
+            let upgraded = upgrade_all(weaks.as_slice()).unwrap();
+            assert_eq!(upgraded.iter().map(|v| **v).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        }
+
+        #[cfg(feature = "heapless")]
+        #[test]
+        fn test_pin_weak_list() {
+            let values: std::vec::Vec<_> = (0..6).map($Rc::pin).collect();
+            let w = |i: usize| PinWeak::downgrade_ref(&values[i]);
+            let mut list = PinWeakList::<i32, 4>::new();
+            for i in 0..4 {
+                list.push(w(i)).unwrap();
+            }
+            assert!(list.push(w(4)).unwrap_err() == values[4]);
+            assert_eq!((list.len(), list.alive_len()), (4, 4));
+
+            let mut values = values;
+            values.remove(1);
+            values.remove(2); // the value 3
+            assert_eq!((list.len(), list.alive_len()), (4, 2));
+            list.push(PinWeak::downgrade_ref(&values[2])).unwrap();
+            list.push(PinWeak::downgrade_ref(&values[3])).unwrap();
+            assert_eq!((list.len(), list.alive_len()), (4, 4));
+            assert!(list.push(PinWeak::downgrade_ref(&values[0])).is_err());
+
+            drop(values.remove(0));
+            let mut seen = std::vec::Vec::new();
+            list.for_each_alive(|v| seen.push(*v));
+            assert_eq!(seen, [2, 4, 5]);
+            assert_eq!(list.len(), 3);
+            list.clear();
+            assert!(list.is_empty());
+        }
+
+        #[test]
+        fn test_handle_registry() {
+            let mut registry = HandleRegistry::new();
+            let a = $Rc::pin(1);
+            let b = $Rc::pin(2);
+            let ha = registry.insert(&a);
+            let hb = registry.insert(&b);
+            assert_ne!(ha, hb);
+            assert_eq!(registry.insert(&a.clone()), ha);
+            assert_eq!(registry.len(), 2);
+
+            drop(a);
+            assert!(registry.resolve(ha).is_none());
+            assert_eq!(registry.compact(), 1);
+            assert_eq!(registry.compact(), 0);
+            assert_eq!(registry.resolve(hb).map(|b| *b), Some(2));
+            assert_eq!(registry.len(), 1);
+
+            let c = $Rc::pin(3);
+            let hc = registry.insert(&c);
+            assert!(hc != ha && hc != hb);
+            assert_eq!(Handle::from(u64::from(hc)), hc);
+            assert!(registry.remove(hb));
+            assert!(!registry.remove(hb));
+            let hb2 = registry.insert(&b);
+            assert!(hb2 != hb && hb2 != hc);
+            assert_eq!(registry.resolve(hb2).map(|b| *b), Some(2));
+        }
+
+        #[test]
+        fn test_cyclic() {
+            use alloc::string::String;
+            struct Gadget {
+                me: PinWeak<Gadget>,
+                value: String,
+            }
+
+            impl Gadget {
+                fn new(value: String) -> Pin<$Rc<Self>> {
+                    PinWeak::new_cyclic(|me| {
+                        Gadget { me: me.clone(), value }
+                    })
+                }
+
+                /// Return a reference counted pointer to Self.
+                fn me(&self) -> Pin<$Rc<Self>> {
+                    self.me.upgrade().unwrap()
+                }
+
+                fn value(self: Pin<&Self>) -> &str {
+                    &self.get_ref().value
+                }
+            }
+
+            let g = Gadget::new("hello".into());
+            assert_eq!(g.me().as_ref().value(), "hello");
+        }
+    };
+}
+
+pub mod rc {
+    #[doc(no_inline)]
+    /// re-exported for convenience
+    pub use alloc::rc::{Rc, Weak};
+    implementation! {Rc, Weak, "Rc", PinnedRcExt}
+
+    /// Pins a `Rc<T>` to an `Unpin` value, so it can be used with [`PinWeak`]
+    pub fn pin_rc<T: ?Sized + Unpin>(rc: Rc<T>) -> Pin<Rc<T>> {
+        Pin::new(rc)
+    }
+
+    /// Pins a `Rc<T>` to a value that may not be `Unpin`
+    ///
+    /// # Safety
+    ///
+    /// The value must never be moved or mutably borrowed through an unpinned pointer from
+    /// now on. This is the case if `rc` is the only strong pointer and there are no `Weak`
+    /// (for example, it was just created), or if the other `Rc` and `Weak` to the same
+    /// allocation are never used to get mutable access to the value.
+    pub unsafe fn pin_rc_unchecked<T: ?Sized>(rc: Rc<T>) -> Pin<Rc<T>> {
+        Pin::new_unchecked(rc)
+    }
+
+    #[test]
+    fn test_pin_rc() {
+        let rc = Rc::new(42);
+        let pinned = pin_rc(rc.clone());
+        let weak = PinWeak::downgrade_ref(&pinned);
+        assert_eq!(*weak.upgrade().unwrap(), 42);
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop((pinned, rc));
+        assert!(weak.upgrade().is_none());
+
+        struct Foo(core::marker::PhantomPinned);
+        let pinned = unsafe { pin_rc_unchecked(Rc::new(Foo(core::marker::PhantomPinned))) };
+        assert!(PinWeak::downgrade_ref(&pinned).upgrade().is_some());
+    }
+
+    impl<T> PinWeak<T> {
+        /// Same as [`PinWeak::new_cyclic`], but `data_fn` can fail, in which case its error is
+        /// returned and the allocation is released.
+        ///
+        /// Weak pointers cloned by `data_fn` stay dangling if it fails.
+        ///
+        /// This is not available for [`sync::PinWeak`](crate::sync::PinWeak): on failure, an
+        /// other thread could upgrade a clone of the weak pointer to the uninitialized value
+        /// before the allocation is released.
+        ///
+        /// ```rust
+        /// use pin_weak::rc::PinWeak;
+        /// struct Config { me: PinWeak<Config>, value: u32 }
+        /// let parse = |s: &str| -> Result<_, core::num::ParseIntError> {
+        ///     PinWeak::try_new_cyclic_with(|me| Ok(Config { me: me.clone(), value: s.parse()? }))
+        /// };
+        /// let config = parse("42").unwrap();
+        /// assert_eq!(config.me.upgrade().unwrap().value, 42);
+        /// assert!(parse("foo").is_err());
+        /// ```
+        pub fn try_new_cyclic_with<F, E>(data_fn: F) -> Result<Pin<Rc<T>>, E>
+        where
+            F: FnOnce(&Self) -> Result<T, E>,
+        {
+            let mut error = None;
+            let rc = Rc::new_cyclic(|weak: &Weak<core::mem::MaybeUninit<T>>| {
+                // Safety: MaybeUninit<T> has the same layout as T, and the weak pointer cannot be
+                // upgraded before new_cyclic returns.
+                let weak = unsafe { Weak::from_raw(Weak::into_raw(weak.clone()) as *const T) };
+                match data_fn(&Self::from_weak(weak)) {
+                    Ok(value) => core::mem::MaybeUninit::new(value),
+                    Err(e) => {
+                        error = Some(e);
+                        core::mem::MaybeUninit::uninit()
+                    }
+                }
+            });
+            if let Some(e) = error {
+                // Nobody can upgrade in between since Rc is not Send
+                drop(rc);
+                return Err(e);
+            }
+            // Safety: the value was initialized, and nobody had access to the unpinned Rc before.
+            Ok(unsafe { Pin::new_unchecked(Rc::from_raw(Rc::into_raw(rc) as *const T)) })
+        }
+    }
+
+    #[test]
+    fn test_try_new_cyclic_with() {
+        use std::cell::RefCell;
+        struct Node {
+            me: PinWeak<Node>,
+            value: u32,
+            _p: core::marker::PhantomPinned,
+        }
+        let rc = PinWeak::<Node>::try_new_cyclic_with(|me| {
+            assert!(me.upgrade().is_none());
+            Ok::<_, ()>(Node { me: me.clone(), value: 42, _p: core::marker::PhantomPinned })
+        })
+        .unwrap();
+        assert_eq!(rc.me.upgrade().unwrap().value, 42);
+        assert!(rc.me == rc);
+        assert_eq!(rc.me.strong_count(), 1);
+        assert_eq!(rc.me.weak_count(), 1);
+
+        let leaked = RefCell::new(PinWeak::new());
+        let err = PinWeak::<Node>::try_new_cyclic_with(|me| {
+            *leaked.borrow_mut() = me.clone();
+            Err(me.clone())
+        })
+        .err()
+        .unwrap();
+        assert!(err.upgrade().is_none());
+        assert_eq!(err.strong_count(), 0);
+        assert_eq!(err.weak_count(), 0);
+        assert!(err.ptr_eq(&leaked.borrow()));
+        assert!(leaked.borrow().upgrade().is_none());
+    }
+
+    /// A list of [`PinWeak`], for example to keep track of observers without keeping them alive.
+    ///
+    /// The methods take `&self`, so entries can be added while the list is being iterated with
+    /// [`PinWeakVec::for_each_alive`].
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{PinWeak, PinWeakVec, Rc};
+    /// use core::pin::Pin;
+    /// trait Listener { fn notify(self: Pin<&Self>); }
+    /// struct Printer;
+    /// impl Listener for Printer { fn notify(self: Pin<&Self>) { println!("notified") } }
+    ///
+    /// let listeners = PinWeakVec::<dyn Listener>::new();
+    /// let printer: Pin<Rc<dyn Listener>> = Rc::pin(Printer);
+    /// listeners.push(PinWeak::downgrade_ref(&printer));
+    /// listeners.for_each_alive(|l| l.notify());
+    /// drop(printer);
+    /// listeners.for_each_alive(|_| unreachable!());
+    /// assert!(listeners.is_empty());
+    /// ```
+    pub struct PinWeakVec<T: ?Sized> {
+        entries: core::cell::RefCell<alloc::vec::Vec<PinWeak<T>>>,
+    }
+
+    impl<T: ?Sized> PinWeakVec<T> {
+        /// Creates an empty list
+        pub const fn new() -> Self {
+            Self { entries: core::cell::RefCell::new(alloc::vec::Vec::new()) }
+        }
+
+        /// Adds a weak pointer at the end of the list
+        pub fn push(&self, weak: PinWeak<T>) {
+            self.entries.borrow_mut().push(weak);
+        }
+
+        /// Adds a weak pointer at the end of the list, unless the list already contains a
+        /// pointer to the same allocation. Returns whether the pointer was added.
+        pub fn push_unique(&self, weak: PinWeak<T>) -> bool {
+            let mut entries = self.entries.borrow_mut();
+            if entries.iter().any(|w| w.ptr_eq(&weak)) {
+                return false;
+            }
+            entries.push(weak);
+            true
+        }
+
+        /// Calls `f` with each target that is still alive, in insertion order, and removes the
+        /// dead entries from the list.
+        ///
+        /// The alive targets are upgraded before the first call to `f`, and kept alive until
+        /// the iteration is done. `f` can push to this list: the new entries will only be
+        /// visited by the next iteration.
+        pub fn for_each_alive(&self, mut f: impl FnMut(Pin<&T>)) {
+            let alive = {
+                let mut entries = self.entries.borrow_mut();
+                let mut alive = alloc::vec::Vec::with_capacity(entries.len());
+                entries.retain(|w| match w.upgrade() {
+                    Some(rc) => {
+                        alive.push(rc);
+                        true
+                    }
+                    None => false,
+                });
+                alive
+            };
+            for rc in &alive {
+                f(rc.as_ref());
+            }
+        }
+
+        /// Keeps the entries whose target is alive and for which `f` returns true
+        pub fn retain(&mut self, mut f: impl FnMut(Pin<&T>) -> bool) {
+            self.entries.get_mut().retain(|w| w.with_upgraded(&mut f).unwrap_or(false));
+        }
+
+        /// Returns the number of entries, including those whose target was dropped
+        pub fn len(&self) -> usize {
+            self.entries.borrow().len()
+        }
+
+        /// Returns true if the list has no entries, including dead ones
+        pub fn is_empty(&self) -> bool {
+            self.entries.borrow().is_empty()
+        }
+
+        /// Returns the number of entries whose target is still alive
+        pub fn alive_len(&self) -> usize {
+            self.entries.borrow().iter().filter(|w| w.strong_count() > 0).count()
+        }
+
+        /// Returns true if the list contains a pointer to the same allocation as `rc`
+        pub fn contains(&self, rc: &Pin<Rc<T>>) -> bool {
+            self.entries.borrow().iter().any(|w| w.ptr_eq_strong(rc))
+        }
+    }
+
+    impl<T: ?Sized> Default for PinWeakVec<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[test]
+    fn test_pin_weak_vec() {
+        use core::cell::Cell;
+        struct Listener {
+            calls: Cell<u32>,
+            _p: core::marker::PhantomPinned,
+        }
+        let new_listener =
+            || Rc::pin(Listener { calls: Cell::new(0), _p: core::marker::PhantomPinned });
+        let a = new_listener();
+        let b = new_listener();
+        let mut list = PinWeakVec::new();
+        assert!(list.push_unique(PinWeak::downgrade_ref(&a)));
+        assert!(!list.push_unique(PinWeak::downgrade_ref(&a)));
+        list.push(PinWeak::downgrade_ref(&b));
+        assert!(list.contains(&a) && list.contains(&b));
+        assert!(!list.contains(&new_listener()));
+        assert_eq!((list.len(), list.alive_len()), (2, 2));
+
+        drop(b);
+        assert_eq!((list.len(), list.alive_len()), (2, 1));
+        list.for_each_alive(|l| l.calls.set(l.calls.get() + 1));
+        assert_eq!(list.len(), 1);
+        assert_eq!(a.calls.get(), 1);
+
+        // Re-entrant push
+        let c = new_listener();
+        list.for_each_alive(|l| {
+            l.calls.set(l.calls.get() + 1);
+            list.push(PinWeak::downgrade_ref(&c));
+        });
+        assert_eq!(list.len(), 2);
+        assert_eq!(a.calls.get(), 2);
+        assert_eq!(c.calls.get(), 0);
+        list.for_each_alive(|l| l.calls.set(l.calls.get() + 1));
+        assert_eq!((a.calls.get(), c.calls.get()), (3, 1));
+
+        list.push(PinWeak::new());
+        list.retain(|l| l.calls.get() != 3);
+        assert!(!list.contains(&a) && list.contains(&c));
+        assert_eq!(list.len(), 1);
+    }
+
+    /// Identifies a connection to a [`Signal`], returned by [`Signal::connect`]
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct ConnectionId(u64);
+
+    struct Slot<Args: ?Sized> {
+        id: ConnectionId,
+        connected: core::cell::Cell<bool>,
+        /// Returns false if the receiver was dropped
+        call: alloc::boxed::Box<dyn Fn(&Args) -> bool>,
+    }
+
+    /// A signal calling handlers on receivers held by [`PinWeak`]
+    ///
+    /// The handlers are called in connection order. The connections whose receiver was dropped
+    /// are removed when the signal is emitted.
+    ///
+    /// [`Signal::emit`] can be called re-entrantly from a handler. Handlers can also connect and
+    /// disconnect while the signal is emitted: new connections are only called by the next
+    /// emission, and disconnected handlers are not called anymore, even by the ongoing ones.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{PinWeak, Rc, Signal};
+    /// use core::{cell::Cell, pin::Pin};
+    /// struct Label { text: Cell<u32> }
+    ///
+    /// let value_changed = Signal::<u32>::new();
+    /// let label = Rc::pin(Label { text: Cell::new(0) });
+    /// value_changed.connect(PinWeak::downgrade_ref(&label), |label, value| label.text.set(*value));
+    /// value_changed.emit(&42);
+    /// assert_eq!(label.text.get(), 42);
+    /// ```
+    pub struct Signal<Args: ?Sized> {
+        slots: core::cell::RefCell<alloc::vec::Vec<Rc<Slot<Args>>>>,
+        next_id: core::cell::Cell<u64>,
+    }
+
+    impl<Args: ?Sized> Signal<Args> {
+        /// Creates a signal without connections
+        pub const fn new() -> Self {
+            Self {
+                slots: core::cell::RefCell::new(alloc::vec::Vec::new()),
+                next_id: core::cell::Cell::new(0),
+            }
+        }
+
+        /// Connects `handler`, which will be called with the receiver and the arguments each
+        /// time the signal is emitted, as long as `receiver` can be upgraded.
+        pub fn connect<T: ?Sized + 'static>(
+            &self,
+            receiver: PinWeak<T>,
+            handler: impl Fn(Pin<&T>, &Args) + 'static,
+        ) -> ConnectionId
+        where
+            Args: 'static,
+        {
+            self.add_slot(alloc::boxed::Box::new(move |args: &Args| {
+                receiver.with_upgraded(|receiver| handler(receiver, args)).is_some()
+            }))
+        }
+
+        fn add_slot(&self, call: alloc::boxed::Box<dyn Fn(&Args) -> bool>) -> ConnectionId {
+            let id = ConnectionId(self.next_id.get());
+            self.next_id.set(id.0 + 1);
+            self.slots.borrow_mut().push(Rc::new(Slot {
+                id,
+                connected: core::cell::Cell::new(true),
+                call,
+            }));
+            id
+        }
+
+        /// Removes a connection. Returns false if it was already removed.
+        pub fn disconnect(&self, id: ConnectionId) -> bool {
+            let mut slots = self.slots.borrow_mut();
+            let Some(index) = slots.iter().position(|s| s.id == id) else { return false };
+            slots.remove(index).connected.set(false);
+            true
+        }
+
+        /// Calls the handlers of all the connections whose receiver is alive
+        pub fn emit(&self, args: &Args) {
+            let slots = self.slots.borrow().clone();
+            let mut pruned = false;
+            for slot in &slots {
+                if slot.connected.get() && !(slot.call)(args) {
+                    slot.connected.set(false);
+                    pruned = true;
+                }
+            }
+            if pruned {
+                self.slots.borrow_mut().retain(|s| s.connected.get());
+            }
+        }
+
+        /// Returns the number of connections, including those whose receiver was dropped
+        /// since the last emission
+        pub fn len(&self) -> usize {
+            self.slots.borrow().len()
+        }
+
+        /// Returns true if the signal has no connections
+        pub fn is_empty(&self) -> bool {
+            self.slots.borrow().is_empty()
+        }
+    }
+
+    impl<Args: ?Sized> Default for Signal<Args> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    struct StreamState<Args> {
+        buffer: alloc::collections::VecDeque<Args>,
+        capacity: usize,
+        waker: Option<core::task::Waker>,
+    }
+
+    #[cfg(feature = "futures")]
+    impl<Args: Clone + 'static> Signal<Args> {
+        /// Returns a stream of the arguments of the signal, which ends when `receiver` can no
+        /// longer be upgraded. Requires the `futures` feature.
+        ///
+        /// Up to 16 values are buffered, see [`Signal::stream_with_capacity`].
+        pub fn stream<T: ?Sized + 'static>(&self, receiver: PinWeak<T>) -> SignalStream<T, Args> {
+            self.stream_with_capacity(receiver, 16)
+        }
+
+        /// Same as [`Signal::stream`], buffering up to `capacity` values.
+        ///
+        /// When the buffer is full, emitting the signal drops the oldest buffered value.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `capacity` is 0.
+        pub fn stream_with_capacity<T: ?Sized + 'static>(
+            &self,
+            receiver: PinWeak<T>,
+            capacity: usize,
+        ) -> SignalStream<T, Args> {
+            assert!(capacity > 0, "the capacity of a SignalStream must not be 0");
+            let state = Rc::new(core::cell::RefCell::new(StreamState {
+                buffer: alloc::collections::VecDeque::new(),
+                capacity,
+                waker: None,
+            }));
+            let weak_state = Rc::downgrade(&state);
+            let weak_receiver = receiver.clone();
+            self.add_slot(alloc::boxed::Box::new(move |args: &Args| {
+                let Some(state) = weak_state.upgrade() else { return false };
+                let mut state = state.borrow_mut();
+                let alive = weak_receiver.strong_count() > 0;
+                if alive {
+                    if state.buffer.len() == state.capacity {
+                        state.buffer.pop_front();
+                    }
+                    state.buffer.push_back(args.clone());
+                }
+                // Also wake the stream when the receiver was dropped, so it can end
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+                alive
+            }));
+            SignalStream { receiver, state }
+        }
+    }
+
+    /// A [`Stream`](futures_core::Stream) of the values of a [`Signal`], created with
+    /// [`Signal::stream`]. Requires the `futures` feature.
+    ///
+    /// Each poll upgrades the receiver, and the stream ends as soon as that fails, dropping the
+    /// values which were not received yet.
+    #[cfg(feature = "futures")]
+    pub struct SignalStream<T: ?Sized, Args> {
+        receiver: PinWeak<T>,
+        state: Rc<core::cell::RefCell<StreamState<Args>>>,
+    }
+
+    #[cfg(feature = "futures")]
+    impl<T: ?Sized, Args> futures_core::Stream for SignalStream<T, Args> {
+        type Item = Args;
+        fn poll_next(
+            self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<Option<Args>> {
+            let mut state = self.state.borrow_mut();
+            if self.receiver.strong_count() == 0 {
+                state.buffer.clear();
+                state.waker = None;
+                return core::task::Poll::Ready(None);
+            }
+            match state.buffer.pop_front() {
+                Some(value) => core::task::Poll::Ready(Some(value)),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    core::task::Poll::Pending
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_signal_stream() {
+        use core::task::{Context, Poll};
+        use futures_core::Stream;
+        struct WakeCounter(core::sync::atomic::AtomicUsize);
+        impl std::task::Wake for WakeCounter {
+            fn wake(self: alloc::sync::Arc<Self>) {
+                self.0.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            }
+        }
+        let counter = alloc::sync::Arc::new(WakeCounter(Default::default()));
+        let wakes = || counter.0.load(core::sync::atomic::Ordering::Relaxed);
+        let waker = core::task::Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let signal = Signal::<u32>::new();
+        let receiver = Rc::pin(core::marker::PhantomPinned);
+        let mut stream = signal.stream_with_capacity(PinWeak::downgrade_ref(&receiver), 2);
+        let mut poll = || Pin::new(&mut stream).poll_next(&mut cx);
+        assert_eq!(poll(), Poll::Pending);
+        signal.emit(&1);
+        assert_eq!(wakes(), 1);
+        assert_eq!(poll(), Poll::Ready(Some(1)));
+        assert_eq!(poll(), Poll::Pending);
+
+        // Overflow drops the oldest value
+        signal.emit(&2);
+        signal.emit(&3);
+        signal.emit(&4);
+        assert_eq!(wakes(), 2);
+        assert_eq!(poll(), Poll::Ready(Some(3)));
+        assert_eq!(poll(), Poll::Ready(Some(4)));
+        assert_eq!(poll(), Poll::Pending);
+
+        // Dropping the receiver wakes and ends the stream
+        drop(receiver);
+        signal.emit(&5);
+        assert_eq!(wakes(), 3);
+        assert_eq!(signal.len(), 0);
+        assert_eq!(poll(), Poll::Ready(None));
+
+        // Dropping the stream disconnects it
+        let receiver = Rc::pin(core::marker::PhantomPinned);
+        let stream = signal.stream(PinWeak::downgrade_ref(&receiver));
+        assert_eq!(signal.len(), 1);
+        drop(stream);
+        signal.emit(&6);
+        assert!(signal.is_empty());
+    }
+
+    #[test]
+    fn test_signal() {
+        use core::cell::{Cell, RefCell};
+        use std::vec::Vec;
+        struct Receiver {
+            name: &'static str,
+            log: Rc<RefCell<Vec<(&'static str, u32)>>>,
+            _p: core::marker::PhantomPinned,
+        }
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let new_receiver =
+            |name| Rc::pin(Receiver { name, log: log.clone(), _p: core::marker::PhantomPinned });
+        fn record(r: Pin<&Receiver>, v: &u32) {
+            r.log.borrow_mut().push((r.name, *v));
+        }
+
+        let signal = Signal::<u32>::new();
+        let a = new_receiver("a");
+        let b = new_receiver("b");
+        let c = new_receiver("c");
+        signal.connect(PinWeak::downgrade_ref(&b), record);
+        let id_a = signal.connect(PinWeak::downgrade_ref(&a), record);
+        signal.connect(PinWeak::downgrade_ref(&c), |r, v| record(r, &(v * 10)));
+        signal.emit(&1);
+        assert_eq!(*log.borrow(), [("b", 1), ("a", 1), ("c", 10)]);
+
+        log.borrow_mut().clear();
+        drop(b);
+        assert_eq!(signal.len(), 3);
+        signal.emit(&2);
+        assert_eq!(signal.len(), 2);
+        assert_eq!(*log.borrow(), [("a", 2), ("c", 20)]);
+
+        log.borrow_mut().clear();
+        assert!(signal.disconnect(id_a));
+        assert!(!signal.disconnect(id_a));
+        signal.emit(&3);
+        assert_eq!(*log.borrow(), [("c", 30)]);
+
+        // Re-entrant emission, connection and disconnection
+        log.borrow_mut().clear();
+        let signal = Rc::new(Signal::<u32>::new());
+        let depth = Rc::new(Cell::new(0));
+        let (s, d) = (signal.clone(), depth.clone());
+        let late = new_receiver("late");
+        let late_weak = PinWeak::downgrade_ref(&late);
+        signal.connect(PinWeak::downgrade_ref(&a), move |r, v| {
+            record(r, v);
+            if d.replace(d.get() + 1) == 0 {
+                s.disconnect(ConnectionId(1));
+                s.connect(late_weak.clone(), record);
+                s.emit(&(v + 1));
+            }
+        });
+        signal.connect(PinWeak::downgrade_ref(&c), record);
+        signal.emit(&4);
+        assert_eq!(*log.borrow(), [("a", 4), ("a", 5), ("late", 5)]);
+        assert_eq!(signal.len(), 2);
+        // break the cycle
+        signal.disconnect(ConnectionId(0));
+        assert!(signal.disconnect(ConnectionId(2)));
+        assert!(signal.is_empty());
+    }
+
+    /// A [`PinWeak`] in a [`Cell`](core::cell::Cell), which can be changed through a shared
+    /// reference
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{PinWeak, PinWeakCell, Rc};
+    /// struct Widget { parent: PinWeakCell<Widget>, name: &'static str }
+    /// let root = Rc::pin(Widget { parent: PinWeakCell::new(), name: "root" });
+    /// let child = Rc::pin(Widget { parent: PinWeakCell::new(), name: "child" });
+    /// child.parent.set_downgrade(&root);
+    /// assert_eq!(child.parent.upgrade().unwrap().name, "root");
+    /// ```
+    pub struct PinWeakCell<T: ?Sized> {
+        weak: core::cell::Cell<PinWeak<T>>,
+    }
+
+    impl<T: ?Sized> PinWeakCell<T> {
+        /// Creates a cell containing a dangling [`PinWeak`]
+        pub const fn new() -> Self {
+            Self { weak: core::cell::Cell::new(PinWeak::new()) }
+        }
+
+        /// Replaces the weak pointer
+        pub fn set(&self, weak: PinWeak<T>) {
+            self.weak.set(weak);
+        }
+
+        /// Replaces the weak pointer by a weak pointer to `rc`
+        pub fn set_downgrade(&self, rc: &Pin<Rc<T>>) {
+            self.weak.set(PinWeak::downgrade_ref(rc));
+        }
+
+        /// Takes the weak pointer, leaving a dangling one in the cell
+        pub fn take(&self) -> PinWeak<T> {
+            self.weak.take()
+        }
+
+        /// Returns a clone of the weak pointer
+        pub fn get(&self) -> PinWeak<T> {
+            let weak = self.weak.take();
+            self.weak.set(weak.clone());
+            weak
+        }
+
+        /// Upgrades the weak pointer
+        pub fn upgrade(&self) -> Option<Pin<Rc<T>>> {
+            let weak = self.weak.take();
+            let rc = weak.upgrade();
+            self.weak.set(weak);
+            rc
+        }
+
+        /// Replaces the weak pointer by a dangling one
+        pub fn clear(&self) {
+            self.weak.take();
+        }
+    }
+
+    impl<T: ?Sized> Default for PinWeakCell<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T: ?Sized> From<PinWeak<T>> for PinWeakCell<T> {
+        fn from(weak: PinWeak<T>) -> Self {
+            Self { weak: core::cell::Cell::new(weak) }
+        }
+    }
+
+    #[test]
+    fn test_pin_weak_cell() {
+        struct Widget {
+            parent: PinWeakCell<Widget>,
+            me: PinWeakCell<Widget>,
+            name: &'static str,
+            _p: core::marker::PhantomPinned,
+        }
+        let new_widget = |name| {
+            PinWeak::new_cyclic(|me| Widget {
+                parent: PinWeakCell::new(),
+                me: me.clone().into(),
+                name,
+                _p: core::marker::PhantomPinned,
+            })
+        };
+        let (root, other, child) = (new_widget("root"), new_widget("other"), new_widget("child"));
+        assert!(child.me.upgrade().unwrap().name == "child");
+        assert!(child.parent.upgrade().is_none());
+        child.parent.set_downgrade(&root);
+        assert_eq!(child.parent.upgrade().unwrap().name, "root");
+        child.parent.set(other.me.get());
+        assert_eq!(child.parent.upgrade().unwrap().name, "other");
+        drop(other);
+        assert!(child.parent.upgrade().is_none());
+        child.parent.set_downgrade(&root);
+        assert!(child.parent.take() == root);
+        assert!(child.parent.upgrade().is_none());
+        child.parent.set_downgrade(&root);
+        child.parent.clear();
+        assert!(child.parent.get().is_dangling());
+    }
+
+    /// A slot memoizing a value with a [`PinWeak`], so it does not keep the value alive
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{Rc, WeakMemo};
+    /// struct Document(String);
+    /// let memo = WeakMemo::new();
+    /// let doc = memo.get_or_insert_with(|| Rc::pin(Document("parsed".into())));
+    /// assert!(memo.get().is_some());
+    /// drop(doc);
+    /// assert!(memo.get().is_none());
+    /// ```
+    pub struct WeakMemo<T: ?Sized> {
+        weak: PinWeakCell<T>,
+    }
+
+    impl<T: ?Sized> WeakMemo<T> {
+        /// Creates an empty slot
+        pub const fn new() -> Self {
+            Self { weak: PinWeakCell::new() }
+        }
+
+        /// Returns the memoized value if it is still alive
+        pub fn get(&self) -> Option<Pin<Rc<T>>> {
+            self.weak.upgrade()
+        }
+
+        /// Returns the memoized value if it is still alive, otherwise creates it with `f`
+        /// and stores a weak pointer to it
+        pub fn get_or_insert_with(&self, f: impl FnOnce() -> Pin<Rc<T>>) -> Pin<Rc<T>> {
+            if let Some(value) = self.get() {
+                return value;
+            }
+            let value = f();
+            self.weak.set_downgrade(&value);
+            value
+        }
+
+        /// Forgets the memoized value, so the next call to
+        /// [`WeakMemo::get_or_insert_with`] creates a new one
+        pub fn clear(&self) {
+            self.weak.clear();
+        }
+    }
+
+    impl<T: ?Sized> Default for WeakMemo<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[test]
+    fn test_weak_memo() {
+        use core::cell::Cell;
+        struct Parsed(u32, core::marker::PhantomPinned);
+        let calls = Cell::new(0);
+        let parse = || {
+            calls.set(calls.get() + 1);
+            Rc::pin(Parsed(calls.get(), core::marker::PhantomPinned))
+        };
+        let memo = WeakMemo::new();
+        assert!(memo.get().is_none());
+        let first = memo.get_or_insert_with(parse);
+        let second = memo.get_or_insert_with(parse);
+        assert_eq!((first.0, second.0, calls.get()), (1, 1, 1));
+        drop(first);
+        assert_eq!(memo.get_or_insert_with(parse).0, 1);
+        assert_eq!(calls.get(), 1);
+        drop(second);
+        assert!(memo.get().is_none());
+        let third = memo.get_or_insert_with(parse);
+        assert_eq!((third.0, calls.get()), (2, 2));
+        memo.clear();
+        assert!(memo.get().is_none());
+        assert_eq!(memo.get_or_insert_with(parse).0, 3);
+    }
+
+    /// A lazily created value, which is only held with a [`PinWeak`] and is created again by
+    /// calling the initializer function when it was dropped
+    ///
+    /// See [`sync::WeakLazy`](crate::sync::WeakLazy) for a version that can be used in a
+    /// `static`.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{Rc, WeakLazy};
+    /// struct Theme;
+    /// thread_local! { static THEME: WeakLazy<Theme> = WeakLazy::new(|| Rc::pin(Theme)); }
+    /// let theme = THEME.with(|t| t.get());
+    /// ```
+    pub struct WeakLazy<T: ?Sized> {
+        memo: WeakMemo<T>,
+        init: fn() -> Pin<Rc<T>>,
+    }
+
+    impl<T: ?Sized> WeakLazy<T> {
+        /// Creates a `WeakLazy` which will call `init` to create the value
+        pub const fn new(init: fn() -> Pin<Rc<T>>) -> Self {
+            Self { memo: WeakMemo::new(), init }
+        }
+
+        /// Returns the value if it is still alive, otherwise creates it with the initializer
+        pub fn get(&self) -> Pin<Rc<T>> {
+            self.memo.get_or_insert_with(self.init)
+        }
+    }
+
+    #[test]
+    fn test_weak_lazy() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        let lazy = WeakLazy::new(|| Rc::pin(CALLS.fetch_add(1, Ordering::Relaxed)));
+        let a = lazy.get();
+        assert!(PinWeak::downgrade_ref(&lazy.get()) == a);
+        assert_eq!(*a, 0);
+        drop(a);
+        assert_eq!(*lazy.get(), 1);
+        assert_eq!(*lazy.get(), 2);
+    }
+
+    trait CallbackTarget<Args> {
+        fn call(&self, args: Args) -> bool;
+        fn is_alive(&self) -> bool;
+        fn id(&self) -> crate::WeakId;
+    }
+
+    struct CallbackTargetImpl<T: ?Sized, F> {
+        target: PinWeak<T>,
+        handler: F,
+    }
+
+    impl<T: ?Sized, Args, F: Fn(Pin<&T>, Args)> CallbackTarget<Args> for CallbackTargetImpl<T, F> {
+        fn call(&self, args: Args) -> bool {
+            self.target.with_upgraded(|target| (self.handler)(target, args)).is_some()
+        }
+        fn is_alive(&self) -> bool {
+            self.target.strong_count() > 0
+        }
+        fn id(&self) -> crate::WeakId {
+            self.target.id()
+        }
+    }
+
+    /// A callback calling a handler on a target held by a [`PinWeak`], if it is still alive
+    ///
+    /// The type of the target is erased, so callbacks to different types can be stored
+    /// together.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{Rc, WeakCallback};
+    /// use core::{cell::Cell, pin::Pin};
+    /// struct Button { clicks: Cell<u32> }
+    /// let button = Rc::pin(Button { clicks: Cell::new(0) });
+    /// let on_click = WeakCallback::new(&button, |b: Pin<&Button>, n: u32| b.clicks.set(n));
+    /// assert!(on_click.call(3));
+    /// assert_eq!(button.clicks.get(), 3);
+    /// drop(button);
+    /// assert!(!on_click.call(4));
+    /// ```
+    pub struct WeakCallback<Args> {
+        inner: Rc<dyn CallbackTarget<Args>>,
+    }
+
+    impl<Args: 'static> WeakCallback<Args> {
+        /// Creates a callback calling `handler` on `target`, which is held with a [`PinWeak`]
+        pub fn new<T: ?Sized + 'static>(
+            target: &Pin<Rc<T>>,
+            handler: impl Fn(Pin<&T>, Args) + 'static,
+        ) -> Self {
+            Self::from_weak(PinWeak::downgrade_ref(target), handler)
+        }
+
+        /// Same as [`WeakCallback::new`], from a weak pointer to the target
+        pub fn from_weak<T: ?Sized + 'static>(
+            target: PinWeak<T>,
+            handler: impl Fn(Pin<&T>, Args) + 'static,
+        ) -> Self {
+            Self { inner: Rc::new(CallbackTargetImpl { target, handler }) }
+        }
+    }
+
+    impl<Args> WeakCallback<Args> {
+        /// Calls the handler if the target is still alive, and returns whether it was called
+        pub fn call(&self, args: Args) -> bool {
+            self.inner.call(args)
+        }
+
+        /// Returns true if the target is still alive
+        pub fn is_alive(&self) -> bool {
+            self.inner.is_alive()
+        }
+
+        /// Returns true if `target` is the target of this callback
+        pub fn target_ptr_eq<T: ?Sized>(&self, target: &Pin<Rc<T>>) -> bool {
+            self.inner.id() == crate::WeakId::from(target)
+        }
+    }
+
+    impl<Args> Clone for WeakCallback<Args> {
+        fn clone(&self) -> Self {
+            Self { inner: self.inner.clone() }
+        }
+    }
+
+    #[test]
+    fn test_weak_callback() {
+        use core::cell::{Cell, RefCell};
+        use std::vec::Vec;
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        enum Event {
+            Click,
+            Key(char),
+        }
+        struct Button(Cell<u32>, core::marker::PhantomPinned);
+        struct TextInput(RefCell<std::string::String>);
+        let button = Rc::pin(Button(Cell::new(0), core::marker::PhantomPinned));
+        let input = Rc::pin(TextInput(Default::default()));
+        let callbacks: Vec<WeakCallback<Event>> = std::vec![
+            WeakCallback::new(&button, |b, e| {
+                if e == Event::Click {
+                    b.0.set(b.0.get() + 1)
+                }
+            }),
+            WeakCallback::new(&input, |i, e| {
+                if let Event::Key(c) = e {
+                    i.0.borrow_mut().push(c)
+                }
+            }),
+        ];
+        for e in [Event::Click, Event::Key('a'), Event::Click] {
+            assert!(callbacks.iter().all(|c| c.call(e)));
+        }
+        assert_eq!(button.0.get(), 2);
+        assert_eq!(*input.0.borrow(), "a");
+        assert!(callbacks[0].target_ptr_eq(&button));
+        assert!(!callbacks[0].target_ptr_eq(&input));
+        assert!(callbacks[1].clone().target_ptr_eq(&input));
+
+        drop(button);
+        assert!(!callbacks[0].is_alive());
+        assert!(callbacks[1].is_alive());
+        assert!(!callbacks[0].call(Event::Click));
+        assert!(callbacks[1].call(Event::Key('b')));
+        assert_eq!(*input.0.borrow(), "ab");
+    }
+
+    /// A string interner which only holds the strings with [`PinWeak`], so the strings are
+    /// released when they are no longer used
+    ///
+    /// ```rust
+    /// use pin_weak::rc::Interner;
+    /// let interner = Interner::new();
+    /// let a = interner.intern("foo");
+    /// let b = interner.intern("foo");
+    /// assert!(core::ptr::eq(&*a, &*b));
+    /// ```
+    pub struct Interner {
+        strings:
+            core::cell::RefCell<alloc::collections::BTreeMap<alloc::boxed::Box<str>, PinWeak<str>>>,
+    }
+
+    impl Interner {
+        /// Creates an empty interner
+        pub const fn new() -> Self {
+            Self { strings: core::cell::RefCell::new(alloc::collections::BTreeMap::new()) }
+        }
+
+        /// Returns the interned string equal to `s` if it is still alive, otherwise copies `s`
+        /// in a new allocation and records it
+        pub fn intern(&self, s: &str) -> Pin<Rc<str>> {
+            let mut strings = self.strings.borrow_mut();
+            if let Some(weak) = strings.get_mut(s) {
+                if let Some(rc) = weak.upgrade() {
+                    return rc;
+                }
+                let rc = pin_str(s);
+                *weak = PinWeak::downgrade_ref(&rc);
+                return rc;
+            }
+            let rc = pin_str(s);
+            strings.insert(s.into(), PinWeak::downgrade_ref(&rc));
+            rc
+        }
+
+        /// Returns the number of interned strings which are still alive
+        pub fn len_live(&self) -> usize {
+            self.strings.borrow().values().filter(|w| w.strong_count() > 0).count()
+        }
+
+        /// Removes the strings which are no longer alive, and returns how many were removed
+        pub fn prune(&self) -> usize {
+            let mut strings = self.strings.borrow_mut();
+            let len = strings.len();
+            strings.retain(|_, w| w.strong_count() > 0);
+            len - strings.len()
+        }
+    }
+
+    impl Default for Interner {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[test]
+    fn test_interner() {
+        let interner = Interner::new();
+        let foo = interner.intern("foo");
+        let foo2 = interner.intern("foo");
+        let bar = interner.intern("bar");
+        assert!(PinWeak::downgrade_ref(&foo) == foo2);
+        assert!(PinWeak::downgrade_ref(&foo) != bar);
+        assert_eq!(&*bar, "bar");
+        assert_eq!(interner.len_live(), 2);
+
+        let old_foo = PinWeak::downgrade_ref(&foo);
+        drop((foo, foo2));
+        assert_eq!(interner.len_live(), 1);
+        let foo3 = interner.intern("foo");
+        assert_eq!(&*foo3, "foo");
+        assert!(old_foo.upgrade().is_none());
+        assert!(old_foo != foo3);
+        assert_eq!(interner.prune(), 0);
+
+        drop(bar);
+        assert_eq!(interner.prune(), 1);
+        assert_eq!(interner.len_live(), 1);
+    }
+
+    /// A node of a tree, holding its children with strong pointers and its parent with a
+    /// [`PinWeak`]
+    ///
+    /// ```rust
+    /// use pin_weak::rc::PinNode;
+    /// let root = PinNode::new("root");
+    /// let child = PinNode::new("child");
+    /// root.append_child(child.clone());
+    /// assert_eq!(*child.parent().unwrap().data(), "root");
+    /// child.detach();
+    /// assert!(child.parent().is_none());
+    /// assert!(root.children().is_empty());
+    /// ```
+    pub struct PinNode<T> {
+        data: T,
+        me: PinWeak<PinNode<T>>,
+        parent: PinWeakCell<PinNode<T>>,
+        children: core::cell::RefCell<alloc::vec::Vec<Pin<Rc<PinNode<T>>>>>,
+    }
+
+    impl<T> PinNode<T> {
+        /// Creates a node without parent nor children
+        pub fn new(data: T) -> Pin<Rc<Self>> {
+            PinWeak::new_cyclic(|me| Self {
+                data,
+                me: me.clone(),
+                parent: PinWeakCell::new(),
+                children: Default::default(),
+            })
+        }
+
+        /// Returns the data of this node
+        pub fn data(&self) -> &T {
+            &self.data
+        }
+
+        /// Returns the data of this node, which is pinned
+        pub fn pinned_data(self: Pin<&Self>) -> Pin<&T> {
+            // Safety: the data is structurally pinned, it is never moved out of the node
+            unsafe { self.map_unchecked(|node| &node.data) }
+        }
+
+        /// Returns the parent of this node, if it has one which is alive
+        pub fn parent(&self) -> Option<Pin<Rc<Self>>> {
+            self.parent.upgrade()
+        }
+
+        /// Returns the children of this node
+        ///
+        /// The children can't be modified while the returned reference is alive.
+        pub fn children(&self) -> core::cell::Ref<'_, [Pin<Rc<Self>>]> {
+            core::cell::Ref::map(self.children.borrow(), |c| c.as_slice())
+        }
+
+        /// Iterates over the parent of this node, its parent, and so on up to the root
+        pub fn ancestors(&self) -> Ancestors<T> {
+            Ancestors { next: self.parent() }
+        }
+
+        /// Adds `child` as the last child of this node, removing it from its previous parent.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `child` is this node or one of its ancestors, as this would create a cycle.
+        pub fn append_child(&self, child: Pin<Rc<Self>>) {
+            assert!(
+                !core::ptr::eq(&*child, self) && !self.ancestors().any(|a| PinWeak::downgrade_ref(&a) == child),
+                "PinNode::append_child: cannot append a node to itself or to one of its descendants"
+            );
+            child.detach();
+            child.parent.set(self.me.clone());
+            self.children.borrow_mut().push(child);
+        }
+
+        /// Removes `child` from the children of this node. Returns false if it was not a child
+        /// of this node.
+        pub fn remove_child(&self, child: &Pin<Rc<Self>>) -> bool {
+            let mut children = self.children.borrow_mut();
+            let Some(index) = children.iter().position(|c| core::ptr::eq(&**c, &**child)) else {
+                return false;
+            };
+            let removed = children.remove(index);
+            drop(children);
+            removed.parent.clear();
+            true
+        }
+
+        /// Removes this node from the children of its parent
+        pub fn detach(&self) {
+            let Some(parent) = self.parent.take().upgrade() else { return };
+            let mut children = parent.children.borrow_mut();
+            let removed = children
+                .iter()
+                .position(|c| core::ptr::eq(&**c, self))
+                .map(|index| children.remove(index));
+            // Drop the removed node after releasing the borrow
+            drop(children);
+            drop(removed);
+        }
+    }
+
+    /// The iterator returned by [`PinNode::ancestors`]
+    pub struct Ancestors<T> {
+        next: Option<Pin<Rc<PinNode<T>>>>,
+    }
+
+    impl<T> Iterator for Ancestors<T> {
+        type Item = Pin<Rc<PinNode<T>>>;
+        fn next(&mut self) -> Option<Self::Item> {
+            let current = self.next.take()?;
+            self.next = current.parent();
+            Some(current)
+        }
+    }
+
+    #[test]
+    fn test_pin_node() {
+        use core::cell::Cell;
+        use std::vec::Vec;
+        struct Data<'a>(&'static str, &'a Cell<u32>);
+        impl Drop for Data<'_> {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() - 1);
+            }
+        }
+        let alive = Cell::new(0);
+        let node = |name| {
+            alive.set(alive.get() + 1);
+            PinNode::new(Data(name, &alive))
+        };
+        let names =
+            |nodes: &[Pin<Rc<PinNode<Data>>>]| nodes.iter().map(|n| n.data().0).collect::<Vec<_>>();
+
+        let root = node("root");
+        let a = node("a");
+        let b = node("b");
+        let a1 = node("a1");
+        root.append_child(a.clone());
+        root.append_child(b.clone());
+        a.append_child(a1.clone());
+        assert_eq!(names(&root.children()), ["a", "b"]);
+        assert_eq!(a1.parent().unwrap().data().0, "a");
+        assert_eq!(names(&a1.ancestors().collect::<Vec<_>>()), ["a", "root"]);
+        assert!(root.parent().is_none());
+        assert_eq!(a1.as_ref().pinned_data().0, "a1");
+
+        // Re-parenting
+        b.append_child(a1.clone());
+        assert!(a.children().is_empty());
+        assert_eq!(names(&a1.ancestors().collect::<Vec<_>>()), ["b", "root"]);
+        root.append_child(a.clone());
+        assert_eq!(names(&root.children()), ["b", "a"]);
+
+        let cycle = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            a1.append_child(root.clone())
+        }));
+        assert!(cycle.is_err());
+        assert!(!a.remove_child(&a1));
+        assert!(b.remove_child(&a1));
+        assert!(a1.parent().is_none());
+
+        // Detaching a subtree
+        b.append_child(a1.clone());
+        drop((a1, b));
+        assert_eq!(alive.get(), 4);
+        let b = root.children()[0].clone();
+        b.detach();
+        assert!(b.parent().is_none());
+        assert_eq!(names(&root.children()), ["a"]);
+        drop(b);
+        assert_eq!(alive.get(), 2);
+        drop(a);
+        drop(root);
+        assert_eq!(alive.get(), 0);
+    }
+
+    struct ListNode<T> {
+        value: T,
+        next: core::cell::RefCell<Option<Pin<Rc<ListNode<T>>>>>,
+        prev: PinWeakCell<ListNode<T>>,
+        /// The address of the `token` of the list containing this node, or null
+        owner: core::cell::Cell<*const ()>,
+    }
+
+    /// A doubly linked list of pinned values, where each node holds the next one with a strong
+    /// pointer and the previous one with a [`PinWeak`]
+    ///
+    /// Inserting a value returns a [`NodeHandle`], which can be used to insert after or remove
+    /// that node. Handles do not keep the nodes alive, and using the handle of a node which was
+    /// removed, or which belongs to an other list, does nothing.
+    ///
+    /// All the modifications take `&mut self`, so the list can't be modified while it is
+    /// iterated:
+    ///
+    /// ```rust,compile_fail
+    /// use pin_weak::rc::PinLinkedList;
+    /// let mut list = PinLinkedList::new();
+    /// let handle = list.push_back(1);
+    /// for _ in list.iter() {
+    ///     list.remove(&handle);
+    /// }
+    /// ```
+    ///
+    /// ```rust
+    /// use pin_weak::rc::PinLinkedList;
+    /// let mut focus_chain = PinLinkedList::new();
+    /// let first = focus_chain.push_back("first");
+    /// focus_chain.push_back("last");
+    /// focus_chain.insert_after(&first, "second");
+    /// assert_eq!(focus_chain.iter().map(|v| *v).collect::<Vec<_>>(), ["first", "second", "last"]);
+    /// ```
+    pub struct PinLinkedList<T> {
+        head: Option<Pin<Rc<ListNode<T>>>>,
+        tail: PinWeak<ListNode<T>>,
+        len: usize,
+        /// Identifies the nodes of this list
+        token: Rc<()>,
+    }
+
+    /// A handle to a node of a [`PinLinkedList`], which does not keep it alive
+    pub struct NodeHandle<T> {
+        node: PinWeak<ListNode<T>>,
+    }
+
+    impl<T> Clone for NodeHandle<T> {
+        fn clone(&self) -> Self {
+            Self { node: self.node.clone() }
+        }
+    }
+
+    /// Two handles are equal if they refer to the same node
+    impl<T> PartialEq for NodeHandle<T> {
+        fn eq(&self, other: &Self) -> bool {
+            self.node.ptr_eq(&other.node)
+        }
+    }
+
+    impl<T> Eq for NodeHandle<T> {}
+
+    impl<T> PinLinkedList<T> {
+        /// Creates an empty list
+        pub fn new() -> Self {
+            Self { head: None, tail: PinWeak::new(), len: 0, token: Rc::new(()) }
+        }
+
+        fn new_node(&self, value: T) -> Pin<Rc<ListNode<T>>> {
+            Rc::pin(ListNode {
+                value,
+                next: Default::default(),
+                prev: PinWeakCell::new(),
+                owner: core::cell::Cell::new(Rc::as_ptr(&self.token)),
+            })
+        }
+
+        /// Upgrades the handle if it refers to a node of this list
+        fn node(&self, handle: &NodeHandle<T>) -> Option<Pin<Rc<ListNode<T>>>> {
+            handle.node.upgrade().filter(|node| node.owner.get() == Rc::as_ptr(&self.token))
+        }
+
+        /// Appends a value at the end of the list
+        pub fn push_back(&mut self, value: T) -> NodeHandle<T> {
+            let node = self.new_node(value);
+            match self.tail.upgrade() {
+                Some(tail) => {
+                    node.prev.set_downgrade(&tail);
+                    *tail.next.borrow_mut() = Some(node.clone());
+                }
+                None => self.head = Some(node.clone()),
+            }
+            self.tail = PinWeak::downgrade_ref(&node);
+            self.len += 1;
+            NodeHandle { node: self.tail.clone() }
+        }
+
+        /// Inserts a value at the start of the list
+        pub fn push_front(&mut self, value: T) -> NodeHandle<T> {
+            let node = self.new_node(value);
+            match self.head.take() {
+                Some(head) => {
+                    head.prev.set_downgrade(&node);
+                    *node.next.borrow_mut() = Some(head);
+                }
+                None => self.tail = PinWeak::downgrade_ref(&node),
+            }
+            let handle = NodeHandle { node: PinWeak::downgrade_ref(&node) };
+            self.head = Some(node);
+            self.len += 1;
+            handle
+        }
+
+        /// Inserts a value after the node of `handle`. Returns `None`, without inserting, if
+        /// that node is not in this list.
+        pub fn insert_after(&mut self, handle: &NodeHandle<T>, value: T) -> Option<NodeHandle<T>> {
+            let prev = self.node(handle)?;
+            let node = self.new_node(value);
+            let next = prev.next.borrow_mut().take();
+            match &next {
+                Some(next) => next.prev.set_downgrade(&node),
+                None => self.tail = PinWeak::downgrade_ref(&node),
+            }
+            *node.next.borrow_mut() = next;
+            node.prev.set_downgrade(&prev);
+            let handle = NodeHandle { node: PinWeak::downgrade_ref(&node) };
+            *prev.next.borrow_mut() = Some(node);
+            self.len += 1;
+            Some(handle)
+        }
+
+        /// Removes the node of `handle` from the list. Returns false if it is not in this list.
+        pub fn remove(&mut self, handle: &NodeHandle<T>) -> bool {
+            let Some(node) = self.node(handle) else { return false };
+            node.owner.set(core::ptr::null());
+            let prev = node.prev.take();
+            let next = node.next.borrow_mut().take();
+            match &next {
+                Some(next) => next.prev.set(prev.clone()),
+                None => self.tail = prev.clone(),
+            }
+            match prev.upgrade() {
+                Some(prev) => *prev.next.borrow_mut() = next,
+                None => self.head = next,
+            }
+            self.len -= 1;
+            true
+        }
+
+        /// Iterates over the values of the list, from the start to the end
+        pub fn iter(&self) -> PinLinkedListIter<'_, T> {
+            PinLinkedListIter { next: self.head.as_deref(), remaining: self.len }
+        }
+
+        /// Returns the number of values in the list
+        pub fn len(&self) -> usize {
+            self.len
+        }
+
+        /// Returns true if the list is empty
+        pub fn is_empty(&self) -> bool {
+            self.len == 0
+        }
+    }
+
+    impl<T> Default for PinLinkedList<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> Drop for PinLinkedList<T> {
+        fn drop(&mut self) {
+            // Drop the nodes iteratively, to avoid a recursion as deep as the list
+            let mut next = self.head.take();
+            while let Some(node) = next {
+                next = node.next.borrow_mut().take();
+            }
+        }
+    }
+
+    /// The iterator returned by [`PinLinkedList::iter`]
+    pub struct PinLinkedListIter<'a, T> {
+        next: Option<&'a ListNode<T>>,
+        remaining: usize,
+    }
+
+    impl<'a, T> Iterator for PinLinkedListIter<'a, T> {
+        type Item = Pin<&'a T>;
+        fn next(&mut self) -> Option<Pin<&'a T>> {
+            let node = self.next?;
+            // Safety: the nodes are only modified through `&mut PinLinkedList`, which can't
+            // exist while the list is borrowed by this iterator
+            self.next = unsafe { node.next.try_borrow_unguarded() }.ok()?.as_deref();
+            self.remaining -= 1;
+            // Safety: the node is pinned, and the value is never moved out of it
+            Some(unsafe { Pin::new_unchecked(&node.value) })
+        }
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (self.remaining, Some(self.remaining))
+        }
+    }
+
+    impl<T> ExactSizeIterator for PinLinkedListIter<'_, T> {}
+
+    #[test]
+    fn test_pin_linked_list() {
+        use core::cell::Cell;
+        use std::vec::Vec;
+        struct Item<'a>(u32, &'a Cell<u32>, core::marker::PhantomPinned);
+        impl Drop for Item<'_> {
+            fn drop(&mut self) {
+                self.1.set(self.1.get() - 1);
+            }
+        }
+        let alive = Cell::new(0);
+        let item = |v| {
+            alive.set(alive.get() + 1);
+            Item(v, &alive, core::marker::PhantomPinned)
+        };
+        let values = |list: &PinLinkedList<Item>| list.iter().map(|i| i.0).collect::<Vec<_>>();
+
+        let mut list = PinLinkedList::new();
+        let two = list.push_back(item(2));
+        list.push_front(item(1));
+        let four = list.push_back(item(4));
+        let three = list.insert_after(&two, item(3)).unwrap();
+        let five = list.insert_after(&four, item(5)).unwrap();
+        assert_eq!(values(&list), [1, 2, 3, 4, 5]);
+        assert_eq!(list.len(), 5);
+        assert_eq!(list.iter().len(), 5);
+
+        assert!(list.remove(&three));
+        assert!(!list.remove(&three));
+        assert!(list.insert_after(&three, item(0)).is_none());
+        assert_eq!(alive.get(), 4);
+        assert!(list.remove(&five));
+        list.push_back(item(6));
+        assert_eq!(values(&list), [1, 2, 4, 6]);
+
+        let mut other = PinLinkedList::new();
+        assert!(!other.remove(&two));
+        assert!(other.insert_after(&two, item(0)).is_none());
+        let seven = other.push_back(item(7));
+        assert!(!list.remove(&seven));
+        assert!(seven == seven.clone() && seven != two);
+
+        let first = list.iter().next().unwrap().0;
+        assert_eq!(first, 1);
+        assert!(list.remove(&two));
+        assert!(list.remove(&four));
+        assert_eq!(values(&list), [1, 6]);
+        assert_eq!(alive.get(), 3);
+        drop(list);
+        assert_eq!(alive.get(), 1);
+        drop(other);
+        assert_eq!(alive.get(), 0);
+
+        let mut long = PinLinkedList::new();
+        for i in 0..if cfg!(miri) { 1000 } else { 100_000 } {
+            long.push_back(i);
+        }
+        drop(long);
+    }
+
+    struct RegistryInner<T: ?Sized> {
+        entries: core::cell::RefCell<alloc::vec::Vec<(u64, PinWeak<T>)>>,
+        next_id: core::cell::Cell<u64>,
+    }
+
+    trait Deregister {
+        fn deregister(&self, id: u64);
+    }
+
+    impl<T: ?Sized> Deregister for RegistryInner<T> {
+        fn deregister(&self, id: u64) {
+            self.entries.borrow_mut().retain(|(i, _)| *i != id);
+        }
+    }
+
+    /// A registry of the live instances of a type, which does not keep them alive
+    ///
+    /// The instances are removed when the [`Registration`] returned by
+    /// [`InstanceRegistry::register`] is dropped, and the entries of the instances which were
+    /// dropped are removed by `register` and `for_each`.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{InstanceRegistry, Rc};
+    /// struct Window(&'static str);
+    /// let windows = InstanceRegistry::new();
+    /// let main = Rc::pin(Window("main"));
+    /// let _registration = windows.register(&main);
+    /// windows.for_each(|w| println!("{}", w.0));
+    /// assert_eq!(windows.count_live(), 1);
+    /// ```
+    pub struct InstanceRegistry<T: ?Sized> {
+        inner: Rc<RegistryInner<T>>,
+    }
+
+    impl<T: ?Sized> InstanceRegistry<T> {
+        /// Creates an empty registry
+        pub fn new() -> Self {
+            Self {
+                inner: Rc::new(RegistryInner {
+                    entries: Default::default(),
+                    next_id: core::cell::Cell::new(0),
+                }),
+            }
+        }
+
+        /// Calls `f` with each registered instance which is still alive, in registration order.
+        ///
+        /// The instances are upgraded before the first call to `f`, so `f` can register or
+        /// drop registrations, which only affects the next calls to `for_each`.
+        pub fn for_each(&self, mut f: impl FnMut(Pin<&T>)) {
+            let alive = {
+                let mut entries = self.inner.entries.borrow_mut();
+                let mut alive = alloc::vec::Vec::with_capacity(entries.len());
+                entries.retain(|(_, w)| match w.upgrade() {
+                    Some(rc) => {
+                        alive.push(rc);
+                        true
+                    }
+                    None => false,
+                });
+                alive
+            };
+            for rc in &alive {
+                f(rc.as_ref());
+            }
+        }
+
+        /// Returns the number of registered instances which are still alive
+        pub fn count_live(&self) -> usize {
+            self.inner.entries.borrow().iter().filter(|(_, w)| w.strong_count() > 0).count()
+        }
+    }
+
+    impl<T: ?Sized + 'static> InstanceRegistry<T> {
+        /// Adds an instance to the registry, until the returned [`Registration`] is dropped or
+        /// the instance is dropped
+        pub fn register(&self, instance: &Pin<Rc<T>>) -> Registration {
+            let id = self.inner.next_id.get();
+            self.inner.next_id.set(id + 1);
+            let mut entries = self.inner.entries.borrow_mut();
+            entries.retain(|(_, w)| w.strong_count() > 0);
+            entries.push((id, PinWeak::downgrade_ref(instance)));
+            let registry: Rc<dyn Deregister> = self.inner.clone();
+            Registration { registry: Rc::downgrade(&registry), id }
+        }
+    }
+
+    impl<T: ?Sized> Default for InstanceRegistry<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Returned by [`InstanceRegistry::register`], removes the instance from the registry when
+    /// dropped
+    #[must_use = "dropping the registration immediately removes the instance from the registry"]
+    pub struct Registration {
+        registry: Weak<dyn Deregister>,
+        id: u64,
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            if let Some(registry) = self.registry.upgrade() {
+                registry.deregister(self.id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_instance_registry() {
+        use std::vec::Vec;
+        let registry = InstanceRegistry::<u32>::new();
+        let instances: Vec<_> = (0..4).map(Rc::pin).collect();
+        let mut registrations: Vec<_> = instances.iter().map(|i| registry.register(i)).collect();
+        let mut instances = instances.into_iter();
+        let (first, second) = (instances.next().unwrap(), instances.next().unwrap());
+        let fourth = instances.nth(1).unwrap();
+        drop(instances);
+        drop(second);
+        let live = || {
+            let mut live = Vec::new();
+            registry.for_each(|i| live.push(*i));
+            live
+        };
+        // The third instance was dropped too
+        assert_eq!(live(), [0, 3]);
+        assert_eq!(registry.count_live(), 2);
+        drop(registrations.remove(3));
+        assert_eq!(live(), [0]);
+
+        let late = Rc::pin(4);
+        let mut late_registration = None;
+        registry.for_each(|_| late_registration = Some(registry.register(&late)));
+        assert_eq!(live(), [0, 4]);
+        drop(first);
+        assert_eq!(registry.count_live(), 1);
+        drop((registry, late_registration, registrations, fourth));
+    }
+
+    /// A queue of closures to run later on targets held with [`PinWeak`], unless the targets
+    /// were dropped by then
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{DeferredQueue, PinWeak, Rc};
+    /// use core::cell::Cell;
+    /// struct Window { repaints: Cell<u32> }
+    /// let queue = DeferredQueue::new();
+    /// let window = Rc::pin(Window { repaints: Cell::new(0) });
+    /// queue.post(PinWeak::downgrade_ref(&window), |w| w.repaints.set(w.repaints.get() + 1));
+    /// assert_eq!(window.repaints.get(), 0);
+    /// queue.drain();
+    /// assert_eq!(window.repaints.get(), 1);
+    /// ```
+    pub struct DeferredQueue {
+        /// Each closure returns false if its target was dropped
+        entries: core::cell::RefCell<
+            alloc::collections::VecDeque<alloc::boxed::Box<dyn FnOnce() -> bool>>,
+        >,
+    }
+
+    impl DeferredQueue {
+        /// Creates an empty queue
+        pub const fn new() -> Self {
+            Self { entries: core::cell::RefCell::new(alloc::collections::VecDeque::new()) }
+        }
+
+        /// Adds `f` at the end of the queue, to be called by [`DeferredQueue::drain`] with the
+        /// target of `weak` if it is still alive
+        pub fn post<T: ?Sized + 'static>(
+            &self,
+            weak: PinWeak<T>,
+            f: impl FnOnce(Pin<&T>) + 'static,
+        ) {
+            self.entries
+                .borrow_mut()
+                .push_back(alloc::boxed::Box::new(move || weak.with_upgraded(f).is_some()));
+        }
+
+        /// Runs the closures which were posted before this call, in the order they were posted,
+        /// and returns the number of closures which were skipped because their target was
+        /// dropped.
+        ///
+        /// The closures can post new closures to this queue, which will be run by the next call
+        /// to `drain`.
+        pub fn drain(&self) -> usize {
+            let entries = core::mem::take(&mut *self.entries.borrow_mut());
+            entries.into_iter().map(|f| f()).filter(|ran| !ran).count()
+        }
+
+        /// Returns the number of closures waiting to be run
+        pub fn len(&self) -> usize {
+            self.entries.borrow().len()
+        }
+
+        /// Returns true if no closures are waiting to be run
+        pub fn is_empty(&self) -> bool {
+            self.entries.borrow().is_empty()
+        }
+    }
+
+    impl Default for DeferredQueue {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[test]
+    fn test_deferred_queue() {
+        use core::cell::RefCell;
+        use std::vec::Vec;
+        struct Target(&'static str, core::marker::PhantomPinned);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let queue = Rc::new(DeferredQueue::new());
+        let a = Rc::pin(Target("a", core::marker::PhantomPinned));
+        let b = Rc::pin(Target("b", core::marker::PhantomPinned));
+        let post = |t: &Pin<Rc<Target>>, n: u32| {
+            let log = log.clone();
+            queue.post(PinWeak::downgrade_ref(t), move |t| log.borrow_mut().push((t.0, n)));
+        };
+        post(&a, 1);
+        post(&b, 2);
+        post(&a, 3);
+        let (l, q, weak_b) = (log.clone(), queue.clone(), PinWeak::downgrade_ref(&b));
+        queue.post(PinWeak::downgrade_ref(&a), move |t| {
+            l.borrow_mut().push((t.0, 4));
+            let l = l.clone();
+            q.post(weak_b, move |t| l.borrow_mut().push((t.0, 5)));
+        });
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.drain(), 0);
+        assert_eq!(*log.borrow(), [("a", 1), ("b", 2), ("a", 3), ("a", 4)]);
+        assert_eq!(queue.len(), 1);
+
+        queue.post(PinWeak::downgrade_ref(&b), |_| unreachable!());
+        drop(b);
+        assert_eq!(queue.drain(), 2);
+        assert!(queue.is_empty());
+        assert_eq!(queue.drain(), 0);
+        assert_eq!(log.borrow().len(), 4);
+    }
+
+    /// A queue of strong pointers to drop later, when calling [`DropQueue::flush`]
+    ///
+    /// This can be used to release the last strong pointer to an object from within one of
+    /// its methods, and run its destructor at a safe point instead.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{DropQueue, Rc};
+    /// let queue = DropQueue::new();
+    /// queue.defer(Rc::pin(42));
+    /// queue.flush();
+    /// ```
+    pub struct DropQueue {
+        pending: core::cell::RefCell<alloc::vec::Vec<alloc::boxed::Box<dyn core::any::Any>>>,
+    }
+
+    impl DropQueue {
+        /// Creates an empty queue
+        pub const fn new() -> Self {
+            Self { pending: core::cell::RefCell::new(alloc::vec::Vec::new()) }
+        }
+
+        /// Keeps `rc` until the next call to [`DropQueue::flush`]
+        pub fn defer<T: ?Sized + 'static>(&self, rc: Pin<Rc<T>>) {
+            self.pending.borrow_mut().push(alloc::boxed::Box::new(rc));
+        }
+
+        /// Drops the pointers given to [`DropQueue::defer`], in order. The pointers deferred
+        /// by the destructors that run are dropped too, before returning.
+        pub fn flush(&self) {
+            loop {
+                let pending = core::mem::take(&mut *self.pending.borrow_mut());
+                if pending.is_empty() {
+                    break;
+                }
+                drop(pending);
+            }
+        }
+
+        /// Returns the number of pointers waiting to be dropped
+        pub fn len(&self) -> usize {
+            self.pending.borrow().len()
+        }
+
+        /// Returns true if no pointers are waiting to be dropped
+        pub fn is_empty(&self) -> bool {
+            self.pending.borrow().is_empty()
+        }
+    }
+
+    impl Default for DropQueue {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    std::thread_local! {
+        static DEFAULT_DROP_QUEUE: DropQueue = const { DropQueue::new() };
+    }
+
+    /// Keeps `rc` in a thread local [`DropQueue`], until [`flush_deferred_drops`] is called
+    /// from the same thread. Requires the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn defer_drop<T: ?Sized + 'static>(rc: Pin<Rc<T>>) {
+        DEFAULT_DROP_QUEUE.with(|queue| queue.defer(rc));
+    }
+
+    /// Drops the pointers given to [`defer_drop`] from this thread. Requires the `std`
+    /// feature.
+    #[cfg(feature = "std")]
+    pub fn flush_deferred_drops() {
+        DEFAULT_DROP_QUEUE.with(DropQueue::flush);
+    }
+
+    #[test]
+    fn test_drop_queue() {
+        use core::cell::Cell;
+        struct DropFlag(Rc<Cell<u32>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+        struct Nested(Rc<DropQueue>, Cell<Option<Pin<Rc<DropFlag>>>>);
+        impl Drop for Nested {
+            fn drop(&mut self) {
+                self.0.defer(self.1.take().unwrap());
+            }
+        }
+        let dropped = Rc::new(Cell::new(0));
+        let queue = Rc::new(DropQueue::new());
+        queue.defer(Rc::pin(DropFlag(dropped.clone())));
+        let shared = Rc::pin(DropFlag(dropped.clone()));
+        queue.defer(shared.clone());
+        assert_eq!((dropped.get(), queue.len()), (0, 2));
+        queue.flush();
+        assert_eq!(dropped.get(), 1);
+        assert!(queue.is_empty());
+        queue.flush();
+        assert_eq!(dropped.get(), 1);
+        drop(shared);
+        assert_eq!(dropped.get(), 2);
+
+        // a destructor deferring another drop during the flush
+        let nested = Rc::pin(DropFlag(dropped.clone()));
+        queue.defer(Rc::pin(Nested(queue.clone(), Cell::new(Some(nested)))));
+        queue.flush();
+        assert_eq!(dropped.get(), 3);
+        assert!(queue.is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_defer_drop() {
+        use core::cell::Cell;
+        struct DropFlag(Rc<Cell<bool>>);
+        impl Drop for DropFlag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+        let dropped = Rc::new(Cell::new(false));
+        defer_drop(Rc::pin(DropFlag(dropped.clone())));
+        assert!(!dropped.get());
+        flush_deferred_drops();
+        assert!(dropped.get());
+        flush_deferred_drops();
+    }
+
+    /// A thread local "current object", held by a [`PinWeak`]. Requires the `std` feature.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{context, PinWeak, Rc};
+    /// struct Window {
+    ///     title: &'static str,
+    /// }
+    /// let window = Rc::pin(Window { title: "main" });
+    /// let _guard = context::set_current(PinWeak::downgrade(window.clone()));
+    /// let title = context::with_current(|w: Option<core::pin::Pin<&Window>>| w.map(|w| w.title));
+    /// assert_eq!(title, Some("main"));
+    /// ```
+    #[cfg(feature = "std")]
+    pub mod context {
+        use super::{PinWeak, Rc};
+        use alloc::boxed::Box;
+        use core::any::Any;
+        use core::cell::RefCell;
+        use core::pin::Pin;
+
+        std::thread_local! {
+            static CURRENT: RefCell<Option<Box<dyn Any>>> = const { RefCell::new(None) };
+        }
+
+        /// Restores the previous current object when dropped
+        ///
+        /// The guards should be dropped in the reverse order in which they were created.
+        #[must_use = "the current object is reset when the guard is dropped"]
+        pub struct ContextGuard {
+            previous: Option<Box<dyn Any>>,
+        }
+
+        impl Drop for ContextGuard {
+            fn drop(&mut self) {
+                let previous = self.previous.take();
+                CURRENT.with(|current| *current.borrow_mut() = previous);
+            }
+        }
+
+        /// Makes `weak` the current object of this thread, until the returned guard is dropped
+        pub fn set_current<T: ?Sized + 'static>(weak: PinWeak<T>) -> ContextGuard {
+            let previous = CURRENT.with(|current| current.borrow_mut().replace(Box::new(weak)));
+            ContextGuard { previous }
+        }
+
+        /// Calls `f` with the current object of this thread, or `None` if there is none, if it
+        /// was dropped, or if it is not a `T`
+        pub fn with_current<T: ?Sized + 'static, R>(f: impl FnOnce(Option<Pin<&T>>) -> R) -> R {
+            let strong: Option<Pin<Rc<T>>> = CURRENT
+                .with(|current| current.borrow().as_ref()?.downcast_ref::<PinWeak<T>>()?.upgrade());
+            f(strong.as_ref().map(|strong| strong.as_ref()))
+        }
+
+        #[test]
+        fn test_context() {
+            struct Window(u32);
+            let current_id = || with_current(|w: Option<Pin<&Window>>| w.map(|w| w.0));
+            assert_eq!(current_id(), None);
+            let outer = Rc::pin(Window(1));
+            let inner = Rc::pin(Window(2));
+            {
+                let _guard = set_current(PinWeak::downgrade(outer.clone()));
+                assert_eq!(current_id(), Some(1));
+                {
+                    let _guard = set_current(PinWeak::downgrade(inner.clone()));
+                    assert_eq!(current_id(), Some(2));
+                    // the object is not kept alive
+                    drop(inner);
+                    assert_eq!(current_id(), None);
+                }
+                assert_eq!(current_id(), Some(1));
+                // another type
+                let _guard = set_current(PinWeak::downgrade(Rc::pin(42u8)));
+                assert_eq!(current_id(), None);
+                assert!(with_current(|v: Option<Pin<&u8>>| v.is_none()));
+            }
+            assert_eq!(current_id(), None);
+
+            let name: Pin<Rc<str>> = super::pin_str("name");
+            let _guard = set_current(PinWeak::downgrade(name.clone()));
+            assert_eq!(with_current(|n: Option<Pin<&str>>| n.map(|n| n.len())), Some(4));
+            assert_eq!(current_id(), None);
+        }
+    }
+
+    /// Records pinned objects with a label, to check in tests that they were all dropped.
+    /// Requires the `test_util` feature.
+    ///
+    /// See also the [`assert_all_dropped!`](crate::assert_all_dropped) macro.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{DropTracker, Rc};
+    /// let tracker = DropTracker::new();
+    /// let window = Rc::pin(42);
+    /// tracker.track(&window, "window");
+    /// assert_eq!(tracker.live(), ["window"]);
+    /// drop(window);
+    /// pin_weak::assert_all_dropped!(tracker);
+    /// ```
+    #[cfg(feature = "test_util")]
+    pub struct DropTracker {
+        tracked: core::cell::RefCell<alloc::vec::Vec<TrackedObject>>,
+    }
+
+    /// A label, and a function returning whether the object is alive
+    #[cfg(feature = "test_util")]
+    type TrackedObject = (&'static str, alloc::boxed::Box<dyn Fn() -> bool>);
+
+    #[cfg(feature = "test_util")]
+    impl DropTracker {
+        /// Creates a tracker without any objects
+        pub const fn new() -> Self {
+            Self { tracked: core::cell::RefCell::new(alloc::vec::Vec::new()) }
+        }
+
+        /// Records `object`, with a label to identify it in [`DropTracker::live`]
+        pub fn track<T: ?Sized + 'static>(&self, object: &Pin<Rc<T>>, label: &'static str) {
+            let weak = PinWeak::downgrade_ref(object);
+            self.tracked
+                .borrow_mut()
+                .push((label, alloc::boxed::Box::new(move || weak.strong_count() > 0)));
+        }
+
+        /// Returns the labels of the recorded objects that are still alive
+        pub fn live(&self) -> alloc::vec::Vec<&'static str> {
+            self.tracked
+                .borrow()
+                .iter()
+                .filter(|(_, alive)| alive())
+                .map(|(label, _)| *label)
+                .collect()
+        }
+    }
+
+    #[cfg(feature = "test_util")]
+    impl Default for DropTracker {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "test_util")]
+    #[test]
+    #[should_panic(expected = "[\"leaked\"]")]
+    fn test_drop_tracker() {
+        let tracker = DropTracker::new();
+        let a = Rc::pin(1);
+        let b: Pin<Rc<str>> = pin_str("b");
+        tracker.track(&a, "a");
+        tracker.track(&b, "leaked");
+        assert_eq!(tracker.live(), ["a", "leaked"]);
+        drop(a);
+        assert_eq!(tracker.live(), ["leaked"]);
+        core::mem::forget(b);
+        crate::assert_all_dropped!(tracker);
+    }
+
+    /// Identifies a hook registered with [`Notified::on_drop`]
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct HookId(u64);
+
+    /// A wrapper calling hooks when it is dropped, so that the holders of a [`PinWeak`] to it
+    /// can be notified instead of finding out when trying to upgrade.
+    ///
+    /// The hooks are called in the order in which they were registered, before the value is
+    /// dropped. The object cannot be reached anymore at that point, so no hooks can be
+    /// registered on it from a hook. If a hook panics, the next hooks are dropped without
+    /// being called.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{Notified, PinWeak};
+    /// use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+    /// let cache: Rc<RefCell<BTreeMap<u32, PinWeak<Notified<String>>>>> = Default::default();
+    /// let value = Notified::pin(String::from("hello"));
+    /// cache.borrow_mut().insert(1, PinWeak::downgrade_ref(&value));
+    /// let c = cache.clone();
+    /// value.on_drop(move || drop(c.borrow_mut().remove(&1)));
+    /// assert_eq!(value.len(), 5);
+    /// drop(value);
+    /// assert!(cache.borrow().is_empty());
+    /// ```
+    pub struct Notified<T: ?Sized> {
+        #[allow(clippy::type_complexity)]
+        hooks: core::cell::RefCell<alloc::vec::Vec<(HookId, alloc::boxed::Box<dyn FnOnce()>)>>,
+        next_id: core::cell::Cell<u64>,
+        value: T,
+    }
+
+    impl<T> Notified<T> {
+        /// Pins `value` in a new allocation, without any hooks
+        pub fn pin(value: T) -> Pin<Rc<Self>> {
+            Rc::pin(Self {
+                hooks: core::cell::RefCell::new(alloc::vec::Vec::new()),
+                next_id: core::cell::Cell::new(0),
+                value,
+            })
+        }
+    }
+
+    impl<T: ?Sized> Notified<T> {
+        /// Registers `f` to be called when this object is dropped
+        pub fn on_drop(&self, f: impl FnOnce() + 'static) -> HookId {
+            let id = HookId(self.next_id.get());
+            self.next_id.set(id.0 + 1);
+            self.hooks.borrow_mut().push((id, alloc::boxed::Box::new(f)));
+            id
+        }
+
+        /// Unregisters a hook without calling it. Returns false if there was no such hook.
+        pub fn remove_hook(&self, id: HookId) -> bool {
+            let mut hooks = self.hooks.borrow_mut();
+            let len = hooks.len();
+            hooks.retain(|(hook, _)| *hook != id);
+            hooks.len() != len
+        }
+
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(self: Pin<&Self>) -> Pin<&T> {
+            // Safety: the value is never moved out of the wrapper
+            unsafe { self.map_unchecked(|this| &this.value) }
+        }
+    }
+
+    impl<T: ?Sized> core::ops::Deref for Notified<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    impl<T: ?Sized> Drop for Notified<T> {
+        fn drop(&mut self) {
+            for (_, hook) in core::mem::take(self.hooks.get_mut()) {
+                hook();
+            }
+        }
+    }
+
+    #[test]
+    fn test_notified() {
+        use core::cell::{Cell, RefCell};
+        let log = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+        let value = Notified::pin(42);
+        let weak = PinWeak::downgrade_ref(&value);
+        for i in 0..3 {
+            let log = log.clone();
+            value.on_drop(move || log.borrow_mut().push(i));
+        }
+        let removed = {
+            let log = log.clone();
+            value.on_drop(move || log.borrow_mut().push(100))
+        };
+        assert!(value.remove_hook(removed));
+        assert!(!value.remove_hook(removed));
+        assert_eq!(*value.as_ref().as_pin_ref(), 42);
+        let weak_in_hook = weak.clone();
+        let was_alive = Rc::new(Cell::new(true));
+        let w = was_alive.clone();
+        value.on_drop(move || w.set(weak_in_hook.upgrade().is_some()));
+
+        // registering hooks on another object from a hook
+        let other = Notified::pin(());
+        let other_weak = PinWeak::downgrade_ref(&other);
+        {
+            let log = log.clone();
+            value.on_drop(move || {
+                let other = other_weak.upgrade().unwrap();
+                other.on_drop(move || log.borrow_mut().push(200));
+            });
+        }
+        drop(value);
+        assert_eq!(*log.borrow(), [0, 1, 2]);
+        assert!(!was_alive.get());
+        assert!(weak.upgrade().is_none());
+        drop(other);
+        assert_eq!(*log.borrow(), [0, 1, 2, 200]);
+
+        // a panicking hook
+        let value = Notified::pin(Rc::new(()));
+        let inner = Rc::downgrade(&value);
+        value.on_drop(|| panic!("hook"));
+        let called = Rc::new(Cell::new(false));
+        let c = called.clone();
+        value.on_drop(move || c.set(true));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(value)));
+        assert!(result.is_err());
+        assert!(!called.get());
+        assert!(inner.upgrade().is_none());
+    }
+
+    /// A pinned future which can be polled through a [`PinWeak`], so that an executor can keep
+    /// only weak pointers to its tasks, and dropping the strong pointer cancels the task.
+    ///
+    /// See [`PinWeak::poll_if_alive`].
+    ///
+    /// ```rust
+    /// use pin_weak::rc::{PinWeak, WeakTask};
+    /// use core::task::{Context, Poll, Waker};
+    /// # struct NoopWaker;
+    /// # impl std::task::Wake for NoopWaker { fn wake(self: std::sync::Arc<Self>) {} }
+    /// # let waker = Waker::from(std::sync::Arc::new(NoopWaker));
+    /// let mut cx = Context::from_waker(&waker);
+    /// let task = WeakTask::pin(async { 42 });
+    /// let handle = PinWeak::downgrade_ref(&task);
+    /// assert_eq!(handle.poll_if_alive(&mut cx), Poll::Ready(Some(42)));
+    /// ```
+    pub struct WeakTask<F> {
+        future: core::cell::RefCell<Option<F>>,
+    }
+
+    impl<F: core::future::Future> WeakTask<F> {
+        /// Pins `future` in a new allocation
+        pub fn pin(future: F) -> Pin<Rc<Self>> {
+            Rc::pin(Self { future: core::cell::RefCell::new(Some(future)) })
+        }
+
+        /// Polls the future. Returns `Poll::Ready(None)` if the future has already completed.
+        ///
+        /// # Panics
+        ///
+        /// Panics if the future polls itself.
+        pub fn poll(
+            self: Pin<&Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<Option<F::Output>> {
+            let mut future = self.future.borrow_mut();
+            let Some(pinned) = future.as_mut() else { return core::task::Poll::Ready(None) };
+            // Safety: the future is never moved out of the allocation: it is only dropped in
+            // place when it completes, or with the task.
+            let output = core::task::ready!(unsafe { Pin::new_unchecked(pinned) }.poll(cx));
+            *future = None;
+            core::task::Poll::Ready(Some(output))
+        }
+
+        /// Returns true if the future has completed
+        pub fn is_finished(&self) -> bool {
+            self.future.borrow().is_none()
+        }
+    }
+
+    impl<F: core::future::Future> PinWeak<WeakTask<F>> {
+        /// Polls the task if it is alive
+        ///
+        /// Returns `Poll::Ready(None)` if the task was dropped, or has already completed.
+        pub fn poll_if_alive(
+            &self,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<Option<F::Output>> {
+            match self.upgrade() {
+                Some(task) => task.as_ref().poll(cx),
+                None => core::task::Poll::Ready(None),
+            }
+        }
+    }
+
+    #[test]
+    fn test_weak_task() {
+        use core::cell::{Cell, RefCell};
+        use core::future::Future;
+        use core::task::{Context, Poll};
+        struct NoopWaker;
+        impl std::task::Wake for NoopWaker {
+            fn wake(self: alloc::sync::Arc<Self>) {}
+        }
+        /// Completes after being polled `remaining + 1` times, recording each poll
+        struct Countdown {
+            name: &'static str,
+            remaining: u32,
+            log: Rc<RefCell<alloc::vec::Vec<&'static str>>>,
+        }
+        impl Future for Countdown {
+            type Output = &'static str;
+            fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<&'static str> {
+                self.log.borrow_mut().push(self.name);
+                if self.remaining == 0 {
+                    return Poll::Ready(self.name);
+                }
+                self.remaining -= 1;
+                Poll::Pending
+            }
+        }
+
+        let waker = core::task::Waker::from(alloc::sync::Arc::new(NoopWaker));
+        let mut cx = Context::from_waker(&waker);
+        let log = Rc::new(RefCell::new(alloc::vec::Vec::new()));
+        let countdown = |name, remaining| Countdown { name, remaining, log: log.clone() };
+        let a = WeakTask::pin(countdown("a", 2));
+        let b = WeakTask::pin(countdown("b", 10));
+        let mut run_queue = alloc::collections::VecDeque::from([
+            PinWeak::downgrade_ref(&a),
+            PinWeak::downgrade_ref(&b),
+        ]);
+        let cancel_b = Cell::new(Some(b));
+        let mut outputs = alloc::vec::Vec::new();
+        while let Some(task) = run_queue.pop_front() {
+            match task.poll_if_alive(&mut cx) {
+                Poll::Pending => run_queue.push_back(task),
+                Poll::Ready(Some(output)) => {
+                    outputs.push(output);
+                    // cancel b in the middle of its execution
+                    drop(cancel_b.take());
+                }
+                Poll::Ready(None) => {}
+            }
+        }
+        assert_eq!(outputs, ["a"]);
+        assert_eq!(*log.borrow(), ["a", "b", "a", "b", "a"]);
+        assert!(a.is_finished());
+        assert_eq!(PinWeak::downgrade_ref(&a).poll_if_alive(&mut cx), Poll::Ready(None));
+    }
+
+    /// The single owner of a pinned value, which can give out [`PinObserver`]s to it
+    ///
+    /// The handle holds the only strong pointer to the value, and the observers can never give
+    /// out another one: they can only access the value for the duration of
+    /// [`PinObserver::with`]. So the value is dropped with the handle, unless an observer is
+    /// accessing it at that time, in which case it is dropped at the end of that access.
+    ///
+    /// [`PinHandle::with_mut`] gives exclusive access to the value, even while there are
+    /// observers.
+    ///
+    /// ```rust
+    /// use pin_weak::rc::PinHandle;
+    /// let mut handle = PinHandle::new(41);
+    /// let observer = handle.downgrade();
+    /// handle.with_mut(|mut value| *value += 1);
+    /// assert_eq!(observer.with(|value| *value), Some(42));
+    /// drop(handle);
+    /// assert_eq!(observer.with(|value| *value), None);
+    /// ```
+    pub struct PinHandle<T: ?Sized> {
+        rc: Pin<Rc<core::cell::RefCell<T>>>,
+    }
+
+    impl<T> PinHandle<T> {
+        /// Pins `value` in a new allocation
+        pub fn new(value: T) -> Self {
+            Self { rc: Rc::pin(core::cell::RefCell::new(value)) }
+        }
+    }
+
+    impl<T: ?Sized> PinHandle<T> {
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(&self) -> Pin<&T> {
+            // Safety: the value is only borrowed mutably by `with_mut`, which borrows the handle
+            // mutably, and it stays pinned in the allocation.
+            unsafe { Pin::new_unchecked(&*core::cell::RefCell::as_ptr(&self.rc)) }
+        }
+
+        /// Calls `f` with a pinned mutable reference to the value
+        ///
+        /// # Panics
+        ///
+        /// Panics if this is called from the closure of [`PinObserver::with`].
+        pub fn with_mut<R>(&mut self, f: impl FnOnce(Pin<&mut T>) -> R) -> R {
+            let mut value = self.rc.borrow_mut();
+            // Safety: the value is never moved out of the allocation
+            f(unsafe { Pin::new_unchecked(&mut *value) })
+        }
+
+        /// Creates an observer of the value
+        pub fn downgrade(&self) -> PinObserver<T> {
+            PinObserver { weak: PinWeak::downgrade_ref(&self.rc) }
+        }
+    }
+
+    impl<T: ?Sized> core::ops::Deref for PinHandle<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            self.as_pin_ref().get_ref()
+        }
+    }
+
+    /// Gives access to the value of a [`PinHandle`] while it is alive, created with
+    /// [`PinHandle::downgrade`]
+    pub struct PinObserver<T: ?Sized> {
+        weak: PinWeak<core::cell::RefCell<T>>,
+    }
+
+    impl<T: ?Sized> Clone for PinObserver<T> {
+        fn clone(&self) -> Self {
+            Self { weak: self.weak.clone() }
+        }
+    }
+
+    impl<T: ?Sized> PinObserver<T> {
+        /// Calls `f` with a pinned reference to the value. Returns `None`, without calling
+        /// `f`, if the handle was dropped, or if this is called from the closure of
+        /// [`PinHandle::with_mut`].
+        pub fn with<R>(&self, f: impl FnOnce(Pin<&T>) -> R) -> Option<R> {
+            let rc = self.weak.upgrade()?;
+            let value = rc.try_borrow().ok()?;
+            // Safety: the value is never moved out of the allocation
+            Some(f(unsafe { Pin::new_unchecked(&*value) }))
+        }
+
+        /// Returns true if the handle was not dropped
+        pub fn is_alive(&self) -> bool {
+            self.weak.strong_count() > 0
+        }
+    }
+
+    #[test]
+    fn test_pin_handle() {
+        let mut handle = PinHandle::new(41);
+        let observers = [handle.downgrade(), handle.downgrade()];
+        // exclusive access while there are observers
+        let (seen, result) = handle.with_mut(|mut value| {
+            *value += 1;
+            (observers[0].with(|v| *v), *value)
+        });
+        assert_eq!((seen, result), (None, 42));
+        assert_eq!(*handle.as_pin_ref(), 42);
+        assert_eq!(observers[1].with(|v| *v + *handle), Some(84));
+        assert!(observers[0].is_alive());
+
+        drop(handle);
+        assert!(observers.iter().all(|o| !o.is_alive() && o.with(|_| ()).is_none()));
+
+        // dropping the handle while the value is being observed
+        let handle = core::cell::RefCell::new(Some(PinHandle::new(2)));
+        let observer = handle.borrow().as_ref().unwrap().downgrade();
+        assert_eq!(
+            observer.with(|v| {
+                drop(handle.take());
+                *v
+            }),
+            Some(2)
+        );
+        assert!(!observer.is_alive());
+    }
+}
+
+#[cfg(feature = "sync")]
+pub mod sync {
+    #[doc(no_inline)]
+    /// re-exported for convenience
+    pub use alloc::sync::{Arc, Weak};
+    implementation! {Arc, Weak, "Arc", PinnedArcExt, Send, Sync}
+
+    /// Pins a `Arc<T>` to an `Unpin` value, so it can be used with [`PinWeak`]
+    pub fn pin_arc<T: ?Sized + Unpin>(arc: Arc<T>) -> Pin<Arc<T>> {
+        Pin::new(arc)
+    }
+
+    /// Pins a `Arc<T>` to a value that may not be `Unpin`
+    ///
+    /// # Safety
+    ///
+    /// The value must never be moved or mutably borrowed through an unpinned pointer from
+    /// now on. This is the case if `arc` is the only strong pointer and there are no `Weak`
+    /// (for example, it was just created), or if the other `Arc` and `Weak` to the same
+    /// allocation are never used to get mutable access to the value.
+    pub unsafe fn pin_arc_unchecked<T: ?Sized>(arc: Arc<T>) -> Pin<Arc<T>> {
+        Pin::new_unchecked(arc)
+    }
+
+    #[test]
+    fn test_pin_arc() {
+        let arc = Arc::new(42);
+        let pinned = pin_arc(arc.clone());
+        let weak = PinWeak::downgrade_ref(&pinned);
+        assert_eq!(*weak.upgrade().unwrap(), 42);
+        assert_eq!(Arc::strong_count(&arc), 2);
+        drop((pinned, arc));
+        assert!(weak.upgrade().is_none());
+
+        struct Foo(core::marker::PhantomPinned);
+        let pinned = unsafe { pin_arc_unchecked(Arc::new(Foo(core::marker::PhantomPinned))) };
+        assert!(PinWeak::downgrade_ref(&pinned).upgrade().is_some());
+    }
+
+    #[test]
+    fn test_static() {
+        struct Widget(u32);
+        static LAST_FOCUSED: std::sync::Mutex<PinWeak<Widget>> =
+            std::sync::Mutex::new(PinWeak::new());
+        assert!(LAST_FOCUSED.lock().unwrap().upgrade().is_none());
+        let widget = Arc::pin(Widget(42));
+        *LAST_FOCUSED.lock().unwrap() = PinWeak::downgrade(widget.clone());
+        assert_eq!(LAST_FOCUSED.lock().unwrap().upgrade().unwrap().0, 42);
+        drop(widget);
+        assert!(LAST_FOCUSED.lock().unwrap().upgrade().is_none());
+    }
+
+    #[cfg(feature = "std")]
+    struct SubscribersInner<T: ?Sized> {
+        entries: alloc::vec::Vec<(u64, PinWeak<T>)>,
+        next_id: u64,
+    }
+
+    #[cfg(feature = "std")]
+    trait Unsubscribe {
+        fn unsubscribe(&self, id: u64);
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Unsubscribe for std::sync::Mutex<SubscribersInner<T>> {
+        fn unsubscribe(&self, id: u64) {
+            lock(self).entries.retain(|(i, _)| *i != id);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn lock<T: ?Sized>(
+        mutex: &std::sync::Mutex<SubscribersInner<T>>,
+    ) -> std::sync::MutexGuard<'_, SubscribersInner<T>> {
+        // The lock is never held while calling user code, so it cannot be poisoned by it
+        mutex.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A thread safe registry of [`PinWeak`], for example to notify observers without keeping
+    /// them alive. Requires the `std` feature.
+    ///
+    /// [`Subscribers::subscribe`] returns a [`Subscription`] which removes the entry when
+    /// dropped. The entries whose target was dropped are removed by `subscribe` and `notify`.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, PinWeak, Subscribers};
+    /// use core::pin::Pin;
+    /// use std::sync::atomic::{AtomicU32, Ordering};
+    /// struct Counter(AtomicU32);
+    ///
+    /// let subscribers = Subscribers::<Counter>::new();
+    /// let counter = Arc::pin(Counter(AtomicU32::new(0)));
+    /// let subscription = subscribers.subscribe(PinWeak::downgrade_ref(&counter));
+    /// subscribers.notify(|c| { c.0.fetch_add(1, Ordering::Relaxed); });
+    /// drop(subscription);
+    /// subscribers.notify(|c| { c.0.fetch_add(1, Ordering::Relaxed); });
+    /// assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct Subscribers<T: ?Sized> {
+        inner: Arc<std::sync::Mutex<SubscribersInner<T>>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Subscribers<T> {
+        /// Creates an empty registry
+        pub fn new() -> Self {
+            Self {
+                inner: Arc::new(std::sync::Mutex::new(SubscribersInner {
+                    entries: alloc::vec::Vec::new(),
+                    next_id: 0,
+                })),
+            }
+        }
+
+        /// Calls `f` with each target that is still alive, in subscription order.
+        ///
+        /// The alive targets are upgraded while holding the lock, and `f` is called after it
+        /// was released, so it can subscribe or drop a [`Subscription`]. Such changes are only
+        /// visible to the next call to `notify`.
+        pub fn notify(&self, mut f: impl FnMut(Pin<&T>)) {
+            let alive = {
+                let mut inner = lock(&self.inner);
+                let mut alive = alloc::vec::Vec::with_capacity(inner.entries.len());
+                inner.entries.retain(|(_, w)| match w.upgrade() {
+                    Some(arc) => {
+                        alive.push(arc);
+                        true
+                    }
+                    None => false,
+                });
+                alive
+            };
+            for arc in &alive {
+                f(arc.as_ref());
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized + Send + Sync + 'static> Subscribers<T> {
+        /// Adds a weak pointer to the registry, until the returned [`Subscription`] is dropped
+        pub fn subscribe(&self, weak: PinWeak<T>) -> Subscription {
+            let mut inner = lock(&self.inner);
+            inner.entries.retain(|(_, w)| w.strong_count() > 0);
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.entries.push((id, weak));
+            let registry: Arc<dyn Unsubscribe + Send + Sync> = self.inner.clone();
+            Subscription { registry: Arc::downgrade(&registry), id }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Default for Subscribers<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Returned by [`Subscribers::subscribe`], removes the entry from the registry when dropped
+    #[cfg(feature = "std")]
+    #[must_use = "dropping the subscription immediately unsubscribes"]
+    pub struct Subscription {
+        registry: Weak<dyn Unsubscribe + Send + Sync>,
+        id: u64,
+    }
+
+    #[cfg(feature = "std")]
+    impl Drop for Subscription {
+        fn drop(&mut self) {
+            if let Some(registry) = self.registry.upgrade() {
+                registry.unsubscribe(self.id);
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_subscribers() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        struct Counter(AtomicU32, core::marker::PhantomPinned);
+        let new_counter = || Arc::pin(Counter(AtomicU32::new(0), core::marker::PhantomPinned));
+        let subscribers = Subscribers::<Counter>::new();
+        let count = |s: &Subscribers<Counter>| {
+            let mut n = 0;
+            s.notify(|_| n += 1);
+            n
+        };
+
+        let a = new_counter();
+        let b = new_counter();
+        let sub_a = subscribers.subscribe(PinWeak::downgrade_ref(&a));
+        let sub_b = subscribers.subscribe(PinWeak::downgrade_ref(&b));
+        assert_eq!(count(&subscribers), 2);
+        drop(b);
+        assert_eq!(count(&subscribers), 1);
+        drop(sub_b);
+        assert_eq!(lock(&subscribers.inner).entries.len(), 1);
+
+        // Dropping a subscription from within a notification
+        let sub_a = std::sync::Mutex::new(Some(sub_a));
+        subscribers.notify(|c| {
+            c.0.fetch_add(1, Ordering::Relaxed);
+            sub_a.lock().unwrap().take();
+        });
+        assert_eq!(a.0.load(Ordering::Relaxed), 1);
+        assert_eq!(count(&subscribers), 0);
+
+        // The subscription outlives the registry
+        let registry = Subscribers::new();
+        let sub = registry.subscribe(PinWeak::downgrade_ref(&a));
+        drop(registry);
+        drop(sub);
+
+        let counters: std::vec::Vec<_> = (0..4).map(|_| new_counter()).collect();
+        std::thread::scope(|scope| {
+            for c in &counters {
+                let subscribers = &subscribers;
+                scope.spawn(move || {
+                    let _sub = subscribers.subscribe(PinWeak::downgrade_ref(c));
+                    for _ in 0..100 {
+                        subscribers.notify(|c| {
+                            c.0.fetch_add(1, Ordering::Relaxed);
+                        });
+                    }
+                });
+            }
+        });
+        assert!(counters.iter().all(|c| c.0.load(Ordering::Relaxed) >= 100));
+        assert_eq!(count(&subscribers), 0);
+    }
+
+    /// Records pinned objects with a label, to check in tests that they were all dropped.
+    /// Requires the `test_util` and `std` features.
+    ///
+    /// This is the thread safe version of [`rc::DropTracker`](crate::rc::DropTracker). See
+    /// also the [`assert_all_dropped!`](crate::assert_all_dropped) macro.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, DropTracker};
+    /// let tracker = DropTracker::new();
+    /// let worker = Arc::pin(42);
+    /// tracker.track(&worker, "worker");
+    /// std::thread::spawn(move || drop(worker)).join().unwrap();
+    /// pin_weak::assert_all_dropped!(tracker);
+    /// ```
+    #[cfg(all(feature = "test_util", feature = "std"))]
+    pub struct DropTracker {
+        tracked: std::sync::Mutex<alloc::vec::Vec<TrackedObject>>,
+    }
+
+    /// A label, and a function returning whether the object is alive
+    #[cfg(all(feature = "test_util", feature = "std"))]
+    type TrackedObject = (&'static str, alloc::boxed::Box<dyn Fn() -> bool + Send>);
+
+    #[cfg(all(feature = "test_util", feature = "std"))]
+    impl DropTracker {
+        /// Creates a tracker without any objects
+        pub const fn new() -> Self {
+            Self { tracked: std::sync::Mutex::new(alloc::vec::Vec::new()) }
+        }
+
+        /// Records `object`, with a label to identify it in [`DropTracker::live`]
+        pub fn track<T: ?Sized + Send + Sync + 'static>(
+            &self,
+            object: &Pin<Arc<T>>,
+            label: &'static str,
+        ) {
+            let weak = PinWeak::downgrade_ref(object);
+            self.lock().push((label, alloc::boxed::Box::new(move || weak.strong_count() > 0)));
+        }
+
+        /// Returns the labels of the recorded objects that are still alive
+        pub fn live(&self) -> alloc::vec::Vec<&'static str> {
+            self.lock().iter().filter(|(_, alive)| alive()).map(|(label, _)| *label).collect()
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, alloc::vec::Vec<TrackedObject>> {
+            // The lock is never held while calling user code, so it cannot be poisoned by it
+            self.tracked.lock().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    #[cfg(all(feature = "test_util", feature = "std"))]
+    impl Default for DropTracker {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(all(feature = "test_util", feature = "std"))]
+    #[test]
+    #[should_panic(expected = "[\"leaked\"]")]
+    fn test_drop_tracker() {
+        let tracker = DropTracker::new();
+        let a = Arc::pin(1);
+        let b = Arc::pin(2);
+        tracker.track(&a, "a");
+        tracker.track(&b, "leaked");
+        std::thread::scope(|s| {
+            s.spawn(|| assert_eq!(tracker.live(), ["a", "leaked"]));
+        });
+        drop(a);
+        core::mem::forget(b);
+        crate::assert_all_dropped!(tracker);
+    }
+
+    /// Identifies a hook registered with [`Notified::on_drop`]
+    #[cfg(feature = "std")]
+    #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+    pub struct HookId(u64);
+
+    #[cfg(feature = "std")]
+    struct NotifiedHooks {
+        entries: alloc::vec::Vec<(HookId, alloc::boxed::Box<dyn FnOnce() + Send>)>,
+        next_id: u64,
+    }
+
+    /// A wrapper calling hooks when it is dropped. Requires the `std` feature.
+    ///
+    /// This is the thread safe version of [`rc::Notified`](crate::rc::Notified). The hooks
+    /// are called in the order in which they were registered, before the value is dropped,
+    /// from the thread which drops the last strong pointer. If a hook panics, the next hooks
+    /// are dropped without being called.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::Notified;
+    /// use std::sync::mpsc;
+    /// let (sender, receiver) = mpsc::channel();
+    /// let value = Notified::pin(42);
+    /// value.on_drop(move || sender.send("dropped").unwrap());
+    /// std::thread::spawn(move || drop(value));
+    /// assert_eq!(receiver.recv(), Ok("dropped"));
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct Notified<T: ?Sized> {
+        hooks: std::sync::Mutex<NotifiedHooks>,
+        value: T,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T> Notified<T> {
+        /// Pins `value` in a new allocation, without any hooks
+        pub fn pin(value: T) -> Pin<Arc<Self>> {
+            Arc::pin(Self {
+                hooks: std::sync::Mutex::new(NotifiedHooks {
+                    entries: alloc::vec::Vec::new(),
+                    next_id: 0,
+                }),
+                value,
+            })
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Notified<T> {
+        /// Registers `f` to be called when this object is dropped
+        pub fn on_drop(&self, f: impl FnOnce() + Send + 'static) -> HookId {
+            let mut hooks = self.lock();
+            let id = HookId(hooks.next_id);
+            hooks.next_id += 1;
+            hooks.entries.push((id, alloc::boxed::Box::new(f)));
+            id
+        }
+
+        /// Unregisters a hook without calling it. Returns false if there was no such hook.
+        pub fn remove_hook(&self, id: HookId) -> bool {
+            let mut hooks = self.lock();
+            let len = hooks.entries.len();
+            hooks.entries.retain(|(hook, _)| *hook != id);
+            hooks.entries.len() != len
+        }
+
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(self: Pin<&Self>) -> Pin<&T> {
+            // Safety: the value is never moved out of the wrapper
+            unsafe { self.map_unchecked(|this| &this.value) }
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, NotifiedHooks> {
+            // The lock is never held while calling user code, so it cannot be poisoned by it
+            self.hooks.lock().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> core::ops::Deref for Notified<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Drop for Notified<T> {
+        fn drop(&mut self) {
+            let hooks = self.hooks.get_mut().unwrap_or_else(|e| e.into_inner());
+            for (_, hook) in core::mem::take(&mut hooks.entries) {
+                hook();
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_notified() {
+        use std::sync::atomic::{AtomicU32, Ordering};
+        let calls = Arc::new(AtomicU32::new(0));
+        let value = Notified::pin(42);
+        let weak = PinWeak::downgrade_ref(&value);
+        for i in 0..3 {
+            let calls = calls.clone();
+            // checks the order of the calls
+            value.on_drop(move || assert_eq!(calls.fetch_add(1, Ordering::Relaxed), i));
+        }
+        let removed = value.on_drop(|| unreachable!());
+        assert!(value.remove_hook(removed));
+        assert!(!value.remove_hook(removed));
+        assert_eq!(*value.as_ref().as_pin_ref(), 42);
+        std::thread::scope(|s| {
+            s.spawn(|| drop(value));
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[cfg(feature = "std")]
+    #[derive(Default)]
+    struct DropState {
+        dropped: std::sync::Mutex<bool>,
+        condvar: std::sync::Condvar,
+    }
+
+    #[cfg(feature = "std")]
+    impl DropState {
+        fn wait(&self, timeout: Option<std::time::Duration>) -> bool {
+            // The lock is never held while calling user code, so it cannot be poisoned by it
+            let dropped = self.dropped.lock().unwrap_or_else(|e| e.into_inner());
+            match timeout {
+                None => {
+                    drop(self.condvar.wait_while(dropped, |dropped| !*dropped));
+                    true
+                }
+                Some(timeout) => {
+                    let (dropped, _) = self
+                        .condvar
+                        .wait_timeout_while(dropped, timeout, |dropped| !*dropped)
+                        .unwrap_or_else(|e| e.into_inner());
+                    *dropped
+                }
+            }
+        }
+    }
+
+    /// A wrapper which lets other threads wait until it is dropped. Requires the `std` feature.
+    ///
+    /// The holders of a `PinWeak<DropSignal<T>>` can wait with [`PinWeak::wait_dropped`], and
+    /// [`DropSignal::waiter`] gives a [`DropWaiter`] which can wait without pointing to the
+    /// object.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{DropSignal, PinWeak};
+    /// let ui = DropSignal::pin(String::from("main window"));
+    /// let weak = PinWeak::downgrade_ref(&ui);
+    /// let worker = std::thread::spawn(move || weak.wait_dropped());
+    /// drop(ui);
+    /// worker.join().unwrap();
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct DropSignal<T: ?Sized> {
+        state: Arc<DropState>,
+        value: T,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T> DropSignal<T> {
+        /// Pins `value` in a new allocation
+        pub fn pin(value: T) -> Pin<Arc<Self>> {
+            Arc::pin(Self { state: Default::default(), value })
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> DropSignal<T> {
+        /// Returns a handle to wait until this object is dropped
+        pub fn waiter(&self) -> DropWaiter {
+            DropWaiter { state: self.state.clone() }
+        }
+
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(self: Pin<&Self>) -> Pin<&T> {
+            // Safety: the value is never moved out of the wrapper
+            unsafe { self.map_unchecked(|this| &this.value) }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> core::ops::Deref for DropSignal<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Drop for DropSignal<T> {
+        fn drop(&mut self) {
+            *self.state.dropped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+            self.state.condvar.notify_all();
+        }
+    }
+
+    /// A handle to wait until a [`DropSignal`] is dropped, returned by [`DropSignal::waiter`].
+    /// Requires the `std` feature.
+    #[cfg(feature = "std")]
+    #[derive(Clone)]
+    pub struct DropWaiter {
+        state: Arc<DropState>,
+    }
+
+    #[cfg(feature = "std")]
+    impl DropWaiter {
+        /// Blocks the current thread until the object is dropped
+        pub fn wait_dropped(&self) {
+            self.state.wait(None);
+        }
+
+        /// Blocks the current thread until the object is dropped, or until `timeout` elapsed.
+        /// Returns true if the object was dropped.
+        pub fn wait_dropped_timeout(&self, timeout: std::time::Duration) -> bool {
+            self.state.wait(Some(timeout))
+        }
+
+        /// Returns true if the object was dropped
+        pub fn is_dropped(&self) -> bool {
+            *self.state.dropped.lock().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> PinWeak<DropSignal<T>> {
+        fn waiter(&self) -> Option<DropWaiter> {
+            self.upgrade().map(|signal| signal.waiter())
+        }
+
+        /// Blocks the current thread until the object is dropped
+        ///
+        /// This returns immediately if the object was already dropped, or if this is a
+        /// dangling pointer.
+        pub fn wait_dropped(&self) {
+            if let Some(waiter) = self.waiter() {
+                waiter.wait_dropped();
+            }
+        }
+
+        /// Blocks the current thread until the object is dropped, or until `timeout` elapsed.
+        /// Returns true if the object was dropped.
+        pub fn wait_dropped_timeout(&self, timeout: std::time::Duration) -> bool {
+            self.waiter().map_or(true, |waiter| waiter.wait_dropped_timeout(timeout))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_drop_signal() {
+        use std::time::Duration;
+        let signal = DropSignal::pin(42);
+        let weak = PinWeak::downgrade_ref(&signal);
+        let waiter = signal.waiter();
+        assert_eq!(*signal.as_ref().as_pin_ref(), 42);
+        assert!(!weak.wait_dropped_timeout(Duration::from_millis(10)));
+        assert!(!waiter.wait_dropped_timeout(Duration::from_millis(10)));
+        assert!(!waiter.is_dropped());
+        std::thread::scope(|s| {
+            let w = s.spawn(|| weak.wait_dropped());
+            let t = s.spawn(|| waiter.wait_dropped_timeout(Duration::from_secs(60)));
+            s.spawn(move || {
+                std::thread::sleep(Duration::from_millis(10));
+                drop(signal);
+            });
+            w.join().unwrap();
+            assert!(t.join().unwrap());
+        });
+        assert!(waiter.is_dropped());
+        assert!(weak.wait_dropped_timeout(Duration::from_secs(60)));
+        waiter.wait_dropped();
+        PinWeak::<DropSignal<u32>>::new().wait_dropped();
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    #[derive(Default)]
+    struct DropNotifyState {
+        dropped: bool,
+        wakers: alloc::collections::BTreeMap<u64, core::task::Waker>,
+        next_key: u64,
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    type SharedDropNotifyState = Arc<std::sync::Mutex<DropNotifyState>>;
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    fn lock_drop_notify(
+        state: &SharedDropNotifyState,
+    ) -> std::sync::MutexGuard<'_, DropNotifyState> {
+        // The lock is never held while calling user code, so it cannot be poisoned by it
+        state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A wrapper which wakes the [`Dropped`] futures when it is dropped. Requires the
+    /// `futures` and `std` features.
+    ///
+    /// This is the asynchronous version of [`DropSignal`]. The futures can be obtained with
+    /// [`WithDropNotify::dropped`], or with [`PinWeak::dropped`] from a weak pointer.
+    ///
+    /// ```rust
+    /// # async fn example() {
+    /// use pin_weak::sync::{PinWeak, WithDropNotify};
+    /// let session = WithDropNotify::pin(String::from("session"));
+    /// let gone = PinWeak::downgrade_ref(&session).dropped();
+    /// drop(session);
+    /// gone.await;
+    /// # }
+    /// ```
+    #[cfg(all(feature = "futures", feature = "std"))]
+    pub struct WithDropNotify<T: ?Sized> {
+        state: SharedDropNotifyState,
+        value: T,
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl<T> WithDropNotify<T> {
+        /// Pins `value` in a new allocation
+        pub fn pin(value: T) -> Pin<Arc<Self>> {
+            Arc::pin(Self { state: Default::default(), value })
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl<T: ?Sized> WithDropNotify<T> {
+        /// Returns a future which resolves when this object is dropped
+        pub fn dropped(&self) -> Dropped {
+            Dropped { state: Some(self.state.clone()), key: None }
+        }
+
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(self: Pin<&Self>) -> Pin<&T> {
+            // Safety: the value is never moved out of the wrapper
+            unsafe { self.map_unchecked(|this| &this.value) }
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl<T: ?Sized> core::ops::Deref for WithDropNotify<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl<T: ?Sized> Drop for WithDropNotify<T> {
+        fn drop(&mut self) {
+            let wakers = {
+                let mut state = lock_drop_notify(&self.state);
+                state.dropped = true;
+                core::mem::take(&mut state.wakers)
+            };
+            wakers.into_values().for_each(core::task::Waker::wake);
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl<T: ?Sized> PinWeak<WithDropNotify<T>> {
+        /// Returns a future which resolves when the object is dropped
+        ///
+        /// The future is ready immediately if the object was already dropped, or if this is a
+        /// dangling pointer.
+        pub fn dropped(&self) -> Dropped {
+            self.upgrade().map_or(Dropped { state: None, key: None }, |strong| strong.dropped())
+        }
+    }
+
+    /// A future which resolves when a [`WithDropNotify`] is dropped. Requires the `futures`
+    /// and `std` features.
+    #[cfg(all(feature = "futures", feature = "std"))]
+    #[must_use = "futures do nothing unless polled"]
+    pub struct Dropped {
+        state: Option<SharedDropNotifyState>,
+        key: Option<u64>,
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl core::future::Future for Dropped {
+        type Output = ();
+        fn poll(
+            mut self: Pin<&mut Self>,
+            cx: &mut core::task::Context<'_>,
+        ) -> core::task::Poll<()> {
+            let this = &mut *self;
+            let Some(shared) = &this.state else { return core::task::Poll::Ready(()) };
+            let mut state = lock_drop_notify(shared);
+            if state.dropped {
+                drop(state);
+                this.state = None;
+                return core::task::Poll::Ready(());
+            }
+            // Registering under the same lock as the check means the drop cannot be missed
+            let key = *this.key.get_or_insert_with(|| {
+                state.next_key += 1;
+                state.next_key
+            });
+            state.wakers.insert(key, cx.waker().clone());
+            core::task::Poll::Pending
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            if let (Some(state), Some(key)) = (&self.state, self.key) {
+                lock_drop_notify(state).wakers.remove(&key);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "futures", feature = "std"))]
+    #[test]
+    fn test_dropped() {
+        use core::future::Future;
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        use core::task::{Context, Poll, Waker};
+        struct WakeCounter(AtomicUsize);
+        impl std::task::Wake for WakeCounter {
+            fn wake(self: Arc<Self>) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let counters = [(); 2].map(|_| Arc::new(WakeCounter(AtomicUsize::new(0))));
+        let wakes = |i: usize| counters[i].0.load(Ordering::Relaxed);
+        let wakers = counters.clone().map(Waker::from);
+        let poll =
+            |f: &mut Dropped, i: usize| Pin::new(f).poll(&mut Context::from_waker(&wakers[i]));
+
+        let session = WithDropNotify::pin(42);
+        let weak = PinWeak::downgrade_ref(&session);
+        let mut from_strong = session.dropped();
+        let mut from_weak = weak.dropped();
+        let mut cancelled = weak.dropped();
+        assert_eq!(poll(&mut from_strong, 0), Poll::Pending);
+        assert_eq!(poll(&mut from_weak, 1), Poll::Pending);
+        assert_eq!(poll(&mut from_weak, 1), Poll::Pending);
+        assert_eq!(poll(&mut cancelled, 1), Poll::Pending);
+        drop(cancelled);
+        assert_eq!(*session.as_ref().as_pin_ref(), 42);
+        assert_eq!((wakes(0), wakes(1)), (0, 0));
+
+        std::thread::spawn(move || drop(session)).join().unwrap();
+        assert_eq!((wakes(0), wakes(1)), (1, 1));
+        assert_eq!(poll(&mut from_strong, 0), Poll::Ready(()));
+        assert_eq!(poll(&mut from_weak, 1), Poll::Ready(()));
+
+        // already dropped
+        assert_eq!(poll(&mut weak.dropped(), 0), Poll::Ready(()));
+        assert_eq!(poll(&mut PinWeak::<WithDropNotify<()>>::new().dropped(), 0), Poll::Ready(()));
+        assert_eq!((wakes(0), wakes(1)), (1, 1));
+    }
+
+    #[cfg(feature = "std")]
+    struct HandleValue<T: ?Sized> {
+        lock: std::sync::RwLock<()>,
+        value: core::cell::UnsafeCell<T>,
+    }
+
+    // Safety: the value is only accessed mutably while the lock is held exclusively, and shared
+    // references to it are given to several threads, like with RwLock<T>.
+    #[cfg(feature = "std")]
+    unsafe impl<T: ?Sized + Send + Sync> Sync for HandleValue<T> {}
+
+    /// The single owner of a pinned value, which can give out [`PinObserver`]s to it. Requires
+    /// the `std` feature.
+    ///
+    /// This is the thread safe version of [`rc::PinHandle`](crate::rc::PinHandle). The value
+    /// is locked while an observer accesses it, and while [`PinHandle::with_mut`] runs.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::PinHandle;
+    /// let mut handle = PinHandle::new(41);
+    /// let observer = handle.downgrade();
+    /// handle.with_mut(|mut value| *value += 1);
+    /// std::thread::spawn(move || assert_eq!(observer.with(|value| *value), Some(42)))
+    ///     .join()
+    ///     .unwrap();
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct PinHandle<T: ?Sized> {
+        arc: Pin<Arc<HandleValue<T>>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T> PinHandle<T> {
+        /// Pins `value` in a new allocation
+        pub fn new(value: T) -> Self {
+            let value = core::cell::UnsafeCell::new(value);
+            Self { arc: Arc::pin(HandleValue { lock: std::sync::RwLock::new(()), value }) }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> PinHandle<T> {
+        /// Returns a pinned reference to the value
+        pub fn as_pin_ref(&self) -> Pin<&T> {
+            // Safety: the value is only accessed mutably by `with_mut`, which borrows the
+            // handle mutably, and it stays pinned in the allocation.
+            unsafe { Pin::new_unchecked(&*self.arc.value.get()) }
+        }
+
+        /// Calls `f` with a pinned mutable reference to the value, waiting for the observers
+        /// accessing it to finish
+        ///
+        /// Calling [`PinObserver::with`] from `f` on an observer of this handle deadlocks.
+        pub fn with_mut<R>(&mut self, f: impl FnOnce(Pin<&mut T>) -> R) -> R {
+            let _guard = self.arc.lock.write().unwrap_or_else(|e| e.into_inner());
+            // Safety: the lock is held exclusively, and the value is never moved out of the
+            // allocation
+            f(unsafe { Pin::new_unchecked(&mut *self.arc.value.get()) })
+        }
+
+        /// Creates an observer of the value
+        pub fn downgrade(&self) -> PinObserver<T> {
+            PinObserver { weak: PinWeak::downgrade_ref(&self.arc) }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> core::ops::Deref for PinHandle<T> {
+        type Target = T;
+        fn deref(&self) -> &T {
+            self.as_pin_ref().get_ref()
+        }
+    }
+
+    /// Gives access to the value of a [`PinHandle`] while it is alive, created with
+    /// [`PinHandle::downgrade`]. Requires the `std` feature.
+    #[cfg(feature = "std")]
+    pub struct PinObserver<T: ?Sized> {
+        weak: PinWeak<HandleValue<T>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Clone for PinObserver<T> {
+        fn clone(&self) -> Self {
+            Self { weak: self.weak.clone() }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> PinObserver<T> {
+        /// Calls `f` with a pinned reference to the value, waiting for
+        /// [`PinHandle::with_mut`] to finish if it is running. Returns `None`, without calling
+        /// `f`, if the handle was dropped.
+        pub fn with<R>(&self, f: impl FnOnce(Pin<&T>) -> R) -> Option<R> {
+            let arc = self.weak.upgrade()?;
+            let _guard = arc.lock.read().unwrap_or_else(|e| e.into_inner());
+            // Safety: the lock is held, and the value is never moved out of the allocation
+            Some(f(unsafe { Pin::new_unchecked(&*arc.value.get()) }))
+        }
+
+        /// Returns true if the handle was not dropped
+        pub fn is_alive(&self) -> bool {
+            self.weak.strong_count() > 0
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_pin_handle() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        let mut handle = PinHandle::new(0u32);
+        let observer = handle.downgrade();
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            let reader = s.spawn(|| {
+                let mut last = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let value = observer.with(|v| *v).unwrap();
+                    assert!(value >= last);
+                    last = value;
+                }
+            });
+            // exclusive access while there is an observer using the value
+            for _ in 0..100 {
+                handle.with_mut(|mut value| *value += 1);
+            }
+            stop.store(true, Ordering::Relaxed);
+            reader.join().unwrap();
+        });
+        assert_eq!(*handle.as_pin_ref(), 100);
+        assert_eq!(observer.clone().with(|v| *v), Some(100));
+        drop(handle);
+        assert!(!observer.is_alive());
+        assert!(observer.with(|_| ()).is_none());
+    }
+
+    /// A thread safe registry of the live instances of a type, which does not keep them alive.
+    /// Requires the `std` feature.
+    ///
+    /// This is the thread safe version of [`rc::InstanceRegistry`](crate::rc::InstanceRegistry),
+    /// implemented with [`Subscribers`].
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, InstanceRegistry};
+    /// struct Window(&'static str);
+    /// let windows = InstanceRegistry::new();
+    /// let main = Arc::pin(Window("main"));
+    /// let _registration = windows.register(&main);
+    /// windows.for_each(|w| println!("{}", w.0));
+    /// assert_eq!(windows.count_live(), 1);
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct InstanceRegistry<T: ?Sized> {
+        subscribers: Subscribers<T>,
+    }
+
+    /// Returned by [`InstanceRegistry::register`], removes the instance from the registry when
+    /// dropped
+    #[cfg(feature = "std")]
+    #[must_use = "dropping the registration immediately removes the instance from the registry"]
+    pub struct Registration(#[allow(dead_code)] Subscription);
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> InstanceRegistry<T> {
+        /// Creates an empty registry
+        pub fn new() -> Self {
+            Self { subscribers: Subscribers::new() }
+        }
+
+        /// Calls `f` with each registered instance which is still alive, in registration order.
+        /// See [`Subscribers::notify`].
+        pub fn for_each(&self, f: impl FnMut(Pin<&T>)) {
+            self.subscribers.notify(f)
+        }
+
+        /// Returns the number of registered instances which are still alive
+        pub fn count_live(&self) -> usize {
+            lock(&self.subscribers.inner)
+                .entries
+                .iter()
+                .filter(|(_, w)| w.strong_count() > 0)
+                .count()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized + Send + Sync + 'static> InstanceRegistry<T> {
+        /// Adds an instance to the registry, until the returned [`Registration`] is dropped or
+        /// the instance is dropped
+        pub fn register(&self, instance: &Pin<Arc<T>>) -> Registration {
+            Registration(self.subscribers.subscribe(PinWeak::downgrade_ref(instance)))
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Default for InstanceRegistry<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_instance_registry() {
+        let registry = InstanceRegistry::<u32>::new();
+        let instances: std::vec::Vec<_> = (0..4).map(Arc::pin).collect();
+        let mut registrations: std::vec::Vec<_> =
+            instances.iter().map(|i| registry.register(i)).collect();
+        let mut instances = instances.into_iter();
+        let (first, second) = (instances.next().unwrap(), instances.next().unwrap());
+        drop(second);
+        drop(registrations.remove(2));
+        let live = || {
+            let mut live = std::vec::Vec::new();
+            registry.for_each(|i| live.push(*i));
+            live
+        };
+        assert_eq!(live(), [0, 3]);
+        assert_eq!(registry.count_live(), 2);
+        std::thread::scope(|scope| {
+            scope.spawn(|| assert_eq!(live(), [0, 3]));
+        });
+        drop(first);
+        assert_eq!(registry.count_live(), 1);
+        assert_eq!(live(), [3]);
+    }
+
+    /// A lazily created value, which is only held with a [`PinWeak`] and is created again by
+    /// calling the initializer function when it was dropped. Requires the `std` feature.
+    ///
+    /// It can be used in a `static`, to share an object which is released when it is no longer
+    /// used. The initializer is called while holding a lock, so only one value is created at
+    /// a time, and it must not call [`WeakLazy::get`] on the same `WeakLazy`.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, WeakLazy};
+    /// struct Theme { dark: bool }
+    /// static DEFAULT_THEME: WeakLazy<Theme> = WeakLazy::new(|| Arc::pin(Theme { dark: false }));
+    /// let theme = DEFAULT_THEME.get();
+    /// assert!(!theme.dark);
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct WeakLazy<T: ?Sized> {
+        weak: std::sync::Mutex<PinWeak<T>>,
+        init: fn() -> Pin<Arc<T>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> WeakLazy<T> {
+        /// Creates a `WeakLazy` which will call `init` to create the value
+        pub const fn new(init: fn() -> Pin<Arc<T>>) -> Self {
+            Self { weak: std::sync::Mutex::new(PinWeak::new()), init }
+        }
+
+        /// Returns the value if it is still alive, otherwise creates it with the initializer
+        pub fn get(&self) -> Pin<Arc<T>> {
+            let mut weak = self.weak.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(value) = weak.upgrade() {
+                return value;
+            }
+            let value = (self.init)();
+            *weak = PinWeak::downgrade_ref(&value);
+            value
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_weak_lazy() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+        static LAZY: WeakLazy<u32> = WeakLazy::new(|| {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            Arc::pin(CALLS.fetch_add(1, Ordering::Relaxed))
+        });
+        let barrier = std::sync::Barrier::new(8);
+        let values: std::vec::Vec<_> = std::thread::scope(|scope| {
+            let threads: std::vec::Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        LAZY.get()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|v| PinWeak::downgrade_ref(v) == values[0]));
+        drop(values);
+        assert_eq!(*LAZY.get(), 1);
+        let kept = LAZY.get();
+        assert_eq!(*kept, 2);
+        assert_eq!(*LAZY.get(), 2);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    }
+
+    /// A [`PinWeak`] which can only be set once, and can then be read without locking.
+    /// Requires the `std` feature.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, OnceWeak, PinWeak};
+    /// struct App;
+    /// static APP: OnceWeak<App> = OnceWeak::new();
+    /// let app = Arc::pin(App);
+    /// assert!(APP.upgrade().is_none());
+    /// assert!(APP.set(PinWeak::downgrade_ref(&app)).is_ok());
+    /// assert!(APP.set(PinWeak::downgrade_ref(&app)).is_err());
+    /// assert!(APP.upgrade().is_some());
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct OnceWeak<T: ?Sized> {
+        weak: std::sync::OnceLock<PinWeak<T>>,
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> OnceWeak<T> {
+        /// Creates an empty slot
+        pub const fn new() -> Self {
+            Self { weak: std::sync::OnceLock::new() }
+        }
+
+        /// Sets the weak pointer, or returns it back if it was already set
+        pub fn set(&self, weak: PinWeak<T>) -> Result<(), PinWeak<T>> {
+            self.weak.set(weak)
+        }
+
+        /// Returns a clone of the weak pointer, if it was set
+        pub fn get(&self) -> Option<PinWeak<T>> {
+            self.weak.get().cloned()
+        }
+
+        /// Upgrades the weak pointer, if it was set
+        pub fn upgrade(&self) -> Option<Pin<Arc<T>>> {
+            self.weak.get()?.upgrade()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<T: ?Sized> Default for OnceWeak<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_once_weak() {
+        let values: std::vec::Vec<_> = (0..8).map(Arc::pin).collect();
+        let slot = OnceWeak::new();
+        let barrier = std::sync::Barrier::new(16);
+        let winners = std::thread::scope(|scope| {
+            let readers: std::vec::Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        let mut seen = None;
+                        for _ in 0..1000 {
+                            match (slot.upgrade(), seen) {
+                                (None, None) => {}
+                                (Some(v), None) => seen = Some(*v),
+                                (Some(v), Some(s)) => assert_eq!(*v, s),
+                                (None, Some(_)) => panic!("the value was unset"),
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let setters: std::vec::Vec<_> = values
+                .iter()
+                .map(|v| {
+                    let (slot, barrier) = (&slot, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        slot.set(PinWeak::downgrade_ref(v)).is_ok()
+                    })
+                })
+                .collect();
+            readers.into_iter().for_each(|t| t.join().unwrap());
+            setters.into_iter().map(|t| t.join().unwrap()).filter(|won| *won).count()
+        });
+        assert_eq!(winners, 1);
+        let winner = slot.upgrade().unwrap();
+        assert!(slot.get().unwrap() == values[*winner as usize]);
+        drop(winner);
+        drop(values);
+        assert!(slot.upgrade().is_none());
+        assert!(slot.get().is_some());
+    }
+
+    /// A [`PinWeak`] which can only be set once, for `no_std` targets. Requires the
+    /// `critical-section` feature.
+    ///
+    /// Unlike [`OnceWeak`], which uses `std::sync::OnceLock`, [`CsOnceWeak::set`] runs in a
+    /// [`critical_section::with`], so this can be used from interrupt handlers. Reading only
+    /// needs an atomic load.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, CsOnceWeak, PinWeak};
+    /// struct App;
+    /// static APP: CsOnceWeak<App> = CsOnceWeak::new();
+    /// let app = Arc::pin(App);
+    /// assert!(APP.set(PinWeak::downgrade_ref(&app)).is_ok());
+    /// assert!(APP.upgrade().is_some());
+    /// ```
+    #[cfg(feature = "critical-section")]
+    pub struct CsOnceWeak<T: ?Sized> {
+        is_set: core::sync::atomic::AtomicBool,
+        weak: core::cell::UnsafeCell<PinWeak<T>>,
+    }
+
+    // Safety: `weak` is only modified once, in a critical section, before `is_set` is set,
+    // and only read after `is_set` was set.
+    #[cfg(feature = "critical-section")]
+    unsafe impl<T: ?Sized + Send + Sync> Sync for CsOnceWeak<T> {}
+
+    #[cfg(feature = "critical-section")]
+    impl<T: ?Sized> CsOnceWeak<T> {
+        /// Creates an empty slot
+        pub const fn new() -> Self {
+            Self {
+                is_set: core::sync::atomic::AtomicBool::new(false),
+                weak: core::cell::UnsafeCell::new(PinWeak::new()),
+            }
+        }
+
+        /// Sets the weak pointer, or returns it back if it was already set
+        pub fn set(&self, weak: PinWeak<T>) -> Result<(), PinWeak<T>> {
+            use core::sync::atomic::Ordering;
+            critical_section::with(|_| {
+                if self.is_set.load(Ordering::Relaxed) {
+                    return Err(weak);
+                }
+                // Safety: nobody reads `weak` until `is_set` is set, and the other writers
+                // are excluded by the critical section
+                unsafe { *self.weak.get() = weak };
+                self.is_set.store(true, Ordering::Release);
+                Ok(())
+            })
+        }
+
+        fn get_ref(&self) -> Option<&PinWeak<T>> {
+            // Safety: `weak` is never modified after `is_set` was set
+            self.is_set
+                .load(core::sync::atomic::Ordering::Acquire)
+                .then(|| unsafe { &*self.weak.get() })
+        }
+
+        /// Returns a clone of the weak pointer, if it was set
+        pub fn get(&self) -> Option<PinWeak<T>> {
+            self.get_ref().cloned()
+        }
+
+        /// Upgrades the weak pointer, if it was set
+        pub fn upgrade(&self) -> Option<Pin<Arc<T>>> {
+            self.get_ref()?.upgrade()
+        }
+    }
+
+    #[cfg(feature = "critical-section")]
+    impl<T: ?Sized> Default for CsOnceWeak<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "critical-section")]
+    #[test]
+    fn test_cs_once_weak() {
+        static SLOT: CsOnceWeak<u32> = CsOnceWeak::new();
+        assert!(SLOT.get().is_none());
+        let a = Arc::pin(1);
+        let b = Arc::pin(2);
+        assert!(SLOT.set(PinWeak::downgrade_ref(&a)).is_ok());
+        assert!(SLOT.set(PinWeak::downgrade_ref(&b)).unwrap_err() == b);
+        assert_eq!(*SLOT.upgrade().unwrap(), 1);
+        drop(a);
+        assert!(SLOT.upgrade().is_none());
+
+        let slot = CsOnceWeak::new();
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (slot, b) = (&slot, &b);
+                scope.spawn(move || {
+                    let _ = slot.set(if i % 2 == 0 {
+                        PinWeak::downgrade_ref(b)
+                    } else {
+                        PinWeak::new()
+                    });
+                    if let Some(weak) = slot.get() {
+                        assert!(weak == *b || weak.is_dangling());
+                    }
+                });
+            }
+        });
+        assert!(slot.get().is_some());
+    }
+
+    /// A [`PinWeak`] slot, protected by a lock, which can be loaded and replaced from several
+    /// threads, for example in a `static`
+    ///
+    /// Despite its name, it is **not** lock-free: cloning the weak pointer while an other
+    /// thread replaces it would otherwise race with the release of the old weak pointer.
+    /// Each operation holds the lock for the duration of a pointer copy and of a reference
+    /// count increment, and the replaced weak pointers are dropped after releasing it.
+    ///
+    /// With the `std` feature, the lock is a [`std::sync::Mutex`], so a waiting thread sleeps
+    /// instead of spinning. Without it, the lock is a spin lock, which is only suitable when
+    /// the holder of the lock can always make progress: it must not be used from an interrupt
+    /// handler that may preempt a holder on the same core, nor from threads with different
+    /// priorities on a scheduler without priority inheritance, since the waiter would spin
+    /// forever.
+    ///
+    /// All the operations on an `AtomicPinWeak` are totally ordered. Acquiring and releasing
+    /// the lock have acquire and release semantics, so what a thread did before a
+    /// [`store`](AtomicPinWeak::store) is visible to the threads that [`load`](AtomicPinWeak::load)
+    /// the value it stored.
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, AtomicPinWeak, PinWeak};
+    /// struct Element(u32);
+    /// static FOCUSED: AtomicPinWeak<Element> = AtomicPinWeak::new(PinWeak::new());
+    /// let element = Arc::pin(Element(42));
+    /// FOCUSED.store(PinWeak::downgrade_ref(&element));
+    /// let worker = std::thread::spawn(|| FOCUSED.load().upgrade().map(|e| e.0));
+    /// assert_eq!(worker.join().unwrap(), Some(42));
+    /// ```
+    pub struct AtomicPinWeak<T: ?Sized> {
+        #[cfg(feature = "std")]
+        weak: std::sync::Mutex<PinWeak<T>>,
+        #[cfg(not(feature = "std"))]
+        locked: core::sync::atomic::AtomicBool,
+        #[cfg(not(feature = "std"))]
+        weak: core::cell::UnsafeCell<PinWeak<T>>,
+    }
+
+    // Safety: `weak` is only accessed while holding the lock
+    #[cfg(not(feature = "std"))]
+    unsafe impl<T: ?Sized + Send + Sync> Sync for AtomicPinWeak<T> {}
+
+    impl<T: ?Sized> AtomicPinWeak<T> {
+        /// Creates an `AtomicPinWeak` containing `weak`
+        pub const fn new(weak: PinWeak<T>) -> Self {
+            Self {
+                #[cfg(feature = "std")]
+                weak: std::sync::Mutex::new(weak),
+                #[cfg(not(feature = "std"))]
+                locked: core::sync::atomic::AtomicBool::new(false),
+                #[cfg(not(feature = "std"))]
+                weak: core::cell::UnsafeCell::new(weak),
+            }
+        }
+
+        #[cfg(feature = "std")]
+        fn with_lock<R>(&self, f: impl FnOnce(&mut PinWeak<T>) -> R) -> R {
+            // Cloning or replacing a `PinWeak` does not panic, so the data is never inconsistent
+            f(&mut self.weak.lock().unwrap_or_else(|e| e.into_inner()))
+        }
+
+        #[cfg(not(feature = "std"))]
+        fn with_lock<R>(&self, f: impl FnOnce(&mut PinWeak<T>) -> R) -> R {
+            use core::sync::atomic::Ordering;
+            while self
+                .locked
+                .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+            {
+                while self.locked.load(Ordering::Relaxed) {
+                    core::hint::spin_loop();
+                }
+            }
+            struct Unlock<'a>(&'a core::sync::atomic::AtomicBool);
+            impl Drop for Unlock<'_> {
+                fn drop(&mut self) {
+                    self.0.store(false, Ordering::Release);
+                }
+            }
+            let _unlock = Unlock(&self.locked);
+            // Safety: we hold the lock
+            f(unsafe { &mut *self.weak.get() })
+        }
+
+        /// Returns a clone of the current weak pointer
+        pub fn load(&self) -> PinWeak<T> {
+            self.with_lock(|weak| weak.clone())
+        }
+
+        /// Replaces the weak pointer
+        pub fn store(&self, weak: PinWeak<T>) {
+            drop(self.swap(weak));
+        }
+
+        /// Replaces the weak pointer, and returns the previous one
+        pub fn swap(&self, weak: PinWeak<T>) -> PinWeak<T> {
+            self.with_lock(|current| core::mem::replace(current, weak))
+        }
+
+        /// Replaces the weak pointer by `new` if it points to the same allocation as
+        /// `current`, see [`PinWeak::ptr_eq`].
+        ///
+        /// Returns the previous weak pointer on success, or gives `new` back otherwise.
+        pub fn compare_exchange(
+            &self,
+            current: &PinWeak<T>,
+            new: PinWeak<T>,
+        ) -> Result<PinWeak<T>, PinWeak<T>> {
+            self.with_lock(|weak| {
+                if weak.ptr_eq(current) {
+                    Ok(core::mem::replace(weak, new))
+                } else {
+                    Err(new)
+                }
+            })
+        }
+
+        /// Returns the weak pointer
+        pub fn into_inner(self) -> PinWeak<T> {
+            #[cfg(feature = "std")]
+            return self.weak.into_inner().unwrap_or_else(|e| e.into_inner());
+            #[cfg(not(feature = "std"))]
+            self.weak.into_inner()
+        }
+    }
+
+    impl<T: ?Sized> Default for AtomicPinWeak<T> {
+        fn default() -> Self {
+            Self::new(PinWeak::new())
+        }
+    }
+
+    impl<T: ?Sized> From<PinWeak<T>> for AtomicPinWeak<T> {
+        fn from(weak: PinWeak<T>) -> Self {
+            Self::new(weak)
+        }
+    }
+
+    impl<T: ?Sized> core::fmt::Debug for AtomicPinWeak<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_tuple("AtomicPinWeak").field(&self.load()).finish()
+        }
+    }
+
+    #[test]
+    fn test_atomic_pin_weak() {
+        let a = Arc::pin(1);
+        let b = Arc::pin(2);
+        let slot = AtomicPinWeak::default();
+        assert!(slot.load().is_dangling());
+        slot.store(PinWeak::downgrade_ref(&a));
+        assert_eq!(*slot.load().upgrade().unwrap(), 1);
+        assert!(slot.compare_exchange(&PinWeak::downgrade_ref(&b), PinWeak::new()).is_err());
+        let old = slot.compare_exchange(&PinWeak::downgrade_ref(&a), PinWeak::downgrade_ref(&b));
+        assert!(old.unwrap() == a);
+        assert!(slot.swap(PinWeak::new()) == b);
+        assert!(slot.into_inner().is_dangling());
+
+        let iterations = if cfg!(miri) { 20 } else { 2000 };
+        let slot = AtomicPinWeak::new(PinWeak::downgrade_ref(&a));
+        std::thread::scope(|scope| {
+            for i in 0..4 {
+                let (slot, b) = (&slot, &b);
+                scope.spawn(move || {
+                    for j in 0..iterations {
+                        let value = Arc::pin(i * iterations + j);
+                        slot.store(PinWeak::downgrade_ref(&value));
+                        if let Some(v) = slot.load().upgrade() {
+                            assert!(*v < 4 * iterations);
+                        }
+                        let _ = slot.compare_exchange(&slot.load(), PinWeak::downgrade_ref(b));
+                    }
+                });
+                scope.spawn(move || {
+                    for _ in 0..iterations {
+                        if let Some(v) = slot.load().upgrade() {
+                            assert!(*v < 4 * iterations);
+                        }
+                    }
+                });
+            }
+        });
+        let last = slot.load();
+        assert!(last == b || last.upgrade().is_none());
+        drop(b);
+        assert!(slot.load().upgrade().is_none());
+        assert_eq!(slot.load().weak_count(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    type CacheEntry<T> = Arc<std::sync::Mutex<PinWeak<T>>>;
+
+    #[cfg(feature = "std")]
+    struct CacheShard<K, T: ?Sized> {
+        map: std::collections::HashMap<K, CacheEntry<T>>,
+        /// The shard is pruned when its length reaches this value
+        prune_at: usize,
+    }
+
+    #[cfg(feature = "std")]
+    impl<K, T: ?Sized> CacheShard<K, T> {
+        /// Removes the dead entries that are not being constructed by another thread
+        fn prune(&mut self) -> usize {
+            let len = self.map.len();
+            self.map.retain(|_, e| {
+                Arc::strong_count(e) > 1
+                    || e.lock().unwrap_or_else(|e| e.into_inner()).strong_count() > 0
+            });
+            self.prune_at = (self.map.len() * 2).max(16);
+            len - self.map.len()
+        }
+    }
+
+    /// A thread safe cache of [`PinWeak`], so the cache does not keep its values alive.
+    /// Requires the `std` feature.
+    ///
+    /// The keys are distributed over several mutex-protected shards, and the dead entries are
+    /// removed when they are looked up, when a shard grows, or with [`WeakCache::prune`].
+    ///
+    /// ```rust
+    /// use pin_weak::sync::{Arc, WeakCache};
+    /// struct Texture(String);
+    /// let cache = WeakCache::new();
+    /// let t1 = cache.get_or_insert_with(&"wall.png", || Arc::pin(Texture("wall".into())));
+    /// let t2 = cache.get_or_insert_with(&"wall.png", || unreachable!());
+    /// assert!(core::ptr::eq(&*t1, &*t2));
+    /// drop((t1, t2));
+    /// assert!(cache.get(&"wall.png").is_none());
+    /// ```
+    #[cfg(feature = "std")]
+    pub struct WeakCache<K, T: ?Sized> {
+        shards: alloc::boxed::Box<[std::sync::Mutex<CacheShard<K, T>>]>,
+        hasher: std::collections::hash_map::RandomState,
+    }
+
+    #[cfg(feature = "std")]
+    impl<K, T: ?Sized> WeakCache<K, T> {
+        /// Creates an empty cache
+        pub fn new() -> Self {
+            let shards = (0..16)
+                .map(|_| {
+                    std::sync::Mutex::new(CacheShard {
+                        map: std::collections::HashMap::new(),
+                        prune_at: 16,
+                    })
+                })
+                .collect();
+            Self { shards, hasher: Default::default() }
+        }
+
+        /// Removes the entries whose value was dropped, and returns how many were removed
+        pub fn prune(&self) -> usize {
+            self.shards
+                .iter()
+                .map(|shard| shard.lock().unwrap_or_else(|e| e.into_inner()).prune())
+                .sum()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<K: Eq + core::hash::Hash, T: ?Sized> WeakCache<K, T> {
+        fn shard<Q: ?Sized + core::hash::Hash>(
+            &self,
+            key: &Q,
+        ) -> std::sync::MutexGuard<'_, CacheShard<K, T>> {
+            use core::hash::{BuildHasher, Hasher};
+            let mut hasher = self.hasher.build_hasher();
+            key.hash(&mut hasher);
+            let index = hasher.finish() as usize % self.shards.len();
+            self.shards[index].lock().unwrap_or_else(|e| e.into_inner())
+        }
+
+        /// Returns the value of `key` if it is still alive
+        ///
+        /// If another thread is constructing the value with [`WeakCache::get_or_insert_with`],
+        /// this waits for it.
+        pub fn get<Q>(&self, key: &Q) -> Option<Pin<Arc<T>>>
+        where
+            K: core::borrow::Borrow<Q>,
+            Q: ?Sized + Eq + core::hash::Hash,
+        {
+            // Don't hold the shard while waiting for the entry
+            let entry = self.shard(key).map.get(key)?.clone();
+            let value = entry.lock().unwrap_or_else(|e| e.into_inner()).upgrade();
+            if value.is_none() {
+                let mut shard = self.shard(key);
+                // Only remove the entry if nobody else is using it, the clones are only made
+                // while holding the shard
+                let unused = shard.map.get(key).is_some_and(|e| Arc::ptr_eq(e, &entry))
+                    && Arc::strong_count(&entry) == 2;
+                if unused {
+                    shard.map.remove(key);
+                }
+            }
+            value
+        }
+
+        /// Returns the value of `key` if it is still alive, otherwise constructs it with
+        /// `f` and stores a weak pointer to it.
+        ///
+        /// If several threads call this function for the same key at the same time, `f` is
+        /// only called by one of them and the others wait for the result. So `f` must not
+        /// call this function with the same key, which would deadlock.
+        pub fn get_or_insert_with(&self, key: &K, f: impl FnOnce() -> Pin<Arc<T>>) -> Pin<Arc<T>>
+        where
+            K: Clone,
+        {
+            let entry = {
+                let mut shard = self.shard(key);
+                if let Some(entry) = shard.map.get(key) {
+                    entry.clone()
+                } else {
+                    if shard.map.len() >= shard.prune_at {
+                        shard.prune();
+                    }
+                    let entry = CacheEntry::default();
+                    shard.map.insert(key.clone(), entry.clone());
+                    entry
+                }
+            };
+            // Only this entry is locked while calling `f`
+            let mut weak = entry.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(value) = weak.upgrade() {
+                return value;
+            }
+            let value = f();
+            *weak = PinWeak::downgrade_ref(&value);
+            value
+        }
+    }
+
+    #[cfg(feature = "std")]
+    impl<K, T: ?Sized> Default for WeakCache<K, T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_weak_cache() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        struct Asset(u32, core::marker::PhantomPinned);
+        let constructions = AtomicUsize::new(0);
+        let load = |v| {
+            constructions.fetch_add(1, Ordering::Relaxed);
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            Arc::pin(Asset(v, core::marker::PhantomPinned))
+        };
+        let cache = WeakCache::<std::string::String, Asset>::new();
+        let barrier = std::sync::Barrier::new(8);
+        let assets: std::vec::Vec<_> = std::thread::scope(|scope| {
+            let threads: std::vec::Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+                        cache.get_or_insert_with(&"key".into(), || load(1))
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(constructions.load(Ordering::Relaxed), 1);
+        assert!(assets.iter().all(|a| PinWeak::downgrade_ref(a) == assets[0]));
+        assert_eq!(cache.get("key").unwrap().0, 1);
+        assert!(cache.get("other").is_none());
+
+        // A new generation after the value was dropped
+        drop(assets);
+        assert!(cache.get("key").is_none());
+        assert_eq!(cache.get_or_insert_with(&"key".into(), || load(2)).0, 2);
+        assert_eq!(constructions.load(Ordering::Relaxed), 2);
+        assert_eq!(cache.prune(), 1);
+
+        let kept: std::vec::Vec<_> =
+            (0..40).map(|i| cache.get_or_insert_with(&std::format!("{i}"), || load(i))).collect();
+        assert_eq!(cache.prune(), 0);
+        drop(kept);
+        assert_eq!(cache.prune(), 40);
+        assert!(cache.get("0").is_none());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_weak_cache_get_race() {
+        let cache = WeakCache::<u32, u32>::new();
+        let value = cache.get_or_insert_with(&1, || Arc::pin(42));
+        let done = core::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    while !done.load(core::sync::atomic::Ordering::Relaxed) {
+                        cache.get_or_insert_with(&1, || unreachable!());
+                    }
+                });
+            }
+            for _ in 0..10_000 {
+                assert_eq!(cache.get(&1).map(|v| *v), Some(42));
+            }
+            done.store(true, core::sync::atomic::Ordering::Relaxed);
+        });
+        drop(value);
+        assert!(cache.get(&1).is_none());
+        assert_eq!(cache.prune(), 0);
+    }
+}
+
+/// A `PinWeak` for the `Arc` of the `portable-atomic-util` crate, which also works on targets
+/// without atomic compare-and-swap. Requires the `portable-atomic` feature.
+///
+/// It is defined with [`define_pin_weak!`], so it only has the core of the API of
+/// [`sync::PinWeak`].
+///
+/// ```rust
+/// use pin_weak::portable::{Arc, PinWeak};
+/// let pinned = Arc::pin(42);
+/// let weak = PinWeak::downgrade_ref(&pinned);
+/// assert_eq!(weak.upgrade().map(|v| *v), Some(42));
+/// drop(pinned);
+/// assert!(weak.upgrade().is_none());
+/// ```
+#[cfg(feature = "portable-atomic")]
+pub mod portable {
+    #[doc(no_inline)]
+    /// re-exported for convenience
+    pub use portable_atomic_util::{Arc, Weak};
+    // Safety: portable_atomic_util::Arc has the same semantics as alloc::sync::Arc
+    crate::define_pin_weak!(unsafe Arc, Weak, "Arc");
+
+    #[test]
+    fn test_portable() {
+        use core::marker::PhantomPinned;
+        struct Node {
+            me: PinWeak<Node>,
+            _pinned: PhantomPinned,
+        }
+        let node = PinWeak::new_cyclic(|me| Node { me: me.clone(), _pinned: PhantomPinned });
+        let weak = node.me.clone();
+        assert_eq!((weak.strong_count(), weak.weak_count()), (1, 2));
+        let name: core::pin::Pin<Arc<str>> = core::pin::Pin::new(Arc::from("name"));
+        assert_eq!(PinWeak::downgrade_ref(&name).upgrade().map(|n| n.len()), Some(4));
+        std::thread::spawn(move || {
+            assert!(weak.upgrade().is_some());
+            assert!(weak.ptr_eq(&weak.upgrade().unwrap().me));
+        })
+        .join()
+        .unwrap();
+        let weak = PinWeak::downgrade(node);
+        assert!(weak.upgrade().is_none());
+    }
+}
+
+/// Defines a `PinWeak` for another reference counted pointer, in the current module.
+///
+/// The arguments are the strong pointer type, the weak pointer type, and the name used for the
+/// strong pointer in the documentation. Both types must be in scope. This generates the core
+/// of the API of [`rc::PinWeak`]: `new`, `downgrade`, `downgrade_ref`, `upgrade`,
+/// `strong_count`, `weak_count`, `ptr_eq` and `new_cyclic`, with the `Clone`, `Default`,
+/// `Debug` and `From` implementations, and a test of these functions. The rest of the API of
+/// [`rc::PinWeak`] and [`sync::PinWeak`] is not generated.
+///
+/// The pointer types must provide the same functions as [`Rc`] and [`alloc::rc::Weak`] for
+/// these, for any `T: ?Sized`: `Deref`, `downgrade` and `new_cyclic` for the strong pointer;
+/// `Clone`, `upgrade`, `strong_count`, `weak_count` and `ptr_eq` for the weak pointer.
+///
+/// # Safety
+///
+/// The macro must be invoked with `unsafe` in front of the arguments, because the generated
+/// code is only sound if the pointers behave like [`Rc`]: the value is never moved while there
+/// are strong or weak pointers to it, `Deref` always returns the same address, and upgrading a
+/// weak pointer returns a pointer to the allocation it was downgraded from.
+///
+/// ```
+/// mod my_rc {
+///     use std::rc::{Rc, Weak};
+///     /// A reference counted pointer, which could also count its allocations for example
+///     pub struct MyRc<T: ?Sized>(Rc<T>);
+///     pub struct MyWeak<T: ?Sized>(Weak<T>);
+///
+///     impl<T: ?Sized> core::ops::Deref for MyRc<T> {
+///         type Target = T;
+///         fn deref(&self) -> &T {
+///             &self.0
+///         }
+///     }
+///     impl<T> MyRc<T> {
+///         pub fn new_cyclic(data_fn: impl FnOnce(&MyWeak<T>) -> T) -> Self {
+///             MyRc(Rc::new_cyclic(|weak| data_fn(&MyWeak(weak.clone()))))
+///         }
+///     }
+///     impl<T: ?Sized> MyRc<T> {
+///         pub fn downgrade(this: &Self) -> MyWeak<T> {
+///             MyWeak(Rc::downgrade(&this.0))
+///         }
+///     }
+///     impl<T: ?Sized> Clone for MyWeak<T> {
+///         fn clone(&self) -> Self {
+///             MyWeak(self.0.clone())
+///         }
+///     }
+///     impl<T: ?Sized> MyWeak<T> {
+///         pub fn upgrade(&self) -> Option<MyRc<T>> {
+///             self.0.upgrade().map(MyRc)
+///         }
+///         pub fn strong_count(&self) -> usize {
+///             self.0.strong_count()
+///         }
+///         pub fn weak_count(&self) -> usize {
+///             self.0.weak_count()
+///         }
+///         pub fn ptr_eq(&self, other: &Self) -> bool {
+///             self.0.ptr_eq(&other.0)
+///         }
+///     }
+/// }
+///
+/// mod pinned {
+///     use super::my_rc::{MyRc, MyWeak};
+///     // Safety: MyRc behaves like Rc
+///     pin_weak::define_pin_weak!(unsafe MyRc, MyWeak, "MyRc");
+/// }
+///
+/// use pinned::PinWeak;
+/// struct Node {
+///     me: PinWeak<Node>,
+///     _pinned: core::marker::PhantomPinned,
+/// }
+///
+/// fn main() {
+///     let node = PinWeak::new_cyclic(|me| Node { me: me.clone(), _pinned: Default::default() });
+///     assert!(node.me.upgrade().is_some());
+///     assert_eq!(node.me.strong_count(), 1);
+///     let weak = PinWeak::downgrade(node);
+///     assert!(weak.upgrade().is_none());
+/// }
+/// ```
+#[macro_export]
+macro_rules! define_pin_weak {
+    (unsafe $Rc:ident, $Weak:ident, $rc_lit:literal $(,)?) => {
+        #[doc = ::core::concat!("A weak pointer which can be obtained from a `Pin<", $rc_lit, "<T>>`, and upgraded to a `Pin<", $rc_lit, "<T>>`")]
+        ///
+        /// The typical way to obtain a `PinWeak` is to call [`PinWeak::downgrade`]
+        pub struct PinWeak<T: ?::core::marker::Sized> {
+            weak: ::core::option::Option<$Weak<T>>,
+        }
+
+        /// Same as [`PinWeak::new`]
+        impl<T: ?::core::marker::Sized> ::core::default::Default for PinWeak<T> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<T: ?::core::marker::Sized> ::core::clone::Clone for PinWeak<T> {
+            fn clone(&self) -> Self {
+                Self { weak: ::core::clone::Clone::clone(&self.weak) }
+            }
+        }
+
+        /// Shows whether the target is alive, and the reference counts
+        impl<T: ?::core::marker::Sized> ::core::fmt::Debug for PinWeak<T> {
+            fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
+                f.debug_struct("PinWeak")
+                    .field("alive", &(self.strong_count() > 0))
+                    .field("strong", &self.strong_count())
+                    .field("weak", &self.weak_count())
+                    .finish()
+            }
+        }
+
+        impl<T: ?::core::marker::Sized> ::core::convert::From<&::core::pin::Pin<$Rc<T>>> for PinWeak<T> {
+            fn from(rc: &::core::pin::Pin<$Rc<T>>) -> Self {
+                Self::downgrade_ref(rc)
+            }
+        }
+
+        #[allow(dead_code)]
+        impl<T: ?::core::marker::Sized> PinWeak<T> {
+            /// Constructs a new `PinWeak` which does not point to any allocation.
+            /// Calling [`PinWeak::upgrade`] on it always returns `None`.
+            pub const fn new() -> Self {
+                Self { weak: ::core::option::Option::None }
+            }
+
+            #[doc = ::core::concat!("Equivalent function to `", $rc_lit, "::downgrade`, but taking a `Pin<", $rc_lit, "<T>>` instead.")]
+            pub fn downgrade(rc: ::core::pin::Pin<$Rc<T>>) -> Self {
+                Self::downgrade_ref(&rc)
+            }
+
+            /// Same as [`PinWeak::downgrade`], but taking the pinned pointer by reference
+            pub fn downgrade_ref(rc: &::core::pin::Pin<$Rc<T>>) -> Self {
+                // Safety: Pin is repr(transparent), and we will never return anything else than
+                // a pinned pointer
+                let rc = unsafe { &*(rc as *const ::core::pin::Pin<$Rc<T>> as *const $Rc<T>) };
+                Self { weak: ::core::option::Option::Some($Rc::downgrade(rc)) }
+            }
+
+            #[doc = ::core::concat!("Upgrades to a `Pin<", $rc_lit, "<T>>`, or returns `None` if the value was dropped")]
+            pub fn upgrade(&self) -> ::core::option::Option<::core::pin::Pin<$Rc<T>>> {
+                let rc = self.weak.as_ref()?.upgrade()?;
+                // Safety: the weak pointer was constructed from a pinned pointer
+                ::core::option::Option::Some(unsafe { ::core::pin::Pin::new_unchecked(rc) })
+            }
+
+            /// Returns the number of strong pointers to the value, or 0 if it was dropped
+            pub fn strong_count(&self) -> usize {
+                self.weak.as_ref().map_or(0, |weak| weak.strong_count())
+            }
+
+            /// Returns the number of weak pointers to the value, or 0 if it was dropped
+            pub fn weak_count(&self) -> usize {
+                self.weak.as_ref().map_or(0, |weak| weak.weak_count())
+            }
+
+            /// Returns true if the two pointers point to the same allocation
+            ///
+            /// Two `PinWeak` created with [`PinWeak::new`] are equal to each other.
+            pub fn ptr_eq(&self, other: &Self) -> bool {
+                match (&self.weak, &other.weak) {
+                    (::core::option::Option::Some(a), ::core::option::Option::Some(b)) => a.ptr_eq(b),
+                    (a, b) => a.is_none() && b.is_none(),
+                }
+            }
+        }
+
+        #[allow(dead_code)]
+        impl<T> PinWeak<T> {
+            #[doc = ::core::concat!("Equivalent function to `", $rc_lit, "::new_cyclic`, but operating on `PinWeak<T>` and `Pin<", $rc_lit, "<T>>` instead.")]
+            pub fn new_cyclic(data_fn: impl ::core::ops::FnOnce(&Self) -> T) -> ::core::pin::Pin<$Rc<T>> {
+                let rc = $Rc::new_cyclic(|weak| {
+                    data_fn(&Self { weak: ::core::option::Option::Some(::core::clone::Clone::clone(weak)) })
+                });
+                // Safety: nobody else had access to the unpinned pointer before
+                unsafe { ::core::pin::Pin::new_unchecked(rc) }
+            }
+        }
+
+        #[cfg(test)]
+        #[test]
+        fn test_define_pin_weak() {
+            let dangling = PinWeak::<u32>::new();
+            ::core::assert!(dangling.upgrade().is_none());
+            ::core::assert!(dangling.ptr_eq(&::core::default::Default::default()));
+            let rc = PinWeak::new_cyclic(|me: &PinWeak<u32>| {
+                ::core::assert!(me.upgrade().is_none());
+                42
+            });
+            let weak = PinWeak::downgrade_ref(&rc);
+            ::core::assert_eq!(weak.upgrade().map(|rc| *rc), ::core::option::Option::Some(42));
+            ::core::assert_eq!((weak.strong_count(), weak.weak_count()), (1, 1));
+            ::core::assert!(weak.ptr_eq(&::core::clone::Clone::clone(&weak)));
+            ::core::assert!(!weak.ptr_eq(&dangling));
+            ::core::mem::drop(rc);
+            ::core::assert!(weak.upgrade().is_none());
+            ::core::assert_eq!(weak.strong_count(), 0);
+        }
+    };
+}
+
+#[cfg(test)]
+mod test_define_pin_weak {
+    mod wrapper {
+        use alloc::rc::{Rc, Weak};
+
+        pub struct CountedRc<T: ?Sized>(Rc<T>);
+        pub struct CountedWeak<T: ?Sized>(Weak<T>);
+
+        impl<T: ?Sized> core::ops::Deref for CountedRc<T> {
+            type Target = T;
+            fn deref(&self) -> &T {
+                &self.0
+            }
+        }
+        impl<T> CountedRc<T> {
+            pub fn new_cyclic(data_fn: impl FnOnce(&CountedWeak<T>) -> T) -> Self {
+                CountedRc(Rc::new_cyclic(|weak| data_fn(&CountedWeak(weak.clone()))))
+            }
+        }
+        impl<T: ?Sized> CountedRc<T> {
+            pub fn downgrade(this: &Self) -> CountedWeak<T> {
+                CountedWeak(Rc::downgrade(&this.0))
+            }
+        }
+        impl<T: ?Sized> Clone for CountedWeak<T> {
+            fn clone(&self) -> Self {
+                CountedWeak(self.0.clone())
+            }
+        }
+        impl<T: ?Sized> CountedWeak<T> {
+            pub fn upgrade(&self) -> Option<CountedRc<T>> {
+                self.0.upgrade().map(CountedRc)
+            }
+            pub fn strong_count(&self) -> usize {
+                self.0.strong_count()
+            }
+            pub fn weak_count(&self) -> usize {
+                self.0.weak_count()
+            }
+            pub fn ptr_eq(&self, other: &Self) -> bool {
+                self.0.ptr_eq(&other.0)
+            }
+        }
+    }
+
+    #[no_implicit_prelude]
+    mod pinned {
+        use super::wrapper::{CountedRc, CountedWeak};
+        // Safety: CountedRc behaves like Rc
+        crate::define_pin_weak!(unsafe CountedRc, CountedWeak, "CountedRc");
+    }
+}
+
+/// Declares a wrapper around a `PinWeak` to a trait object, which can be created from any
+/// `Pin<Rc<T>>` or `PinWeak<T>` where `T` implements the trait.
+///
+/// This is useful on stable Rust, where a `PinWeak<T>` cannot be coerced to `PinWeak<dyn Trait>`.
+/// The first argument is the module providing the pointer (`rc` or `sync`), followed by the
+/// trait object type. Attributes and visibility are forwarded to the generated struct.
+///
+/// ```
+/// use pin_weak::{pin_weak_dyn, rc::Rc};
+///
+/// trait EventHandler {
+///     fn handle(&self) -> u32;
+/// }
+/// struct Button(u32);
+/// impl EventHandler for Button {
+///     fn handle(&self) -> u32 {
+///         self.0
+///     }
+/// }
+///
+/// pin_weak_dyn! {
+///     /// A weak pointer to any event handler
+///     pub struct EventHandlerWeak(rc, dyn EventHandler);
+/// }
+///
+/// let button = Rc::pin(Button(42));
+/// let weak = EventHandlerWeak::from(button.clone());
+/// assert_eq!(weak.upgrade().unwrap().handle(), 42);
+/// drop(button);
+/// assert!(weak.upgrade().is_none());
+/// ```
+///
+/// The generated code uses `unsafe`, so it cannot be used in a crate with `#![forbid(unsafe_code)]`.
+#[macro_export]
+macro_rules! pin_weak_dyn {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident(rc, dyn $($bound:tt)+);) => {
+        $crate::pin_weak_dyn!(@impl [$(#[$attr])*] [$vis] $name rc Rc [$($bound)+]);
+    };
+    ($(#[$attr:meta])* $vis:vis struct $name:ident(sync, dyn $($bound:tt)+);) => {
+        $crate::pin_weak_dyn!(@impl [$(#[$attr])*] [$vis] $name sync Arc [$($bound)+]);
+    };
+    (@impl [$($attr:tt)*] [$vis:vis] $name:ident $m:ident $Rc:ident [$($bound:tt)+]) => {
+        $($attr)*
+        #[derive(Clone, Default)]
+        $vis struct $name($crate::$m::PinWeak<dyn $($bound)+>);
+
+        impl $name {
+            /// Creates a new weak pointer that never upgrades
+            #[allow(dead_code)]
+            $vis const fn new() -> Self {
+                Self($crate::$m::PinWeak::new())
+            }
+
+            /// Equivalent to `PinWeak::upgrade`
+            #[allow(dead_code)]
+            $vis fn upgrade(&self) -> Option<$crate::$m::Pin<$crate::$m::$Rc<dyn $($bound)+>>> {
+                self.0.upgrade()
+            }
+
+            /// Equivalent to `PinWeak::ptr_eq`
+            #[allow(dead_code)]
+            $vis fn ptr_eq(&self, other: &Self) -> bool {
+                self.0.ptr_eq(&other.0)
+            }
+        }
+
+        impl<T: $($bound)+ + 'static> From<$crate::$m::Pin<$crate::$m::$Rc<T>>> for $name {
+            fn from(rc: $crate::$m::Pin<$crate::$m::$Rc<T>>) -> Self {
+                let rc: $crate::$m::Pin<$crate::$m::$Rc<dyn $($bound)+>> = rc;
+                Self($crate::$m::PinWeak::downgrade(rc))
+            }
+        }
+
+        impl<T: $($bound)+ + 'static> From<$crate::$m::PinWeak<T>> for $name {
+            fn from(weak: $crate::$m::PinWeak<T>) -> Self {
+                match weak.try_into_raw() {
+                    // Safety: the pointer comes from PinWeak::into_raw, the cast only adds the vtable
+                    #[allow(unused_parens)]
+                    Some(ptr) => Self(unsafe {
+                        $crate::$m::PinWeak::from_raw(ptr as *const (dyn $($bound)+))
+                    }),
+                    None => Self::new(),
+                }
+            }
+        }
+
+        impl From<$name> for $crate::$m::PinWeak<dyn $($bound)+> {
+            fn from(weak: $name) -> Self {
+                weak.0
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test_pin_weak_dyn {
+    use core::marker::PhantomPinned;
+
+    trait EventHandler {
+        fn handle(&self) -> u32;
+    }
+    struct Button {
+        _p: PhantomPinned,
+        value: u32,
+    }
+    impl EventHandler for Button {
+        fn handle(&self) -> u32 {
+            self.value
+        }
+    }
+
+    pin_weak_dyn! {
+        struct RcHandlerWeak(rc, dyn EventHandler);
+    }
+
+    #[cfg(feature = "sync")]
+    pin_weak_dyn! {
+        /// A thread safe weak pointer
+        struct SyncHandlerWeak(sync, dyn EventHandler + Send + Sync);
+    }
+
+    #[test]
+    fn test_rc() {
+        use crate::rc::{PinWeak, Rc};
+        let button = Rc::pin(Button { _p: PhantomPinned, value: 42 });
+        let weak = RcHandlerWeak::from(button.clone());
+        assert_eq!(weak.upgrade().unwrap().handle(), 42);
+        let weak2 = RcHandlerWeak::from(PinWeak::downgrade(button.clone()));
+        assert!(weak.ptr_eq(&weak2));
+        assert!(weak.clone().ptr_eq(&weak));
+        let other = RcHandlerWeak::from(Rc::pin(Button { _p: PhantomPinned, value: 1 }));
+        assert!(!weak.ptr_eq(&other));
+        assert!(other.upgrade().is_none());
+        drop(button);
+        assert!(weak.upgrade().is_none());
+        assert!(weak2.upgrade().is_none());
+        assert!(RcHandlerWeak::from(PinWeak::<Button>::new()).upgrade().is_none());
+        assert!(RcHandlerWeak::default().upgrade().is_none());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_sync() {
+        use crate::sync::{Arc, PinWeak};
+        let button = Arc::pin(Button { _p: PhantomPinned, value: 42 });
+        let weak = SyncHandlerWeak::from(PinWeak::downgrade(button.clone()));
+        let handle = std::thread::spawn(move || weak.upgrade().map(|h| h.handle()));
+        assert_eq!(handle.join().unwrap(), Some(42));
+        let weak =
+            PinWeak::<dyn EventHandler + Send + Sync>::from(SyncHandlerWeak::from(button.clone()));
+        drop(button);
+        assert!(weak.upgrade().is_none());
+    }
+}
+
+/// Constructs a self-referential object with `PinWeak::new_cyclic`, replacing `@weak` in
+/// the fields of the struct literal by a clone of the weak pointer to the new object.
+///
+/// The first argument is the module providing the pointer (`rc` or `sync`), followed by a
+/// struct literal or a tuple struct constructor. `@weak` can only be used directly as the value
+/// of a field, not within a nested expression.
+///
+/// ```
+/// use pin_weak::{pin_cyclic, rc::{PinWeak, Pin, Rc}};
+///
+/// struct Gadget {
+///     me: PinWeak<Gadget>,
+///     value: u32,
+/// }
+/// impl Gadget {
+///     fn new(value: u32) -> Pin<Rc<Self>> {
+///         pin_cyclic!(rc Self { me: @weak, value })
+///     }
+/// }
+///
+/// let gadget = Gadget::new(42);
+/// assert_eq!(gadget.me.upgrade().unwrap().value, 42);
+/// ```
+#[macro_export]
+macro_rules! pin_cyclic {
+    (rc $($t:tt)+) => {
+        $crate::rc::PinWeak::new_cyclic(|weak| $crate::pin_cyclic!(@path weak [] $($t)+))
+    };
+    (sync $($t:tt)+) => {
+        $crate::sync::PinWeak::new_cyclic(|weak| $crate::pin_cyclic!(@path weak [] $($t)+))
+    };
+    (@path $w:ident [$($path:tt)+] { $($body:tt)* }) => {
+        $crate::pin_cyclic!(@fields $w [$($path)+] {} [] $($body)*)
+    };
+    (@path $w:ident [$($path:tt)+] ( $($body:tt)* )) => {
+        $crate::pin_cyclic!(@fields $w [$($path)+] () [] $($body)*)
+    };
+    (@path $w:ident [$($path:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::pin_cyclic!(@path $w [$($path)* $t] $($rest)*)
+    };
+    (@fields $w:ident [$($path:tt)+] {} [$($done:tt)*]) => {
+        $($path)+ { $($done)* }
+    };
+    (@fields $w:ident [$($path:tt)+] () [$($done:tt)*]) => {
+        $($path)+ ( $($done)* )
+    };
+    (@fields $w:ident $path:tt $kind:tt [$($done:tt)*] @weak $($rest:tt)*) => {
+        $crate::pin_cyclic!(@fields $w $path $kind [$($done)* $w.clone()] $($rest)*)
+    };
+    (@fields $w:ident $path:tt $kind:tt [$($done:tt)*] $t:tt $($rest:tt)*) => {
+        $crate::pin_cyclic!(@fields $w $path $kind [$($done)* $t] $($rest)*)
+    };
+}
+
+#[cfg(test)]
+mod test_pin_cyclic {
+    use crate::rc::{Pin, PinWeak, Rc};
+
+    struct Gadget {
+        me: PinWeak<Gadget>,
+        value: u32,
+        _p: core::marker::PhantomPinned,
+    }
+
+    impl Gadget {
+        fn new(value: u32) -> Pin<Rc<Self>> {
+            pin_cyclic!(rc Self { value, me: @weak, _p: core::marker::PhantomPinned })
+        }
+    }
+
+    struct Generic<T> {
+        me: PinWeak<Generic<T>>,
+        value: T,
+    }
+
+    struct Tuple(u32, PinWeak<Tuple>);
+
+    #[test]
+    fn test_pin_cyclic() {
+        let gadget = Gadget::new(42);
+        assert!(gadget.me == gadget);
+        assert_eq!(gadget.me.upgrade().unwrap().value, 42);
+
+        let generic = pin_cyclic!(rc Generic { me: @weak, value: "hello" });
+        assert_eq!(generic.me.upgrade().unwrap().value, "hello");
+        let generic = pin_cyclic!(rc Generic::<u8> { value: 1 + 2, me: @weak });
+        assert_eq!(generic.me.upgrade().unwrap().value, 3);
+
+        let tuple = pin_cyclic!(rc Tuple(42, @weak));
+        assert!(tuple.1 == tuple);
+        assert_eq!(tuple.1.upgrade().unwrap().0, 42);
+        let weak = tuple.1.clone();
+        drop(tuple);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_pin_cyclic_sync() {
+        use crate::sync::PinWeak;
+        struct Node<T> {
+            value: T,
+            me: PinWeak<Node<T>>,
+        }
+        let node = pin_cyclic!(sync Node { value: 42u32, me: @weak });
+        assert!(node.me == node);
+        let weak = node.me.clone();
+        assert_eq!(std::thread::spawn(move || weak.upgrade().unwrap().value).join().unwrap(), 42);
+    }
+}
+
+/// Creates a closure which captures clones of some `PinWeak`, and upgrades them each time it
+/// is called.
+///
+/// The captures are given in brackets, either as the name of a `PinWeak` variable, or as
+/// `name = expression`. In the body of the closure, each name refers to the upgraded strong
+/// pointer. If one of the pointers can't be upgraded, the body is not run and the closure
+/// returns `Default::default()`, or the expression given after `, else`.
+///
+/// The closure is always a `move` closure. It implements `Fn` or `FnMut` depending on its body.
+/// Since `self` can't be rebound in a closure, a weak pointer to self must be given a name, as
+/// in `[this = self.me]`.
+///
+/// ```rust
+/// use pin_weak::{rc::{PinWeak, Rc}, weak_closure};
+/// use core::{cell::Cell, pin::Pin};
+///
+/// struct Button { on_click: Vec<Box<dyn Fn(u32)>> }
+///
+/// struct Counter { me: PinWeak<Counter>, count: Cell<u32> }
+/// impl Counter {
+///     fn on_event(self: Pin<&Self>, clicks: u32) {
+///         self.count.set(self.count.get() + clicks);
+///     }
+///     fn connect(&self, button: &mut Button) {
+///         button.on_click.push(Box::new(weak_closure!([this = self.me] move |clicks| {
+///             this.as_ref().on_event(clicks)
+///         })));
+///     }
+/// }
+///
+/// let mut button = Button { on_click: Vec::new() };
+/// let counter = PinWeak::new_cyclic(|me| Counter { me: me.clone(), count: Cell::new(0) });
+/// counter.connect(&mut button);
+/// button.on_click.iter().for_each(|f| f(2));
+/// assert_eq!(counter.count.get(), 2);
+///
+/// let weak_counter = PinWeak::downgrade_ref(&counter);
+/// let is_big = weak_closure!([weak_counter] |threshold: u32| weak_counter.count.get() > threshold, else false);
+/// assert!(is_big(1));
+/// drop(counter);
+/// button.on_click.iter().for_each(|f| f(2));
+/// assert!(!is_big(1));
+/// ```
+#[macro_export]
+macro_rules! weak_closure {
+    ([$($captures:tt)+] $(move)? || $body:expr $(, else $default:expr)?) => {
+        $crate::weak_closure!(@impl [$($captures)+] [] [$body] [$($default)?])
+    };
+    ([$($captures:tt)+] $(move)? |$($arg:tt $(: $ty:ty)?),*| $body:expr $(, else $default:expr)?) => {
+        $crate::weak_closure!(@impl [$($captures)+] [$($arg $(: $ty)?),*] [$body] [$($default)?])
+    };
+    (@impl [$($name:ident $(= $weak:expr)?),+ $(,)?] [$($args:tt)*] [$body:expr] $default:tt) => {{
+        $(let $name = $crate::weak_closure!(@clone $name $(= $weak)?);)+
+        move |$($args)*| {
+            $(
+                let ::core::option::Option::Some($name) = $name.upgrade() else {
+                    return $crate::weak_closure!(@default $default);
+                };
+            )+
+            $body
+        }
+    }};
+    (@clone $name:ident) => {
+        ::core::clone::Clone::clone(&$name)
+    };
+    (@clone $name:ident = $weak:expr) => {
+        ::core::clone::Clone::clone(&$weak)
+    };
+    (@default []) => {
+        ::core::default::Default::default()
+    };
+    (@default [$default:expr]) => {
+        $default
+    };
+}
+
+#[cfg(test)]
+mod test_weak_closure {
+    use crate::rc::{Pin, PinWeak, Rc};
+    use core::cell::Cell;
+
+    struct Widget {
+        me: PinWeak<Widget>,
+        clicks: Cell<u32>,
+        _p: core::marker::PhantomPinned,
+    }
+
+    impl Widget {
+        fn new() -> Pin<Rc<Self>> {
+            PinWeak::new_cyclic(|me| Widget {
+                me: me.clone(),
+                clicks: Cell::new(0),
+                _p: core::marker::PhantomPinned,
+            })
+        }
+
+        fn handler(&self) -> impl Fn(u32) -> u32 {
+            weak_closure!([this = self.me] move |n| {
+                this.clicks.set(this.clicks.get() + n);
+                this.clicks.get()
+            })
+        }
+    }
+
+    #[test]
+    fn test_weak_closure() {
+        let widget = Widget::new();
+        let handler = widget.handler();
+        assert_eq!(handler(2), 2);
+        assert_eq!(handler(3), 5);
+        let owner = Widget::new();
+        let (a, b) = (widget.me.clone(), owner.me.clone());
+        let both = weak_closure!([a, b] || a.clicks.get() + b.clicks.get(), else 42);
+        assert_eq!(both(), 5);
+
+        let mut calls = 0;
+        let mut counting = weak_closure!([widget = widget.me] move |x: u32, y: u32| {
+            calls += 1;
+            x + y + calls + widget.clicks.get()
+        });
+        assert_eq!(counting(1, 2), 9);
+        assert_eq!(counting(1, 2), 10);
+
+        drop(widget);
+        assert_eq!(handler(2), 0);
+        assert_eq!(both(), 42);
+        assert_eq!(counting(1, 2), 0);
+        assert_eq!(owner.clicks.get(), 0);
+    }
+}
+
+/// Panics if some of the objects recorded by a `DropTracker` are still alive, listing their
+/// labels. Requires the `test_util` feature.
+///
+/// This works with both [`rc::DropTracker`] and [`sync::DropTracker`].
+///
+/// ```rust,should_panic
+/// use pin_weak::rc::{DropTracker, Rc};
+/// let tracker = DropTracker::new();
+/// let leaked = Rc::pin(());
+/// tracker.track(&leaked, "leaked");
+/// pin_weak::assert_all_dropped!(tracker); // panics: `leaked` is still alive
+/// ```
+#[cfg(feature = "test_util")]
+#[macro_export]
+macro_rules! assert_all_dropped {
+    ($tracker:expr) => {{
+        let live = $tracker.live();
+        if !live.is_empty() {
+            ::core::panic!("objects were not dropped: {:?}", live);
+        }
+    }};
+}
+
+#[cfg(all(test, pin_weak_allocator_api))]
+mod test_try_pin {
+    use core::cell::Cell;
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    std::thread_local! {
+        static FAIL: Cell<bool> = const { Cell::new(false) };
+    }
+
+    struct FailingAllocator;
+    unsafe impl GlobalAlloc for FailingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if FAIL.with(Cell::get) {
+                return core::ptr::null_mut();
+            }
+            System.alloc(layout)
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: FailingAllocator = FailingAllocator;
+
+    struct Counted<'a>(&'a Cell<u32>, core::marker::PhantomPinned);
+    impl Drop for Counted<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_try_pin() {
+        let drops = Cell::new(0);
+        let rc = crate::rc::try_pin(Counted(&drops, core::marker::PhantomPinned)).unwrap();
+        let weak = crate::rc::PinWeak::downgrade_ref(&rc);
+        drop(rc);
+        assert!(weak.upgrade().is_none());
+        assert_eq!(drops.get(), 1);
+
+        FAIL.with(|f| f.set(true));
+        let rc = crate::rc::try_pin(Counted(&drops, core::marker::PhantomPinned));
+        #[cfg(feature = "sync")]
+        let arc = crate::sync::try_pin(42);
+        FAIL.with(|f| f.set(false));
+        assert!(rc.is_err());
+        #[cfg(feature = "sync")]
+        assert!(arc.is_err());
+        assert_eq!(drops.get(), 2);
+    }
+}
+
+#[cfg(all(test, pin_weak_allocator_api))]
+mod test_allocator {
+    use core::alloc::{AllocError, Allocator, Layout};
+    use core::ptr::NonNull;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::alloc::{Global, System};
+    use std::sync::Arc;
+
+    /// Counts the allocations and deallocations made through it
+    #[derive(Clone, Default)]
+    pub(crate) struct CountingAllocator {
+        pub(crate) allocs: Arc<AtomicUsize>,
+        pub(crate) deallocs: Arc<AtomicUsize>,
+    }
+    unsafe impl Allocator for CountingAllocator {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+            self.allocs.fetch_add(1, Ordering::Relaxed);
+            System.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.deallocs.fetch_add(1, Ordering::Relaxed);
+            System.deallocate(ptr, layout)
+        }
+    }
+    impl CountingAllocator {
+        pub(crate) fn counts(&self) -> (usize, usize) {
+            (self.allocs.load(Ordering::Relaxed), self.deallocs.load(Ordering::Relaxed))
+        }
+    }
+
+    #[test]
+    fn test_pin_in() {
+        use crate::rc::{pin_in, PinWeak};
+        let alloc = CountingAllocator::default();
+        let rc = pin_in(42u32, alloc.clone());
+        assert_eq!(alloc.counts(), (1, 0));
+        let weak: PinWeak<u32, CountingAllocator> = PinWeak::downgrade_ref(&rc);
+        let weak2 = PinWeak::downgrade(rc.clone());
+        assert!(weak2.clone().upgrade().is_some());
+        let upgraded: core::pin::Pin<std::rc::Rc<u32, CountingAllocator>> = weak.upgrade().unwrap();
+        assert_eq!(*upgraded, 42);
+        drop((rc, upgraded));
+        assert!(weak.upgrade().is_none());
+        // the weak pointers keep the allocation, which is freed with the same allocator
+        assert_eq!(alloc.counts(), (1, 0));
+        drop((weak, weak2));
+        assert_eq!(alloc.counts(), (1, 1));
+
+        // PinWeak<T> still defaults to the global allocator
+        let _: PinWeak<u32, Global> = PinWeak::downgrade(std::rc::Rc::pin(1u32));
+    }
+
+    #[test]
+    fn test_new_cyclic_in() {
+        use crate::rc::PinWeak;
+        struct Node {
+            me: PinWeak<Node, CountingAllocator>,
+            _p: core::marker::PhantomPinned,
+        }
+        let alloc = CountingAllocator::default();
+        let node = PinWeak::new_cyclic_in(
+            |me| {
+                assert!(me.upgrade().is_none());
+                Node { me: me.clone(), _p: core::marker::PhantomPinned }
+            },
+            alloc.clone(),
+        );
+        assert_eq!(alloc.counts(), (1, 0));
+        assert!(core::ptr::eq(&*node.me.upgrade().unwrap(), &*node));
+        // the value holds the only weak pointer, so dropping it frees the allocation
+        drop(node);
+        assert_eq!(alloc.counts(), (1, 1));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_pin_in_sync() {
+        use crate::sync::{pin_in, PinWeak};
+        let alloc = CountingAllocator::default();
+        let arc = pin_in(42u32, alloc.clone());
+        let weak = PinWeak::downgrade_ref(&arc);
+        let handle = std::thread::spawn(move || weak.upgrade().map(|arc| *arc));
+        assert_eq!(handle.join().unwrap(), Some(42));
+        drop(arc);
+        assert_eq!(alloc.counts(), (1, 1));
+    }
 }